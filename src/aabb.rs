@@ -2,6 +2,7 @@ use ray::Ray;
 use vec3::Vec3;
 use std::mem;
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Copy, Clone)]
 pub struct AABB {
     min: Vec3,
@@ -0,0 +1,333 @@
+use vec3::Vec3;
+use ray::Ray;
+use hitable::Hit;
+use tile::Tile;
+use framebuffer::PixelStats;
+use output::Channel;
+use rng::splitmix64;
+
+/// Arbitrary output variables: buffers besides the beauty image, for compositing and denoising
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Aov {
+    /// Reflectance of the first non-specular surface, looking through mirrors and glass
+    Albedo,
+    /// World space normal of the first non-specular surface
+    Normal,
+    /// Distance from the camera to the first hit, infinite where nothing was hit
+    Depth,
+    /// World space position of the first hit
+    Position,
+    ObjectId,
+    MaterialId,
+    /// The radiance each light group contributes, one image per group; they add up to the
+    /// beauty image when rendered with the box filter
+    LightGroups
+}
+
+pub const ALL: [Aov; 7] = [Aov::Albedo, Aov::Normal, Aov::Depth, Aov::Position, Aov::ObjectId, Aov::MaterialId, Aov::LightGroups];
+
+/// What one camera sample saw besides its radiance
+pub struct SampleAovs {
+    pub albedo: Vec3,
+    pub normal: Vec3,
+    pub position: Vec3,
+    pub depth: Option<f32>,
+    pub object_id: u32,
+    pub material_id: u32,
+    pub light_groups: Vec<Vec3>,
+    /// Product of the attenuations along the path so far
+    throughput: Vec3,
+    found_surface: bool
+}
+
+/// Sums over the samples taken inside one pixel
+#[derive(Clone, Copy, Debug)]
+pub struct AovPixel {
+    pub albedo: Vec3,
+    pub normal: Vec3,
+    pub position: Vec3,
+    pub depth: f32,
+    /// Samples that hit anything, which normal, position and depth are averaged over
+    pub hits: u32,
+    /// IDs are taken from each pixel's first sample, as averaging them means nothing
+    pub object_id: u32,
+    pub material_id: u32
+}
+
+/// AOV sums for a rectangle of pixels, row-major
+#[derive(Clone)]
+pub struct AovBuffer {
+    pub width: u32,
+    pub height: u32,
+    pub groups: usize,
+    pub pixels: Vec<AovPixel>,
+    /// `groups` sums per pixel
+    pub light_groups: Vec<Vec3>
+}
+
+impl Aov {
+    pub fn from_name(name: &str) -> Option<Aov> {
+        match name {
+            "albedo" => Some(Aov::Albedo),
+            "normal" => Some(Aov::Normal),
+            "depth" => Some(Aov::Depth),
+            "position" => Some(Aov::Position),
+            "object_id" => Some(Aov::ObjectId),
+            "material_id" => Some(Aov::MaterialId),
+            "light_groups" => Some(Aov::LightGroups),
+            _ => None
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Aov::Albedo => "albedo",
+            Aov::Normal => "normal",
+            Aov::Depth => "depth",
+            Aov::Position => "position",
+            Aov::ObjectId => "object_id",
+            Aov::MaterialId => "material_id",
+            Aov::LightGroups => "light_groups"
+        }
+    }
+}
+
+impl SampleAovs {
+    pub fn new(groups: usize) -> SampleAovs {
+        SampleAovs {
+            albedo: Vec3::zero_vector(),
+            normal: Vec3::zero_vector(),
+            position: Vec3::zero_vector(),
+            depth: None,
+            object_id: 0,
+            material_id: 0,
+            light_groups: vec![Vec3::zero_vector(); groups],
+            throughput: Vec3::new(1.0, 1.0, 1.0),
+            found_surface: false
+        }
+    }
+
+    /// Clears the record for the next sample
+    pub fn reset(&mut self) {
+        self.albedo = Vec3::zero_vector();
+        self.normal = Vec3::zero_vector();
+        self.position = Vec3::zero_vector();
+        self.depth = None;
+        self.object_id = 0;
+        self.material_id = 0;
+        for group in self.light_groups.iter_mut() {
+            *group = Vec3::zero_vector();
+        }
+        self.throughput = Vec3::new(1.0, 1.0, 1.0);
+        self.found_surface = false;
+    }
+
+    /// Records a path vertex. `attenuation` is None where the path ends, `emitted` is the
+    /// emission counted towards the radiance and `direct` the light sampled there with its group
+    pub fn record(&mut self, depth: u32, r: &Ray, hit: &Hit, attenuation: Option<Vec3>, emitted: Vec3, direct: Option<(Vec3, usize)>) {
+        let labels = hit.material.labels();
+        if depth == 0 {
+            self.depth = Some(hit.t * r.direction().length());
+            self.position = hit.p;
+            self.object_id = labels.object_id;
+            self.material_id = labels.material_id;
+        }
+        if !self.found_surface && !hit.material.is_specular() {
+            self.found_surface = true;
+            self.normal = Vec3::unit_vector(hit.normal);
+            //Emitters are given their colour, scaled into [0, 1]
+            let albedo = attenuation.unwrap_or_else(|| {
+                let e = hit.material.emitted(0.0, 0.0, &hit.p);
+                let peak = e.x().max(e.y()).max(e.z());
+                if peak > 0.0 { e / peak } else { e }
+            });
+            self.albedo = self.throughput * albedo;
+        }
+        if !self.light_groups.is_empty() {
+            let group = &mut self.light_groups[labels.light_group];
+            *group = *group + self.throughput * emitted;
+            if let Some((radiance, index)) = direct {
+                let group = &mut self.light_groups[index];
+                *group = *group + self.throughput * radiance;
+            }
+        }
+        if let Some(a) = attenuation {
+            self.throughput = self.throughput * a;
+        }
+    }
+}
+
+impl AovPixel {
+    pub fn new() -> AovPixel {
+        AovPixel {
+            albedo: Vec3::zero_vector(),
+            normal: Vec3::zero_vector(),
+            position: Vec3::zero_vector(),
+            depth: 0.0,
+            hits: 0,
+            object_id: 0,
+            material_id: 0
+        }
+    }
+}
+
+impl Default for AovPixel {
+    fn default() -> AovPixel {
+        AovPixel::new()
+    }
+}
+
+/// Encodes values in [0, 1] as RGBA8 without any display curve, as data images are read back
+pub fn to_rgba8(values: &[Vec3]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(values.len() * 4);
+    for v in values {
+        for c in [v.x(), v.y(), v.z()] {
+            data.push((255.0 * c.clamp(0.0, 1.0) + 0.5) as u8);
+        }
+        data.push(255);
+    }
+    data
+}
+
+/// A bright colour per ID, black for 0
+fn id_colour(id: u32) -> Vec3 {
+    if id == 0 {
+        return Vec3::zero_vector();
+    }
+    let h = splitmix64(id as u64);
+    let channel = |shift: u32| 0.25 + 0.75 * ((h >> shift) & 0xff) as f32 / 255.0;
+    Vec3::new(channel(0), channel(8), channel(16))
+}
+
+impl AovBuffer {
+    pub fn new(width: u32, height: u32, groups: usize) -> AovBuffer {
+        let size = (width * height) as usize;
+        AovBuffer {
+            width,
+            height,
+            groups,
+            pixels: vec![AovPixel::new(); size],
+            light_groups: vec![Vec3::zero_vector(); size * groups]
+        }
+    }
+
+    /// Adds a sample to pixel `index`; `first` marks the pixel's first sample, which sets the IDs
+    pub fn add(&mut self, index: usize, sample: &SampleAovs, first: bool) {
+        let pixel = &mut self.pixels[index];
+        pixel.albedo = pixel.albedo + sample.albedo;
+        if let Some(depth) = sample.depth {
+            pixel.normal = pixel.normal + sample.normal;
+            pixel.position = pixel.position + sample.position;
+            pixel.depth += depth;
+            pixel.hits += 1;
+        }
+        if first {
+            pixel.object_id = sample.object_id;
+            pixel.material_id = sample.material_id;
+        }
+        for (sum, value) in self.light_groups[index * self.groups..(index + 1) * self.groups].iter_mut().zip(sample.light_groups.iter()) {
+            *sum = *sum + *value;
+        }
+    }
+
+    /// Adds the sums of a buffer covering `tile`
+    pub fn add_tile(&mut self, tile: &Tile, other: &AovBuffer) {
+        for row in 0..tile.height() {
+            for column in 0..tile.width() {
+                let source = (row * other.width + column) as usize;
+                let target = ((tile.y0 + row) * self.width + tile.x0 + column) as usize;
+                let (from, to) = (&other.pixels[source], &mut self.pixels[target]);
+                to.albedo = to.albedo + from.albedo;
+                to.normal = to.normal + from.normal;
+                to.position = to.position + from.position;
+                to.depth += from.depth;
+                to.hits += from.hits;
+                if from.object_id != 0 || from.material_id != 0 {
+                    to.object_id = from.object_id;
+                    to.material_id = from.material_id;
+                }
+                for g in 0..self.groups {
+                    let sum = &mut self.light_groups[target * self.groups + g];
+                    *sum = *sum + other.light_groups[source * self.groups + g];
+                }
+            }
+        }
+    }
+
+    /// Per pixel averages of a three component AOV; depth and the IDs are repeated in every
+    /// component. `pixels` gives the sample counts
+    pub fn image(&self, aov: Aov, pixels: &[PixelStats]) -> Vec<Vec3> {
+        self.pixels.iter().zip(pixels.iter()).map(|(p, stats)| {
+            let samples = stats.samples.max(1) as f32;
+            let hits = p.hits.max(1) as f32;
+            let repeat = |v: f32| Vec3::new(v, v, v);
+            match aov {
+                Aov::Albedo => p.albedo / samples,
+                Aov::Normal if p.hits > 0 => Vec3::unit_vector(p.normal),
+                Aov::Normal => Vec3::zero_vector(),
+                Aov::Position => p.position / hits,
+                Aov::Depth if p.hits > 0 => repeat(p.depth / hits),
+                Aov::Depth => repeat(f32::INFINITY),
+                Aov::ObjectId => repeat(p.object_id as f32),
+                Aov::MaterialId => repeat(p.material_id as f32),
+                Aov::LightGroups => Vec3::zero_vector()
+            }
+        }).collect()
+    }
+
+    pub fn light_group_image(&self, group: usize, pixels: &[PixelStats]) -> Vec<Vec3> {
+        pixels.iter().enumerate().map(|(index, stats)| {
+            self.light_groups[index * self.groups + group] / stats.samples.max(1) as f32
+        }).collect()
+    }
+
+    /// EXR channels for an AOV other than the light groups, as a layer named after it
+    pub fn channels(&self, aov: Aov, pixels: &[PixelStats]) -> Vec<Channel> {
+        let image = self.image(aov, pixels);
+        let names: &[&str] = match aov {
+            Aov::Albedo => &["R", "G", "B"],
+            Aov::Normal | Aov::Position => &["X", "Y", "Z"],
+            Aov::Depth => &["Z"],
+            _ => &["id"]
+        };
+        names.iter().enumerate().map(|(i, name)| Channel {
+            name: format!("{}.{}", aov.name(), name),
+            values: image.iter().map(|v| [v.x(), v.y(), v.z()][i]).collect()
+        }).collect()
+    }
+
+    /// A viewable version of an AOV other than the albedo and light groups, in [0, 1]: normals
+    /// and positions mapped to colours, depth as grey from near (black) to far (white), IDs as
+    /// random colours
+    pub fn visualise(&self, aov: Aov, pixels: &[PixelStats]) -> Vec<Vec3> {
+        let image = self.image(aov, pixels);
+        match aov {
+            Aov::Normal => image.iter().zip(self.pixels.iter()).map(|(n, p)| {
+                if p.hits > 0 { 0.5 * *n + Vec3::new(0.5, 0.5, 0.5) } else { Vec3::zero_vector() }
+            }).collect(),
+            Aov::Depth => {
+                let far = image.iter().map(|d| d.x()).filter(|d| d.is_finite()).fold(0.0, f32::max);
+                image.iter().map(|d| if d.x().is_finite() && far > 0.0 { *d / far } else { Vec3::new(1.0, 1.0, 1.0) }).collect()
+            },
+            Aov::Position => {
+                let hit: Vec<Vec3> = image.iter().zip(self.pixels.iter()).filter(|(_, p)| p.hits > 0).map(|(v, _)| *v).collect();
+                let fold = |f: fn(f32, f32) -> f32, start: f32| hit.iter().fold(Vec3::new(start, start, start), |a, v| {
+                    Vec3::new(f(a.x(), v.x()), f(a.y(), v.y()), f(a.z(), v.z()))
+                });
+                let (low, high) = (fold(f32::min, f32::INFINITY), fold(f32::max, f32::NEG_INFINITY));
+                let size = high - low;
+                let scale = |v: f32, s: f32| if s > 0.0 { v / s } else { 0.0 };
+                image.iter().zip(self.pixels.iter()).map(|(v, p)| {
+                    if p.hits == 0 {
+                        return Vec3::zero_vector();
+                    }
+                    let d = *v - low;
+                    Vec3::new(scale(d.x(), size.x()), scale(d.y(), size.y()), scale(d.z(), size.z()))
+                }).collect()
+            },
+            Aov::ObjectId => self.pixels.iter().map(|p| id_colour(p.object_id)).collect(),
+            Aov::MaterialId => self.pixels.iter().map(|p| id_colour(p.material_id)).collect(),
+            _ => image
+        }
+    }
+}
@@ -1,53 +1,532 @@
 use vec3::Vec3;
 use ray::Ray;
+use hitable::Hitable;
+use lens::{LensSystem, RealisticCamera};
+use error::{Error, Result};
+use std::f32::consts::PI;
+use std::sync::Arc;
+use rng;
+use sampler;
 
-extern crate rand;
+
+/// Height of a full frame sensor, used to relate the field of view to a focal length
+const SENSOR_HEIGHT_MM: f32 = 24.0;
+
+pub trait Camera {
+    /// Ray through the normalised image position (s, t), with (0, 0) at the bottom left.
+    /// Returns None for positions outside the projection, such as the corners of a fisheye
+    fn get_ray(&self, s: f32, t: f32) -> Option<Ray>;
+
+    /// Ray through (s, t) together with the factor its radiance is scaled by on the film.
+    /// Only cameras that model how much light reaches the film return anything but 1
+    fn get_weighted_ray(&self, s: f32, t: f32) -> Option<(Ray, f32)> {
+        self.get_ray(s, t).map(|r| (r, 1.0))
+    }
+}
+
+/// Assigns each ray of the wrapped camera a uniformly distributed time within the shutter interval
+pub struct ShutterCamera {
+    camera: Box<dyn Camera + Sync>,
+    open: f32,
+    close: f32
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Projection {
+    Perspective,
+    Orthographic,
+    FisheyeEquidistant,
+    FisheyeEquisolid,
+    Equirectangular,
+    /// Six faces, right, left, up, down, front, back, side by side in a 6:1 image or as two
+    /// rows of three in a 3:2 one
+    Cubemap,
+    /// Traces rays through `CameraSettings::lens`
+    Realistic
+}
+
+/// Orthonormal camera basis; `w` points backwards, away from the look-at point
+#[derive(Clone, Copy, Debug)]
+pub struct Basis {
+    pub u: Vec3,
+    pub v: Vec3,
+    pub w: Vec3
+}
 
 #[derive(Debug)]
-pub struct Camera {
+pub struct PerspectiveCamera {
     origin: Vec3,
     lower_left_corner: Vec3,
     horizontal: Vec3,
     vertical: Vec3,
     u: Vec3,
     v: Vec3,
-    w: Vec3,
-    lens_radius: f32
+    lens_radius: f32,
+    blades: u32,
+    blade_rotation: f32
+}
+
+#[derive(Debug)]
+pub struct OrthographicCamera {
+    lower_left_corner: Vec3,
+    horizontal: Vec3,
+    vertical: Vec3,
+    direction: Vec3
+}
+
+#[derive(Debug)]
+pub struct FisheyeCamera {
+    origin: Vec3,
+    basis: Basis,
+    aspect: f32,
+    half_fov: f32,
+    equisolid: bool
+}
+
+#[derive(Debug)]
+pub struct EquirectangularCamera {
+    origin: Vec3,
+    basis: Basis
+}
+
+#[derive(Debug)]
+pub struct CubemapCamera {
+    origin: Vec3,
+    basis: Basis,
+    /// Faces per row, 6 or 3
+    columns: u32
+}
+
+/// User facing camera description, resolved into a `Camera` once the image size and world are known
+#[derive(Clone, Debug)]
+pub struct CameraSettings {
+    pub projection: Projection,
+    pub lookfrom: Vec3,
+    pub lookat: Vec3,
+    pub vup: Vec3,
+    /// Vertical field of view; for fisheye projections the field of view across the image circle
+    pub vfov: f32,
+    /// Lens diameter in scene units, ignored when `fstop` is set
+    pub aperture: f32,
+    /// F-number, assuming scene units are metres
+    pub fstop: Option<f32>,
+    /// Distance to the plane in focus, defaults to the look-at point
+    pub focus_dist: Option<f32>,
+    /// Pixel to autofocus on with a probe ray, overrides `focus_dist` when it hits something
+    pub focus_pixel: Option<(u32, u32)>,
+    /// Number of aperture blades, 0 for a circular aperture
+    pub blades: u32,
+    pub blade_rotation: f32,
+    /// Shutter interval in scene time, a zero length interval disables motion blur
+    pub shutter_open: f32,
+    pub shutter_close: f32,
+    /// Lens prescription for the realistic projection
+    pub lens: Option<Arc<LensSystem>>
+}
+
+/// Uniform sample inside a regular polygon inscribed in the unit disk
+fn random_in_unit_polygon(sides: u32, rotation: f32) -> Vec3 {
+    //The fraction left over after picking a side positions the sample along it, so the
+    //polygon takes two dimensions like the disk does
+    let pick = rng::random() * sides as f32;
+    let side = (pick as u32).min(sides - 1);
+    let step = 2.0 * PI / sides as f32;
+    let a0 = rotation + side as f32 * step;
+    let a1 = a0 + step;
+    let v0 = Vec3::new(a0.cos(), a0.sin(), 0.0);
+    let v1 = Vec3::new(a1.cos(), a1.sin(), 0.0);
+    let su = rng::random().sqrt();
+    let t = (pick - side as f32).min(1.0);
+    su * (1.0 - t) * v0 + su * t * v1
 }
 
 fn random_in_unit_disk() -> Vec3 {
-    loop {
-        let p = 2.0 * Vec3::new(rand::random::<f32>(), rand::random::<f32>(), 0.0) - Vec3::new(1.0, 1.0, 0.0);
-        if p.squared_length() < 1.0 {
-            return p;
+    let r = rng::random().sqrt();
+    let phi = 2.0 * PI * rng::random();
+    Vec3::new(r * phi.cos(), r * phi.sin(), 0.0)
+}
+
+impl Basis {
+    pub fn new(lookfrom: Vec3, lookat: Vec3, vup: Vec3) -> Basis {
+        let w = Vec3::unit_vector(lookfrom - lookat);
+        let u = Vec3::unit_vector(vup.cross(w));
+        let v = w.cross(u);
+        Basis {
+            u,
+            v,
+            w
         }
     }
+
+    pub fn forward(&self) -> Vec3 {
+        -1.0 * self.w
+    }
 }
 
-impl Camera {
-    pub fn new(lookfrom: Vec3, lookat: Vec3, vup: Vec3, vfov: f32, aspect: f32, aperture: f32, focus_dist: f32) -> Camera {
+impl Projection {
+    pub fn from_name(name: &str) -> Option<Projection> {
+        match name {
+            "perspective" => Some(Projection::Perspective),
+            "orthographic" => Some(Projection::Orthographic),
+            "fisheye" | "fisheye-equidistant" => Some(Projection::FisheyeEquidistant),
+            "fisheye-equisolid" => Some(Projection::FisheyeEquisolid),
+            "equirectangular" => Some(Projection::Equirectangular),
+            "cubemap" => Some(Projection::Cubemap),
+            _ => None
+        }
+    }
+}
+
+impl PerspectiveCamera {
+    pub fn new(lookfrom: Vec3, lookat: Vec3, vup: Vec3, vfov: f32, aspect: f32, aperture: f32, focus_dist: f32) -> PerspectiveCamera {
         let theta = vfov.to_radians();
         let half_height = (theta/2.0).tan();
         let half_width = aspect * half_height;
 
-        let w = Vec3::unit_vector(lookfrom - lookat);
-        let u = Vec3::unit_vector(vup.cross(w));
-        let v = w.cross(u);
-        Camera {
+        let Basis { u, v, w } = Basis::new(lookfrom, lookat, vup);
+        PerspectiveCamera {
             origin: lookfrom,
             lower_left_corner: lookfrom - half_width*focus_dist*u - half_height*focus_dist*v - focus_dist*w,
             horizontal: 2.0 * half_width * focus_dist * u,
             vertical: 2.0 * half_height * focus_dist * v,
             u,
             v,
-            w,
-            lens_radius: aperture / 2.0
+            lens_radius: aperture / 2.0,
+            blades: 0,
+            blade_rotation: 0.0
         }
     }
 
-    pub fn get_ray(&self, s: f32, t: f32) -> Ray {
-        let rd = self.lens_radius * random_in_unit_disk();
+    /// Shapes the aperture as a regular polygon for polygonal bokeh
+    pub fn with_blades(mut self, blades: u32, rotation: f32) -> PerspectiveCamera {
+        self.blades = if blades >= 3 { blades } else { 0 };
+        self.blade_rotation = rotation.to_radians();
+        self
+    }
+}
+
+impl Camera for PerspectiveCamera {
+    fn get_ray(&self, s: f32, t: f32) -> Option<Ray> {
+        let lens = if self.blades >= 3 {
+            random_in_unit_polygon(self.blades, self.blade_rotation)
+        } else {
+            random_in_unit_disk()
+        };
+        let rd = self.lens_radius * lens;
         let offset = self.u * rd.x() + self.v*rd.y();
-        Ray::new(self.origin + offset, self.lower_left_corner + s*self.horizontal + t*self.vertical - self.origin - offset)
+        Some(Ray::new(self.origin + offset, self.lower_left_corner + s*self.horizontal + t*self.vertical - self.origin - offset, 0.0))
+    }
+}
+
+impl ShutterCamera {
+    pub fn new(camera: Box<dyn Camera + Sync>, open: f32, close: f32) -> ShutterCamera {
+        ShutterCamera {
+            camera,
+            open,
+            close
+        }
+    }
+}
+
+impl ShutterCamera {
+    fn time(&self) -> f32 {
+        rng::start_dimensions(sampler::CAMERA_DIMENSION + sampler::TIME_OFFSET, 1);
+        let time = self.open + rng::random() * (self.close - self.open);
+        //Hand the lens dimensions to the wrapped camera
+        rng::start_dimensions(sampler::CAMERA_DIMENSION + sampler::LENS_OFFSET, sampler::LENS_DIMENSIONS);
+        time
+    }
+}
+
+impl Camera for ShutterCamera {
+    fn get_ray(&self, s: f32, t: f32) -> Option<Ray> {
+        self.get_weighted_ray(s, t).map(|(r, _)| r)
+    }
+
+    fn get_weighted_ray(&self, s: f32, t: f32) -> Option<(Ray, f32)> {
+        let time = self.time();
+        self.camera.get_weighted_ray(s, t).map(|(r, weight)| (Ray::new(r.origin(), r.direction(), time), weight))
+    }
+}
+
+impl OrthographicCamera {
+    /// `height` is the extent of the view in scene units
+    pub fn new(lookfrom: Vec3, lookat: Vec3, vup: Vec3, height: f32, aspect: f32) -> OrthographicCamera {
+        let basis = Basis::new(lookfrom, lookat, vup);
+        let half_height = 0.5 * height;
+        let half_width = aspect * half_height;
+        OrthographicCamera {
+            lower_left_corner: lookfrom - half_width * basis.u - half_height * basis.v,
+            horizontal: 2.0 * half_width * basis.u,
+            vertical: 2.0 * half_height * basis.v,
+            direction: basis.forward()
+        }
+    }
+}
+
+impl Camera for OrthographicCamera {
+    fn get_ray(&self, s: f32, t: f32) -> Option<Ray> {
+        Some(Ray::new(self.lower_left_corner + s * self.horizontal + t * self.vertical, self.direction, 0.0))
+    }
+}
+
+impl FisheyeCamera {
+    /// `fov` spans the image circle inscribed in the shorter side of the image
+    pub fn new(lookfrom: Vec3, lookat: Vec3, vup: Vec3, fov: f32, aspect: f32, equisolid: bool) -> FisheyeCamera {
+        FisheyeCamera {
+            origin: lookfrom,
+            basis: Basis::new(lookfrom, lookat, vup),
+            aspect,
+            half_fov: 0.5 * fov.to_radians().min(2.0 * PI),
+            equisolid
+        }
+    }
+}
+
+impl Camera for FisheyeCamera {
+    fn get_ray(&self, s: f32, t: f32) -> Option<Ray> {
+        let (x, y) = if self.aspect >= 1.0 {
+            ((2.0 * s - 1.0) * self.aspect, 2.0 * t - 1.0)
+        } else {
+            (2.0 * s - 1.0, (2.0 * t - 1.0) / self.aspect)
+        };
+        let r = (x * x + y * y).sqrt();
+        if r > 1.0 {
+            return None;
+        }
+        let theta = if self.equisolid {
+            //r = 2 sin(theta / 2), normalised so the edge of the circle is at half_fov
+            2.0 * (r * (0.5 * self.half_fov).sin()).min(1.0).asin()
+        } else {
+            r * self.half_fov
+        };
+        let phi = y.atan2(x);
+        let b = &self.basis;
+        let direction = theta.sin() * (phi.cos() * b.u + phi.sin() * b.v) + theta.cos() * b.forward();
+        Some(Ray::new(self.origin, direction, 0.0))
+    }
+}
+
+impl EquirectangularCamera {
+    pub fn new(lookfrom: Vec3, lookat: Vec3, vup: Vec3) -> EquirectangularCamera {
+        EquirectangularCamera {
+            origin: lookfrom,
+            basis: Basis::new(lookfrom, lookat, vup)
+        }
+    }
+}
+
+impl Camera for EquirectangularCamera {
+    fn get_ray(&self, s: f32, t: f32) -> Option<Ray> {
+        //Longitude 0 is straight ahead in the centre of the image
+        let phi = (s - 0.5) * 2.0 * PI;
+        let theta = (t - 0.5) * PI;
+        let b = &self.basis;
+        let direction = theta.cos() * (phi.sin() * b.u + phi.cos() * b.forward()) + theta.sin() * b.v;
+        Some(Ray::new(self.origin, direction, 0.0))
+    }
+}
+
+impl CubemapCamera {
+    /// Lays the faces out in a row for images wider than 3:1, otherwise in two rows
+    pub fn new(lookfrom: Vec3, lookat: Vec3, vup: Vec3, aspect: f32) -> CubemapCamera {
+        CubemapCamera {
+            origin: lookfrom,
+            basis: Basis::new(lookfrom, lookat, vup),
+            columns: if aspect > 3.0 { 6 } else { 3 }
+        }
+    }
+
+    /// Whether a width x height image holds square faces in one of the layouts
+    pub fn fits(width: u32, height: u32) -> bool {
+        let (width, height) = (width as u64, height as u64);
+        width == 6 * height || 2 * width == 3 * height
+    }
+}
+
+impl Camera for CubemapCamera {
+    fn get_ray(&self, s: f32, t: f32) -> Option<Ray> {
+        let rows = 6 / self.columns;
+        let (across, down) = (s * self.columns as f32, t * rows as f32);
+        let (column, row) = ((across as u32).min(self.columns - 1), (down as u32).min(rows - 1));
+        //Faces are numbered along the top row first
+        let face = (rows - 1 - row) * self.columns + column;
+        let a = 2.0 * (across - column as f32) - 1.0;
+        let c = 2.0 * (down - row as f32) - 1.0;
+        let b = &self.basis;
+        let f = b.forward();
+        //(forward, right, up) of each face
+        let (forward, right, up) = match face {
+            0 => (b.u, b.w, b.v),
+            1 => (-1.0 * b.u, f, b.v),
+            2 => (b.v, b.u, b.w),
+            3 => (-1.0 * b.v, b.u, f),
+            4 => (f, b.u, b.v),
+            _ => (b.w, -1.0 * b.u, b.v)
+        };
+        Some(Ray::new(self.origin, forward + a * right + c * up, 0.0))
     }
 }
+
+impl Default for CameraSettings {
+    fn default() -> CameraSettings {
+        CameraSettings {
+            lookfrom: 3.0 * Vec3::new(-2.267_884_3, 0.320_256_86, 1.835_032),
+            lookat: Vec3::new(-1.336_433_4, 0.320_256_86, 1.471_164_7),
+            vup: Vec3::new(0.0, 1.0, 0.0),
+            vfov: 20.0,
+            aperture: 0.0,
+            fstop: None,
+            focus_dist: None,
+            focus_pixel: None,
+            blades: 0,
+            blade_rotation: 0.0,
+            projection: Projection::Perspective,
+            shutter_open: 0.0,
+            shutter_close: 0.0,
+            lens: None
+        }
+    }
+}
+
+impl CameraSettings {
+    pub fn focal_length_mm(&self) -> f32 {
+        0.5 * SENSOR_HEIGHT_MM / (0.5 * self.vfov.to_radians()).tan()
+    }
+
+    pub fn aperture_diameter(&self) -> f32 {
+        match self.fstop {
+            Some(n) => self.focal_length_mm() / n / 1000.0,
+            None => self.aperture
+        }
+    }
+
+    fn projection_camera(&self, aspect: f32, aperture: Option<f32>, focus_dist: f32) -> Result<Box<dyn Camera + Sync>> {
+        let (from, at, up) = (self.lookfrom, self.lookat, self.vup);
+        Ok(match self.projection {
+            Projection::Perspective => Box::new(PerspectiveCamera::new(from, at, up, self.vfov, aspect, aperture.unwrap_or(0.0), focus_dist)
+                .with_blades(self.blades, self.blade_rotation)),
+            Projection::Orthographic => {
+                //Frame the same extent at the look-at point as the perspective camera would
+                let height = 2.0 * (from - at).length() * (0.5 * self.vfov.to_radians()).tan();
+                Box::new(OrthographicCamera::new(from, at, up, height, aspect))
+            },
+            Projection::FisheyeEquidistant => Box::new(FisheyeCamera::new(from, at, up, self.vfov, aspect, false)),
+            Projection::FisheyeEquisolid => Box::new(FisheyeCamera::new(from, at, up, self.vfov, aspect, true)),
+            Projection::Equirectangular => Box::new(EquirectangularCamera::new(from, at, up)),
+            Projection::Cubemap => Box::new(CubemapCamera::new(from, at, up, aspect)),
+            Projection::Realistic => {
+                let lens = self.lens.clone().ok_or_else(|| Error::scene("the realistic projection needs a lens file"))?;
+                //An explicit aperture or f-stop replaces the stop diameter of the prescription
+                let lens = match aperture {
+                    Some(diameter) => Arc::new(lens.with_stop_diameter(diameter)),
+                    None => lens
+                };
+                Box::new(RealisticCamera::new(lens, from, at, up, SENSOR_HEIGHT_MM / 1000.0, aspect, focus_dist))
+            }
+        })
+    }
+
+    /// Distance along the view axis to whatever is visible through the centre of `pixel`
+    fn autofocus(&self, pixel: (u32, u32), width: u32, height: u32, world: &(dyn Hitable + Sync)) -> Option<f32> {
+        let pinhole = self.projection_camera(width as f32 / height as f32, None, 1.0).ok()?;
+        let u = (pixel.0 as f32 + 0.5) / width as f32;
+        let v = 1.0 - (pixel.1 as f32 + 0.5) / height as f32;
+        //Lens systems may vignette the probe, so allow a few attempts
+        let probe = (0..16).filter_map(|_| pinhole.get_ray(u, v)).next()?;
+        let probe = Ray::new(probe.origin(), probe.direction(), self.shutter_open);
+        let forward = Basis::new(self.lookfrom, self.lookat, self.vup).forward();
+        world.hit(0.001, f32::MAX, &probe).map(|hit| (hit.p - self.lookfrom).dot(forward))
+    }
+
+    pub fn build(&self, width: u32, height: u32, world: &(dyn Hitable + Sync)) -> Result<Box<dyn Camera + Sync>> {
+        if self.projection == Projection::Cubemap && !CubemapCamera::fits(width, height) {
+            return Err(Error::Usage(format!("a cubemap needs a 6:1 or 3:2 image, such as {}x{} or {}x{}, not {}x{}",
+                                            6 * height, height, 3 * height / 2, height, width, height)));
+        }
+        if let Some((x, y)) = self.focus_pixel {
+            if x >= width || y >= height {
+                return Err(Error::scene(format!("focus pixel {} {} is outside the {}x{} image", x, y, width, height)));
+            }
+        }
+        let focus_dist = self.focus_pixel
+            .and_then(|pixel| self.autofocus(pixel, width, height, world))
+            .or(self.focus_dist)
+            .unwrap_or_else(|| (self.lookfrom - self.lookat).length());
+        let aperture = if self.fstop.is_some() || self.aperture > 0.0 {
+            Some(self.aperture_diameter())
+        } else {
+            None
+        };
+        let camera = self.projection_camera(width as f32 / height as f32, aperture, focus_dist)?;
+        if self.shutter_close > self.shutter_open || self.shutter_open != 0.0 {
+            Ok(Box::new(ShutterCamera::new(camera, self.shutter_open, self.shutter_close.max(self.shutter_open))))
+        } else {
+            Ok(camera)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sphere::Sphere;
+    use material::Lambertian;
+    use texture::ConstantTexture;
+
+    #[test]
+    fn ray_times_fall_inside_the_shutter() {
+        let (open, close) = (0.25, 0.75);
+        let perspective = PerspectiveCamera::new(Vec3::new(0.0, 0.0, 1.0), Vec3::zero_vector(), Vec3::new(0.0, 1.0, 0.0), 40.0, 1.5, 0.1, 1.0);
+        let camera = ShutterCamera::new(Box::new(perspective), open, close);
+        let times: Vec<f32> = (0..1000).map(|i| camera.get_ray(0.001 * i as f32, 0.5).unwrap().time()).collect();
+        assert!(times.iter().all(|&t| t >= open && t <= close), "{:?}", times);
+        //And cover the interval rather than sitting at one end
+        let mean = times.iter().sum::<f32>() / times.len() as f32;
+        assert!((mean - 0.5).abs() < 0.02, "{}", mean);
+        assert!(times.iter().any(|&t| t < 0.3) && times.iter().any(|&t| t > 0.7));
+
+        //Also when the time comes from a sampler's dimension
+        rng::set_sampler(Some(Arc::from(sampler::SamplerKind::Sobol.build(1, 64))));
+        for sample in 0..64 {
+            rng::start_sample(3, 5, sample);
+            rng::start_dimensions(sampler::CAMERA_DIMENSION, sampler::CAMERA_DIMENSIONS);
+            let time = camera.get_ray(0.5, 0.5).unwrap().time();
+            assert!(time >= open && time <= close, "{}", time);
+        }
+        rng::set_sampler(None);
+    }
+
+    #[test]
+    fn refuses_focus_pixels_outside_the_image() {
+        let world = Sphere::new(Vec3::new(0.0, 0.0, -5.0), 1.0, Box::new(Lambertian::new(Box::new(ConstantTexture::new(Vec3::new(0.5, 0.5, 0.5))))));
+        let settings = |pixel| CameraSettings { focus_pixel: Some(pixel), ..CameraSettings::default() };
+        assert!(settings((9, 4)).build(10, 5, &world).is_ok());
+        for &pixel in &[(10, 0), (0, 5)] {
+            assert!(matches!(settings(pixel).build(10, 5, &world), Err(Error::Scene { .. })), "{:?}", pixel);
+        }
+    }
+
+    #[test]
+    fn cubemaps_need_a_row_or_two_rows_of_square_faces() {
+        assert!(CubemapCamera::fits(600, 100) && CubemapCamera::fits(150, 100) && CubemapCamera::fits(3, 2));
+        assert!(!CubemapCamera::fits(480, 270) && !CubemapCamera::fits(100, 100) && !CubemapCamera::fits(601, 100));
+        let world = Sphere::new(Vec3::new(0.0, 0.0, -5.0), 1.0, Box::new(Lambertian::new(Box::new(ConstantTexture::new(Vec3::new(0.5, 0.5, 0.5))))));
+        let cubemap = CameraSettings { projection: Projection::Cubemap, ..CameraSettings::default() };
+        assert!(matches!(cubemap.build(480, 270, &world), Err(Error::Usage(_))));
+        assert!(cubemap.build(600, 100, &world).is_ok() && cubemap.build(300, 200, &world).is_ok());
+
+        //Both layouts look the same way through the centre of each face
+        let (from, at, up) = (Vec3::zero_vector(), Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.0, 1.0, 0.0));
+        let (row, grid) = (CubemapCamera::new(from, at, up, 6.0), CubemapCamera::new(from, at, up, 1.5));
+        for face in 0..6 {
+            let a = row.get_ray((face as f32 + 0.5) / 6.0, 0.5).unwrap().direction();
+            let b = grid.get_ray(((face % 3) as f32 + 0.5) / 3.0, 1.0 - ((face / 3) as f32 + 0.5) / 2.0).unwrap().direction();
+            assert!((Vec3::unit_vector(a) - Vec3::unit_vector(b)).length() < 1e-6, "face {}: {:?} {:?}", face, a, b);
+        }
+        //The front face looks at the look-at point
+        let front = row.get_ray(4.5 / 6.0, 0.5).unwrap().direction();
+        assert!((Vec3::unit_vector(front) - at).length() < 1e-6, "{:?}", front);
+    }
+}
+
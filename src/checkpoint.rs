@@ -0,0 +1,307 @@
+use vec3::Vec3;
+use framebuffer::{Framebuffer, PixelStats};
+use aov::{AovBuffer, AovPixel};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+/// Binary snapshot of a render in progress, little endian:
+///   magic, scene hash (u64), seed (u64), width and height (u32), then per pixel the radiance
+///   sum (3 x f32), luminance sum and sum of squares (2 x f64), sample count (u32) and the
+///   fixed point filtered radiance and weight (4 x i64). Then the number of light groups plus
+///   one, or 0 without AOVs (u32), and per pixel the AOV sums: albedo, normal and position
+///   (9 x f32), depth (f32), hits, object and material IDs (3 x u32) and the light groups.
+///   Last the number of median of means batches, 0 for none (u32), and per pixel their sums.
+/// Every sample's random stream is derived from the seed, pixel and sample index, so the seed
+/// and the per pixel counts are all the random state needed to carry on exactly
+const MAGIC: &[u8; 8] = b"PTCKPT04";
+
+pub struct Checkpoint {
+    pub scene_hash: u64,
+    pub seed: u64,
+    pub framebuffer: Framebuffer
+}
+
+/// FNV-1a, stable between runs and platforms unlike the std hasher
+pub struct SceneHasher {
+    state: u64
+}
+
+impl SceneHasher {
+    pub fn new() -> SceneHasher {
+        SceneHasher {
+            state: 0xcbf2_9ce4_8422_2325
+        }
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.state ^= b as u64;
+            self.state = self.state.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    pub fn finish(&self) -> u64 {
+        self.state
+    }
+}
+
+impl Default for SceneHasher {
+    fn default() -> SceneHasher {
+        SceneHasher::new()
+    }
+}
+
+pub fn read_u32(r: &mut dyn Read) -> io::Result<u32> {
+    let mut b = [0; 4];
+    r.read_exact(&mut b)?;
+    Ok(u32::from_le_bytes(b))
+}
+
+pub fn read_u64(r: &mut dyn Read) -> io::Result<u64> {
+    let mut b = [0; 8];
+    r.read_exact(&mut b)?;
+    Ok(u64::from_le_bytes(b))
+}
+
+pub fn read_f32(r: &mut dyn Read) -> io::Result<f32> {
+    Ok(f32::from_bits(read_u32(r)?))
+}
+
+pub fn read_f64(r: &mut dyn Read) -> io::Result<f64> {
+    Ok(f64::from_bits(read_u64(r)?))
+}
+
+pub fn read_vec3(r: &mut dyn Read) -> io::Result<Vec3> {
+    Ok(Vec3::new(read_f32(r)?, read_f32(r)?, read_f32(r)?))
+}
+
+pub fn write_vec3(w: &mut dyn Write, v: Vec3) -> io::Result<()> {
+    for c in [v.x(), v.y(), v.z()] {
+        w.write_all(&c.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+pub fn write_stats(w: &mut dyn Write, p: &PixelStats) -> io::Result<()> {
+    write_vec3(w, p.sum)?;
+    w.write_all(&p.luminance_sum.to_le_bytes())?;
+    w.write_all(&p.luminance_squares.to_le_bytes())?;
+    w.write_all(&p.samples.to_le_bytes())
+}
+
+pub fn read_stats(r: &mut dyn Read) -> io::Result<PixelStats> {
+    Ok(PixelStats {
+        sum: read_vec3(r)?,
+        luminance_sum: read_f64(r)?,
+        luminance_squares: read_f64(r)?,
+        samples: read_u32(r)?
+    })
+}
+
+/// Per pixel AOV sums, in the layout described above
+pub fn write_aovs(w: &mut dyn Write, aovs: &AovBuffer) -> io::Result<()> {
+    for (index, p) in aovs.pixels.iter().enumerate() {
+        let groups = &aovs.light_groups[index * aovs.groups..(index + 1) * aovs.groups];
+        for v in [p.albedo, p.normal, p.position].iter().chain(groups.iter()) {
+            write_vec3(w, *v)?;
+        }
+        w.write_all(&p.depth.to_le_bytes())?;
+        for v in [p.hits, p.object_id, p.material_id] {
+            w.write_all(&v.to_le_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+/// Fills in a buffer of the right size and number of light groups
+pub fn read_aovs(r: &mut dyn Read, aovs: &mut AovBuffer) -> io::Result<()> {
+    for index in 0..aovs.pixels.len() {
+        let (albedo, normal, position) = (read_vec3(r)?, read_vec3(r)?, read_vec3(r)?);
+        for g in 0..aovs.groups {
+            aovs.light_groups[index * aovs.groups + g] = read_vec3(r)?;
+        }
+        aovs.pixels[index] = AovPixel {
+            albedo,
+            normal,
+            position,
+            depth: read_f32(r)?,
+            hits: read_u32(r)?,
+            object_id: read_u32(r)?,
+            material_id: read_u32(r)?
+        };
+    }
+    Ok(())
+}
+
+/// Writes to a temporary file first so an interrupted save never replaces a good checkpoint
+pub fn save(path: &Path, scene_hash: u64, seed: u64, framebuffer: &Framebuffer) -> io::Result<()> {
+    let temporary = path.with_extension("tmp");
+    {
+        let mut w = BufWriter::new(File::create(&temporary)?);
+        w.write_all(MAGIC)?;
+        w.write_all(&scene_hash.to_le_bytes())?;
+        w.write_all(&seed.to_le_bytes())?;
+        w.write_all(&framebuffer.width.to_le_bytes())?;
+        w.write_all(&framebuffer.height.to_le_bytes())?;
+        for (p, filtered) in framebuffer.pixels.iter().zip(framebuffer.filtered.iter()) {
+            write_stats(&mut w, p)?;
+            for v in filtered {
+                w.write_all(&v.to_le_bytes())?;
+            }
+        }
+        match framebuffer.aovs.as_ref() {
+            Some(aovs) => {
+                w.write_all(&(aovs.groups as u32 + 1).to_le_bytes())?;
+                write_aovs(&mut w, aovs)?;
+            },
+            None => w.write_all(&0u32.to_le_bytes())?
+        }
+        match framebuffer.batches.as_ref() {
+            Some(batches) => {
+                w.write_all(&(batches.count as u32).to_le_bytes())?;
+                for v in batches.sums.iter() {
+                    write_vec3(&mut w, *v)?;
+                }
+            },
+            None => w.write_all(&0u32.to_le_bytes())?
+        }
+        w.flush()?;
+    }
+    fs::rename(&temporary, path)
+}
+
+fn read_header(r: &mut dyn Read) -> io::Result<(u64, u64)> {
+    let mut magic = [0; 8];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a render checkpoint"));
+    }
+    Ok((read_u64(r)?, read_u64(r)?))
+}
+
+/// Refuses a size stored in a checkpoint that differs from the render's
+fn expect(what: &str, found: u64, expected: u64) -> io::Result<()> {
+    if found != expected {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  format!("checkpoint's {} is {}, the render's is {}", what, found, expected)));
+    }
+    Ok(())
+}
+
+impl Checkpoint {
+    /// The seed a checkpoint was rendered with, without reading its pixels
+    pub fn read_seed(path: &Path) -> io::Result<u64> {
+        let (_, seed) = read_header(&mut BufReader::new(File::open(path)?))?;
+        Ok(seed)
+    }
+
+    /// Reads a checkpoint into `framebuffer`, a new one with the size, AOVs and batches of the
+    /// render it is to carry on. Sizes in the file are checked against it before anything is
+    /// read, so a checkpoint for another render, or a corrupt one, can't ask for any memory
+    pub fn load(path: &Path, mut framebuffer: Framebuffer) -> io::Result<Checkpoint> {
+        let mut r = BufReader::new(File::open(path)?);
+        let (scene_hash, seed) = read_header(&mut r)?;
+        expect("width", read_u32(&mut r)? as u64, framebuffer.width as u64)?;
+        expect("height", read_u32(&mut r)? as u64, framebuffer.height as u64)?;
+        for (p, filtered) in framebuffer.pixels.iter_mut().zip(framebuffer.filtered.iter_mut()) {
+            *p = read_stats(&mut r)?;
+            for v in filtered.iter_mut() {
+                *v = read_u64(&mut r)? as i64;
+            }
+        }
+        let groups = framebuffer.aovs.as_ref().map_or(0, |aovs| aovs.groups as u64 + 1);
+        expect("light group count plus one", read_u32(&mut r)? as u64, groups)?;
+        if let Some(aovs) = framebuffer.aovs.as_mut() {
+            read_aovs(&mut r, aovs)?;
+        }
+        let count = framebuffer.batches.as_ref().map_or(0, |batches| batches.count as u64);
+        expect("median of means batch count", read_u32(&mut r)? as u64, count)?;
+        if let Some(batches) = framebuffer.batches.as_mut() {
+            for v in batches.sums.iter_mut() {
+                *v = read_vec3(&mut r)?;
+            }
+        }
+        Ok(Checkpoint {
+            scene_hash,
+            seed,
+            framebuffer
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use job::RenderJob;
+    use render::render;
+    use std::env;
+
+    fn saved(name: &str, framebuffer: &Framebuffer) -> std::path::PathBuf {
+        let path = env::temp_dir().join(format!("pathtracer-{}-{}.ckpt", name, std::process::id()));
+        save(&path, 7, 3, framebuffer).unwrap();
+        path
+    }
+
+    #[test]
+    fn loads_what_was_saved() {
+        let mut framebuffer = Framebuffer::new(4, 3).with_aovs(1).with_batches(2);
+        framebuffer.pixels[5].samples = 9;
+        framebuffer.filtered[5] = [1, 2, 3, 4];
+        let path = saved("round-trip", &framebuffer);
+        let checkpoint = Checkpoint::load(&path, Framebuffer::new(4, 3).with_aovs(1).with_batches(2)).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!((checkpoint.scene_hash, checkpoint.seed), (7, 3));
+        assert_eq!(checkpoint.framebuffer.pixels[5].samples, 9);
+        assert_eq!(checkpoint.framebuffer.filtered[5], [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn refuses_other_sizes() {
+        let path = saved("sizes", &Framebuffer::new(4, 3).with_batches(2));
+        assert!(Checkpoint::load(&path, Framebuffer::new(3, 4).with_batches(2)).is_err());
+        assert!(Checkpoint::load(&path, Framebuffer::new(4, 3).with_aovs(0).with_batches(2)).is_err());
+        assert!(Checkpoint::load(&path, Framebuffer::new(4, 3).with_batches(3)).is_err());
+        assert!(Checkpoint::load(&path, Framebuffer::new(4, 3).with_batches(2)).is_ok());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn resuming_renders_the_same_image() {
+        let job = |spp: &str| {
+            let scene = format!("{}/scenes/ies_demo.scene", env!("CARGO_MANIFEST_DIR"));
+            let args: Vec<String> = ["rust-pathtracer-demo", &scene, "-w", "40", "-h", "24", "-s", spp, "--filter", "gaussian", "--denoise"]
+                .iter().map(|s| s.to_string()).collect();
+            RenderJob::from_args(&args, 3).unwrap()
+        };
+        let (start, finish) = (job("4"), job("16"));
+        assert_eq!(start.scene_hash, finish.scene_hash);
+        let partial = render(&start.scene.context(), &start.settings, start.framebuffer());
+        let path = env::temp_dir().join(format!("pathtracer-resume-{}.ckpt", std::process::id()));
+        save(&path, start.scene_hash, 3, &partial).unwrap();
+        let checkpoint = Checkpoint::load(&path, finish.framebuffer()).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!((checkpoint.scene_hash, checkpoint.seed), (finish.scene_hash, 3));
+
+        let resumed = render(&finish.scene.context(), &finish.settings, checkpoint.framebuffer);
+        let uninterrupted = render(&finish.scene.context(), &finish.settings, finish.framebuffer());
+        assert!(resumed.filtered == uninterrupted.filtered);
+        let aovs = |f: &Framebuffer| format!("{:?}", f.aovs.as_ref().unwrap().pixels);
+        assert_eq!(aovs(&resumed), aovs(&uninterrupted));
+        for (a, b) in resumed.pixels.iter().zip(uninterrupted.pixels.iter()) {
+            assert_eq!(format!("{:?}", a), format!("{:?}", b));
+        }
+    }
+
+    #[test]
+    fn refuses_huge_sizes_without_allocating() {
+        let path = env::temp_dir().join(format!("pathtracer-huge-{}.ckpt", std::process::id()));
+        let mut header = MAGIC.to_vec();
+        header.extend_from_slice(&[0; 16]);
+        header.extend_from_slice(&u32::MAX.to_le_bytes());
+        header.extend_from_slice(&u32::MAX.to_le_bytes());
+        fs::write(&path, &header).unwrap();
+        assert!(Checkpoint::load(&path, Framebuffer::new(4, 3)).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,245 @@
+use vec3::Vec3;
+use aov::{Aov, AovBuffer};
+use framebuffer::Framebuffer;
+
+use rayon::prelude::*;
+
+/// Edge-avoiding a-trous wavelet filter (Dammertz et al. 2010) with the variance guided
+/// luminance weights of SVGF (Schied et al. 2017). Lighting is divided by the albedo first and
+/// multiplied back afterwards, so textures stay sharp while the lighting is smoothed
+#[derive(Clone, Copy, Debug)]
+pub struct DenoiseSettings {
+    /// Filter passes; each doubles the spacing of the taps, so the footprint grows to about
+    /// 4 * 2^iterations pixels
+    pub iterations: u32,
+    /// Luminance differences, in standard deviations of the noise, that still blend freely
+    pub sigma_luminance: f32,
+    /// Exponent on the cosine between normals
+    pub sigma_normal: f32,
+    /// Depth differences, relative to what the local depth gradient predicts
+    pub sigma_depth: f32,
+    pub sigma_albedo: f32
+}
+
+impl Default for DenoiseSettings {
+    fn default() -> DenoiseSettings {
+        DenoiseSettings {
+            iterations: 4,
+            sigma_luminance: 2.0,
+            sigma_normal: 128.0,
+            sigma_depth: 1.0,
+            sigma_albedo: 0.1
+        }
+    }
+}
+
+/// B3 spline taps
+const KERNEL: [f32; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+
+/// Per pixel guides, fixed for every pass
+struct Guides {
+    width: usize,
+    height: usize,
+    albedo: Vec<Vec3>,
+    normal: Vec<Vec3>,
+    depth: Vec<f32>,
+    /// Screen space depth gradient, per pixel step in x and y
+    gradient: Vec<(f32, f32)>
+}
+
+impl Guides {
+    fn new(aovs: &AovBuffer, framebuffer: &Framebuffer) -> Guides {
+        let (width, height) = (framebuffer.width as usize, framebuffer.height as usize);
+        let depth: Vec<f32> = aovs.image(Aov::Depth, &framebuffer.pixels).iter().map(|d| d.x()).collect();
+        let at = |x: isize, y: isize| -> Option<f32> {
+            if x < 0 || y < 0 || x >= width as isize || y >= height as isize {
+                return None;
+            }
+            Some(depth[y as usize * width + x as usize]).filter(|d| d.is_finite())
+        };
+        //Central differences, one sided at edges and silhouettes
+        let slope = |before: Option<f32>, here: f32, after: Option<f32>| match (before, after) {
+            (Some(b), Some(a)) => 0.5 * (a - b),
+            (Some(b), None) => here - b,
+            (None, Some(a)) => a - here,
+            (None, None) => 0.0
+        };
+        let mut gradient = Vec::with_capacity(width * height);
+        for y in 0..height as isize {
+            for x in 0..width as isize {
+                gradient.push(match at(x, y) {
+                    Some(here) => (slope(at(x - 1, y), here, at(x + 1, y)), slope(at(x, y - 1), here, at(x, y + 1))),
+                    None => (0.0, 0.0)
+                });
+            }
+        }
+        Guides {
+            width,
+            height,
+            albedo: aovs.image(Aov::Albedo, &framebuffer.pixels),
+            normal: aovs.image(Aov::Normal, &framebuffer.pixels),
+            depth,
+            gradient
+        }
+    }
+
+    fn hit(&self, index: usize) -> bool {
+        self.depth[index].is_finite()
+    }
+
+    /// How likely pixel q, at (dx, dy) from p, is to lie on the same surface as p: the product
+    /// of the depth and normal weights. Zero when only one of them sees the background
+    fn geometry_weight(&self, p: usize, q: usize, dx: isize, dy: isize, settings: &DenoiseSettings) -> f32 {
+        if self.hit(p) != self.hit(q) {
+            return 0.0;
+        }
+        if !self.hit(p) || p == q {
+            return 1.0;
+        }
+        let (gx, gy) = self.gradient[p];
+        let expected = (gx * dx as f32 + gy * dy as f32).abs();
+        let depth_difference = (self.depth[p] - self.depth[q]).abs();
+        let w_depth = (-depth_difference / (settings.sigma_depth * expected + 1e-3 * self.depth[p])).exp();
+        let w_normal = self.normal[p].dot(self.normal[q]).max(0.0).powf(settings.sigma_normal);
+        w_depth * w_normal
+    }
+}
+
+/// Divides out the albedo, leaving channels with next to no albedo as they are
+fn demodulate_factor(albedo: Vec3) -> Vec3 {
+    let f = |a: f32| if a > 0.01 { a } else { 1.0 };
+    Vec3::new(f(albedo.x()), f(albedo.y()), f(albedo.z()))
+}
+
+fn divide(a: Vec3, b: Vec3) -> Vec3 {
+    Vec3::new(a.x() / b.x(), a.y() / b.y(), a.z() / b.z())
+}
+
+/// 3x3 Gaussian blur of the variance, which steadies the luminance weights
+fn blur_variance(variance: &[f32], width: usize, height: usize) -> Vec<f32> {
+    const TAPS: [f32; 3] = [0.25, 0.5, 0.25];
+    (0..width * height).into_par_iter().map(|index| {
+        let (x, y) = ((index % width) as isize, (index / width) as isize);
+        let (mut sum, mut total) = (0.0, 0.0);
+        for dy in -1..=1isize {
+            for dx in -1..=1isize {
+                let (qx, qy) = (x + dx, y + dy);
+                if qx < 0 || qy < 0 || qx >= width as isize || qy >= height as isize {
+                    continue;
+                }
+                let k = TAPS[(dx + 1) as usize] * TAPS[(dy + 1) as usize];
+                sum += k * variance[qy as usize * width + qx as usize];
+                total += k;
+            }
+        }
+        sum / total
+    }).collect()
+}
+
+/// One a-trous pass with taps `step` pixels apart, returning the filtered colour and its variance
+fn filter_pass(colour: &[Vec3], variance: &[f32], guides: &Guides, step: isize, settings: &DenoiseSettings) -> (Vec<Vec3>, Vec<f32>) {
+    let (width, height) = (guides.width, guides.height);
+    let blurred = blur_variance(variance, width, height);
+    (0..width * height).into_par_iter().map(|p| {
+        let (x, y) = ((p % width) as isize, (p / width) as isize);
+        let luminance = colour[p].luminance();
+        let luminance_scale = settings.sigma_luminance * blurred[p].max(0.0).sqrt() + 1e-4;
+        let (mut sum, mut sum_variance, mut total) = (Vec3::zero_vector(), 0.0, 0.0);
+        for (j, ky) in KERNEL.iter().enumerate() {
+            for (i, kx) in KERNEL.iter().enumerate() {
+                let (dx, dy) = ((i as isize - 2) * step, (j as isize - 2) * step);
+                let (qx, qy) = (x + dx, y + dy);
+                if qx < 0 || qy < 0 || qx >= width as isize || qy >= height as isize {
+                    continue;
+                }
+                let q = qy as usize * width + qx as usize;
+                let w_geometry = guides.geometry_weight(p, q, dx, dy, settings);
+                if w_geometry <= 0.0 {
+                    continue;
+                }
+                let albedo_difference = (guides.albedo[p] - guides.albedo[q]).squared_length();
+                let w_albedo = (-albedo_difference / (settings.sigma_albedo * settings.sigma_albedo)).exp();
+                let w_luminance = (-(luminance - colour[q].luminance()).abs() / luminance_scale).exp();
+                let w = kx * ky * w_geometry * w_albedo * w_luminance;
+                sum = sum + w * colour[q];
+                sum_variance += w * w * variance[q];
+                total += w;
+            }
+        }
+        //The centre tap always has a positive weight
+        (sum / total, sum_variance / (total * total))
+    }).unzip()
+}
+
+/// Denoised linear radiance of the framebuffer, which must have been gathering AOVs
+pub fn denoise(framebuffer: &Framebuffer, settings: &DenoiseSettings) -> Vec<Vec3> {
+    let radiance = framebuffer.radiance();
+    let aovs = match framebuffer.aovs.as_ref() {
+        Some(a) => a,
+        None => return radiance
+    };
+    let guides = Guides::new(aovs, framebuffer);
+    let factors: Vec<Vec3> = guides.albedo.iter().map(|a| demodulate_factor(*a)).collect();
+    let mut colour: Vec<Vec3> = radiance.iter().zip(factors.iter()).map(|(c, f)| divide(*c, *f)).collect();
+    let mut variance: Vec<f32> = framebuffer.pixels.iter().zip(factors.iter()).map(|(p, f)| {
+        let scale = f.luminance().max(1e-3);
+        p.variance_of_mean() / (scale * scale)
+    }).collect();
+    for iteration in 0..settings.iterations {
+        let (c, v) = filter_pass(&colour, &variance, &guides, 1 << iteration, settings);
+        colour = c;
+        variance = v;
+    }
+    colour.iter().zip(factors.iter()).map(|(c, f)| *c * *f).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use render::{render, PreparedScene, RenderSettings};
+    use scene::Scene;
+    use std::path::PathBuf;
+
+    const WIDTH: u32 = 64;
+    const HEIGHT: u32 = 36;
+
+    /// The IES demo at `spp` samples per pixel, with the AOVs the denoiser needs
+    fn render_demo(spp: usize) -> Framebuffer {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("scenes/ies_demo.scene");
+        let scene = Scene::load(&path).unwrap();
+        let groups = scene.light_groups.len();
+        let prepared = PreparedScene::new(scene.objects, scene.lights, &scene.camera, WIDTH, HEIGHT).unwrap();
+        let settings = RenderSettings::new(WIDTH, HEIGHT, spp);
+        render(&prepared.context(), &settings, Framebuffer::new(WIDTH, HEIGHT).with_aovs(groups))
+    }
+
+    /// Squared error of each pixel against the reference, relative to the reference's brightness
+    /// so the lit floor doesn't drown out the rest, sorted from smallest to largest
+    fn relative_errors(image: &[Vec3], reference: &[Vec3]) -> Vec<f64> {
+        let mut errors: Vec<f64> = image.iter().zip(reference.iter()).map(|(c, r)| {
+            let d = *c - *r;
+            let scale = r.luminance().max(0.01) as f64;
+            (d.x() * d.x() + d.y() * d.y() + d.z() * d.z()) as f64 / (scale * scale)
+        }).collect();
+        errors.sort_by(|a, b| a.total_cmp(b));
+        errors
+    }
+
+    fn mean(errors: &[f64]) -> f64 {
+        errors.iter().sum::<f64>() / errors.len() as f64
+    }
+
+    /// Most pixels should end up far closer to the reference. Pixels on the sphere's silhouette
+    /// owe their noise to how much of them each sample covered, which the normal and depth
+    /// guides keep the filter from smoothing over, so the mean is only required to improve
+    #[test]
+    fn brings_a_noisy_render_closer_to_the_reference() {
+        let reference = render_demo(256).radiance();
+        let noisy = render_demo(4);
+        let before = relative_errors(&noisy.radiance(), &reference);
+        let after = relative_errors(&denoise(&noisy, &DenoiseSettings::default()), &reference);
+        let (median_before, median_after) = (before[before.len() / 2], after[after.len() / 2]);
+        assert!(median_after < 0.25 * median_before, "median error went from {} to {}", median_before, median_after);
+        assert!(mean(&after) < mean(&before), "mean error went from {} to {}", mean(&before), mean(&after));
+    }
+}
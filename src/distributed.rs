@@ -0,0 +1,535 @@
+use framebuffer::{BatchSums, Splats, TileBuffer};
+use aov::AovBuffer;
+use stats::RenderStats;
+use tile::Tile;
+use render;
+use checkpoint::{self, read_u32, read_u64};
+use job::RenderJob;
+use error::Error;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rayon::prelude::*;
+
+/// Rendering spread over worker processes. A coordinator connects to each worker and sends the
+/// command line it was started with, its seed and its scene hash. The worker loads the scene from
+/// the same path, which must hash the same, then renders the batches of tiles the coordinator
+/// sends and returns their sums to be merged as if the tiles were rendered locally. All little
+/// endian:
+///
+/// ```text
+///   job:      magic, scene hash and seed (2 x u64), argument count (u32), each argument as a
+///             byte count (u32) and UTF-8
+///   reply:    status (u8), then the worker's thread count (u32) or an error message
+///   batch:    tile count (u32, 0 ends the job), pass total (u32), milliseconds left before the
+///             deadline (u64, all ones for none), light groups plus one or 0 without AOVs (u32),
+///             median of means batches (u32), then per tile its bounds (4 x u32) and pixel stats
+///   rendered: status (u8), then per tile its pixel stats, splats (4 x i64 each), AOV sums, batch
+///             sums, the samples it took (u64) and its render statistics (8 x u64), or an error
+///             message
+/// ```
+///
+/// Statuses are 0 for done and 1 for an error. While loading the scene or rendering a batch
+/// the worker sends a 2 every `HEARTBEAT`, so the coordinator can tell a slow worker from one
+/// that has gone away
+const MAGIC: &[u8; 8] = b"PTJOB004";
+
+const NO_DEADLINE: u64 = u64::MAX;
+
+const BUSY: u8 = 2;
+const HEARTBEAT: Duration = Duration::from_secs(2);
+
+/// How long the coordinator waits for a worker to connect, accept data or show it is still
+/// busy before giving up on it and rendering its tiles locally
+const TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Largest argument or error message, and most arguments, a worker accepts in a job
+const MAX_STRING: u32 = 1 << 16;
+const MAX_ARGS: u32 = 1 << 12;
+
+/// What a coordinator asks a worker to render
+pub struct Job {
+    pub scene_hash: u64,
+    pub seed: u64,
+    pub args: Vec<String>
+}
+
+fn write_string(w: &mut dyn Write, s: &str) -> io::Result<()> {
+    w.write_all(&(s.len() as u32).to_le_bytes())?;
+    w.write_all(s.as_bytes())
+}
+
+fn read_string(r: &mut dyn Read) -> io::Result<String> {
+    let length = read_u32(r)?;
+    if length > MAX_STRING {
+        return Err(invalid("string too long"));
+    }
+    let mut bytes = vec![0; length as usize];
+    r.read_exact(&mut bytes)?;
+    String::from_utf8(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn read_u8(r: &mut dyn Read) -> io::Result<u8> {
+    let mut b = [0; 1];
+    r.read_exact(&mut b)?;
+    Ok(b[0])
+}
+
+/// A status byte of 0, or 1 followed by the worker's error message, after any number of
+/// heartbeats
+fn read_status(r: &mut dyn Read) -> io::Result<()> {
+    loop {
+        match read_u8(r)? {
+            0 => return Ok(()),
+            BUSY => continue,
+            _ => return Err(io::Error::other(read_string(r)?))
+        }
+    }
+}
+
+/// Runs `work` while telling the coordinator every `HEARTBEAT` that the worker is still busy.
+/// Nothing else may be written to the stream until it returns
+fn with_heartbeat<T, F: FnOnce() -> T>(stream: &TcpStream, work: F) -> io::Result<T> {
+    let mut beat = stream.try_clone()?;
+    let (stop, stopped) = mpsc::channel::<()>();
+    Ok(thread::scope(|s| {
+        s.spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(HEARTBEAT) {
+                if beat.write_all(&[BUSY]).is_err() {
+                    break;
+                }
+            }
+        });
+        let result = work();
+        drop(stop);
+        result
+    }))
+}
+
+fn write_error(w: &mut dyn Write, message: &str) -> io::Result<()> {
+    w.write_all(&[1])?;
+    write_string(w, message)?;
+    w.flush()
+}
+
+/// Says what a socket timeout means, as some platforms report one as WouldBlock
+fn timed_out(e: io::Error) -> io::Error {
+    match e.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut =>
+            io::Error::new(io::ErrorKind::TimedOut, format!("no word from the worker for {} seconds", TIMEOUT.as_secs())),
+        _ => e
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn write_tile(w: &mut dyn Write, buffer: &TileBuffer) -> io::Result<()> {
+    for p in buffer.pixels.iter() {
+        checkpoint::write_stats(w, p)?;
+    }
+    for sum in buffer.splats.sums.iter() {
+        for v in sum {
+            w.write_all(&v.to_le_bytes())?;
+        }
+    }
+    if let Some(aovs) = buffer.aovs.as_ref() {
+        checkpoint::write_aovs(w, aovs)?;
+    }
+    if let Some(batches) = buffer.batches.as_ref() {
+        for v in batches.sums.iter() {
+            checkpoint::write_vec3(w, *v)?;
+        }
+    }
+    w.write_all(&buffer.samples.to_le_bytes())?;
+    for v in buffer.stats.counters().iter() {
+        w.write_all(&v.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn read_tile(r: &mut dyn Read, buffer: &mut TileBuffer) -> io::Result<()> {
+    for p in buffer.pixels.iter_mut() {
+        *p = checkpoint::read_stats(r)?;
+    }
+    for sum in buffer.splats.sums.iter_mut() {
+        for v in sum.iter_mut() {
+            *v = read_u64(r)? as i64;
+        }
+    }
+    if let Some(aovs) = buffer.aovs.as_mut() {
+        checkpoint::read_aovs(r, aovs)?;
+    }
+    if let Some(batches) = buffer.batches.as_mut() {
+        for v in batches.sums.iter_mut() {
+            *v = checkpoint::read_vec3(r)?;
+        }
+    }
+    buffer.samples = read_u64(r)?;
+    for v in buffer.stats.counters_mut() {
+        *v = read_u64(r)?;
+    }
+    Ok(())
+}
+
+/// A connection to a worker that has accepted a job
+pub struct RemoteWorker {
+    pub address: String,
+    pub threads: usize,
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+    /// Set once the connection fails; its tiles are then rendered locally
+    pub failed: bool,
+    /// Tiles the worker has sent back rendered
+    pub tiles_rendered: usize
+}
+
+impl RemoteWorker {
+    /// Sends the worker a job and waits for it to load the scene. Every read and write on the
+    /// connection from here on fails if the worker goes quiet for longer than `TIMEOUT`
+    pub fn connect(address: &str, job: &Job) -> io::Result<RemoteWorker> {
+        RemoteWorker::start(address, job).map_err(timed_out)
+    }
+
+    fn start(address: &str, job: &Job) -> io::Result<RemoteWorker> {
+        let mut last_error = invalid("address resolved to nothing");
+        let mut connected = None;
+        for socket_address in address.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&socket_address, TIMEOUT) {
+                Ok(stream) => {
+                    connected = Some(stream);
+                    break;
+                },
+                Err(e) => last_error = e
+            }
+        }
+        let stream = connected.ok_or(last_error)?;
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        let mut writer = BufWriter::new(stream.try_clone()?);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&job.scene_hash.to_le_bytes())?;
+        writer.write_all(&job.seed.to_le_bytes())?;
+        writer.write_all(&(job.args.len() as u32).to_le_bytes())?;
+        for arg in job.args.iter() {
+            write_string(&mut writer, arg)?;
+        }
+        writer.flush()?;
+
+        let mut reader = BufReader::new(stream);
+        read_status(&mut reader)?;
+        let threads = read_u32(&mut reader)?.max(1) as usize;
+        Ok(RemoteWorker {
+            address: address.to_string(),
+            threads,
+            reader,
+            writer,
+            failed: false,
+            tiles_rendered: 0
+        })
+    }
+
+    /// Has the worker render one pass worth of samples into each tile's buffer, as
+    /// `render::render_tile` would
+    pub fn render_tiles(&mut self, tiles: &[Tile], buffers: &mut [TileBuffer], pass_total: u32, deadline: Option<Instant>) -> io::Result<()> {
+        self.exchange(tiles, buffers, pass_total, deadline).map_err(timed_out)?;
+        self.tiles_rendered += tiles.len();
+        Ok(())
+    }
+
+    fn exchange(&mut self, tiles: &[Tile], buffers: &mut [TileBuffer], pass_total: u32, deadline: Option<Instant>) -> io::Result<()> {
+        let remaining = deadline.map_or(NO_DEADLINE, |d| d.saturating_duration_since(Instant::now()).as_millis() as u64);
+        let w = &mut self.writer;
+        w.write_all(&(tiles.len() as u32).to_le_bytes())?;
+        w.write_all(&pass_total.to_le_bytes())?;
+        w.write_all(&remaining.to_le_bytes())?;
+        let groups = buffers[0].aovs.as_ref().map_or(0, |a| a.groups as u32 + 1);
+        w.write_all(&groups.to_le_bytes())?;
+        w.write_all(&(buffers[0].batches.as_ref().map_or(0, |b| b.count as u32)).to_le_bytes())?;
+        for (tile, buffer) in tiles.iter().zip(buffers.iter()) {
+            for v in [tile.x0, tile.y0, tile.x1, tile.y1] {
+                w.write_all(&v.to_le_bytes())?;
+            }
+            for p in buffer.pixels.iter() {
+                checkpoint::write_stats(w, p)?;
+            }
+        }
+        w.flush()?;
+
+        read_status(&mut self.reader)?;
+        for buffer in buffers.iter_mut() {
+            read_tile(&mut self.reader, buffer)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for RemoteWorker {
+    fn drop(&mut self) {
+        if !self.failed {
+            let _ = self.writer.write_all(&0u32.to_le_bytes()).and_then(|_| self.writer.flush());
+        }
+    }
+}
+
+/// Accepts coordinators on `address`, each on its own thread, which hands the job to `start`
+pub fn listen(address: &str, start: fn(TcpStream)) -> io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+    println!("Worker listening on {}", listener.local_addr()?);
+    accept(listener, start)
+}
+
+fn accept(listener: TcpListener, start: fn(TcpStream)) -> io::Result<()> {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                thread::spawn(move || start(stream));
+            },
+            Err(e) => eprintln!("Failed to accept a connection: {}", e)
+        }
+    }
+    Ok(())
+}
+
+/// Helps any coordinator that connects to `address` with its render, as long as the scene files
+/// here are the same as the coordinator's
+pub fn serve(address: &str) -> io::Result<()> {
+    listen(address, serve_job)
+}
+
+/// Takes on one coordinator's job, as long as the scene here is the same as the coordinator's
+fn serve_job(mut stream: TcpStream) {
+    let peer = stream.peer_addr().map(|a| a.to_string()).unwrap_or_default();
+    let request = match read_job(&mut stream) {
+        Ok(request) => request,
+        Err(e) => {
+            eprintln!("Bad job from {}: {}", peer, e);
+            return;
+        }
+    };
+    let job = match with_heartbeat(&stream, || RenderJob::from_args(&request.args, request.seed)) {
+        Ok(job) => job,
+        Err(e) => {
+            eprintln!("Lost {} while loading the scene: {}", peer, e);
+            return;
+        }
+    };
+    let job = match job {
+        Ok(job) if job.scene_hash != request.scene_hash => Err(Error::Usage("Scene files or settings differ from the coordinator's".to_string())),
+        job => job
+    };
+    match job {
+        Ok(job) => {
+            println!("Rendering {} for {}", job.filename, peer);
+            match serve_tiles(&mut stream, &job) {
+                Ok(()) => println!("Finished the job for {}", peer),
+                Err(e) => eprintln!("Job for {} failed: {}", peer, e)
+            }
+        },
+        Err(e) => {
+            eprintln!("Refused a job from {}: {}", peer, e);
+            let _ = refuse(&mut stream, &e.to_string());
+        }
+    }
+}
+
+/// Reads a coordinator's job. Lengths are checked before anything is allocated for them, and
+/// a coordinator that stops sending halfway is dropped after `TIMEOUT`
+pub fn read_job(stream: &mut TcpStream) -> io::Result<Job> {
+    stream.set_nodelay(true)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let job = read_job_from(&mut BufReader::new(&*stream));
+    //Between batches the coordinator may be busy with its own share for a long time
+    stream.set_read_timeout(None)?;
+    job
+}
+
+fn read_job_from(r: &mut dyn Read) -> io::Result<Job> {
+    let mut magic = [0; 8];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid("not a render job"));
+    }
+    let scene_hash = read_u64(r)?;
+    let seed = read_u64(r)?;
+    let count = read_u32(r)?;
+    if count > MAX_ARGS {
+        return Err(invalid("too many arguments"));
+    }
+    let args = (0..count).map(|_| read_string(r)).collect::<io::Result<Vec<String>>>()?;
+    Ok(Job {
+        scene_hash,
+        seed,
+        args
+    })
+}
+
+/// Tells the coordinator the job can't be done
+pub fn refuse(stream: &mut TcpStream, message: &str) -> io::Result<()> {
+    write_error(stream, message)
+}
+
+/// Accepts a job whose scene has been set up, then renders the tiles the coordinator sends
+/// until it is done. Batches must fit the job: tiles inside the image, no more pixels than it
+/// has and the AOVs and median of means batches it renders
+pub fn serve_tiles(stream: &mut TcpStream, job: &RenderJob) -> io::Result<()> {
+    let (scene, settings) = (&job.scene.context(), &job.settings);
+    let expected_groups = if !job.aovs.is_empty() || job.denoise { job.light_groups.len() as u32 + 1 } else { 0 };
+    let expected_batches = job.batches.unwrap_or(0) as u32;
+    let image_pixels = settings.width as usize * settings.height as usize;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    writer.write_all(&[0])?;
+    writer.write_all(&(rayon::current_num_threads() as u32).to_le_bytes())?;
+    writer.flush()?;
+
+    let reach = settings.filter.pixel_reach();
+    loop {
+        let count = read_u32(&mut reader)?;
+        if count == 0 {
+            return Ok(());
+        }
+        let pass_total = read_u32(&mut reader)?;
+        let remaining = read_u64(&mut reader)?;
+        let deadline = if remaining == NO_DEADLINE { None } else { Some(Instant::now() + Duration::from_millis(remaining)) };
+        let groups = read_u32(&mut reader)?;
+        let batches = read_u32(&mut reader)?;
+        if groups != expected_groups || batches != expected_batches {
+            write_error(&mut writer, "AOVs or batches differ from the job's")?;
+            return Err(invalid("AOVs or batches differ from the job's"));
+        }
+        let batches = batches as usize;
+
+        let mut tiles = Vec::new();
+        let mut buffers = Vec::new();
+        let mut pixels_read = 0;
+        for _ in 0..count {
+            let tile = Tile { x0: read_u32(&mut reader)?, y0: read_u32(&mut reader)?, x1: read_u32(&mut reader)?, y1: read_u32(&mut reader)? };
+            if tile.x0 >= tile.x1 || tile.y0 >= tile.y1 || tile.x1 > settings.width || tile.y1 > settings.height {
+                write_error(&mut writer, "tile outside the image")?;
+                return Err(invalid("tile outside the image"));
+            }
+            pixels_read += tile.pixel_count();
+            if pixels_read > image_pixels {
+                write_error(&mut writer, "batch larger than the image")?;
+                return Err(invalid("batch larger than the image"));
+            }
+            let pixels = (0..tile.pixel_count()).map(|_| checkpoint::read_stats(&mut reader)).collect::<io::Result<Vec<_>>>()?;
+            buffers.push(TileBuffer {
+                pixels,
+                splats: Splats::new(&tile, reach),
+                aovs: if groups > 0 { Some(AovBuffer::new(tile.width(), tile.height(), groups as usize - 1)) } else { None },
+                batches: if batches > 0 { Some(BatchSums::new(tile.pixel_count(), batches)) } else { None },
+                samples: 0,
+                stats: RenderStats::default()
+            });
+            tiles.push(tile);
+        }
+
+        with_heartbeat(writer.get_ref(), || {
+            buffers.par_iter_mut().zip(tiles.par_iter()).for_each(|(buffer, tile)| {
+                render::render_tile(scene, settings, tile, buffer, pass_total, deadline);
+            });
+        })?;
+
+        writer.write_all(&[0])?;
+        for buffer in buffers.iter() {
+            write_tile(&mut writer, buffer)?;
+        }
+        writer.flush()?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use framebuffer::Framebuffer;
+    use std::io::Cursor;
+    use std::net::SocketAddr;
+
+    fn job_header(count: u32) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&[0; 16]);
+        bytes.extend_from_slice(&count.to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn reads_a_job() {
+        let mut bytes = job_header(1);
+        bytes.extend_from_slice(&5u32.to_le_bytes());
+        bytes.extend_from_slice(b"hello");
+        let job = read_job_from(&mut Cursor::new(bytes)).unwrap();
+        assert_eq!(job.args, vec!["hello".to_string()]);
+    }
+
+    #[test]
+    fn refuses_oversized_lengths() {
+        assert!(read_job_from(&mut Cursor::new(job_header(u32::MAX))).is_err());
+        let mut bytes = job_header(1);
+        bytes.extend_from_slice(&u32::MAX.to_le_bytes());
+        assert!(read_job_from(&mut Cursor::new(bytes)).is_err());
+    }
+
+    #[test]
+    fn waits_through_heartbeats() {
+        assert!(read_status(&mut Cursor::new(vec![BUSY, BUSY, 0])).is_ok());
+        let mut failed = vec![BUSY, 1];
+        failed.extend_from_slice(&4u32.to_le_bytes());
+        failed.extend_from_slice(b"nope");
+        assert_eq!(read_status(&mut Cursor::new(failed)).unwrap_err().to_string(), "nope");
+    }
+
+    /// A worker serving jobs on a free local port
+    fn start_worker() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || accept(listener, serve_job));
+        address
+    }
+
+    fn demo_args() -> Vec<String> {
+        let scene = format!("{}/scenes/ies_demo.scene", env!("CARGO_MANIFEST_DIR"));
+        ["rust-pathtracer-demo", &scene, "-w", "48", "-h", "27", "-s", "8", "--tile-size", "8", "--filter", "gaussian"]
+            .iter().map(|s| s.to_string()).collect()
+    }
+
+    fn same_image(a: &Framebuffer, b: &Framebuffer) -> bool {
+        a.filtered == b.filtered && a.pixels.iter().zip(b.pixels.iter()).all(|(p, q)| p.samples == q.samples)
+    }
+
+    #[test]
+    fn workers_render_the_same_image() {
+        let address = start_worker();
+        let args = demo_args();
+        let job = RenderJob::from_args(&args, 0).unwrap();
+        let request = Job { scene_hash: job.scene_hash, seed: 0, args: args.clone() };
+        let mut workers = vec![RemoteWorker::connect(&address.to_string(), &request).unwrap()];
+        let context = job.scene.context();
+        //One local thread, so the worker gets a good share of the tiles
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+        let shared = pool.install(|| render::render_distributed(&context, &job.settings, job.framebuffer(), &mut workers, &|_| {}));
+        assert!(!workers[0].failed);
+        assert!(workers[0].tiles_rendered > 0);
+        let local = render::render(&context, &job.settings, job.framebuffer());
+        assert!(same_image(&shared, &local), "the worker changed the image");
+    }
+
+    #[test]
+    fn turns_away_a_different_scene() {
+        let address = start_worker();
+        let args = demo_args();
+        let job = RenderJob::from_args(&args, 0).unwrap();
+        let request = Job { scene_hash: job.scene_hash ^ 1, seed: 0, args };
+        match RemoteWorker::connect(&address.to_string(), &request) {
+            Ok(_) => panic!("a worker took on a job for another scene"),
+            Err(e) => assert!(e.to_string().contains("differ from the coordinator's"), "{}", e)
+        }
+    }
+}
@@ -0,0 +1,161 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::result;
+
+use png;
+use obj::ObjError;
+
+/// Everything that can stop a scene from loading, rendering or being saved. Errors that come
+/// from a file carry its path once it is known, so the message says which file was at fault
+#[derive(Debug)]
+pub enum Error {
+    /// Reading or writing a file or connection failed
+    Io(Option<PathBuf>, io::Error),
+    /// A scene, OBJ, lens, IES or image file is malformed
+    Parse {
+        path: Option<PathBuf>,
+        line: Option<usize>,
+        message: String
+    },
+    /// The scene was read but can't be rendered as described
+    Scene {
+        path: Option<PathBuf>,
+        message: String
+    },
+    /// An image could not be encoded
+    Encoding {
+        path: Option<PathBuf>,
+        message: String
+    },
+    /// A command line value or combination of inputs that makes no sense
+    Usage(String)
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+impl Error {
+    pub fn io(path: &Path, error: io::Error) -> Error {
+        Error::Io(Some(path.to_path_buf()), error)
+    }
+
+    pub fn parse<S: Into<String>>(message: S) -> Error {
+        Error::Parse { path: None, line: None, message: message.into() }
+    }
+
+    pub fn parse_line<S: Into<String>>(line: usize, message: S) -> Error {
+        Error::Parse { path: None, line: Some(line), message: message.into() }
+    }
+
+    pub fn scene<S: Into<String>>(message: S) -> Error {
+        Error::Scene { path: None, message: message.into() }
+    }
+
+    pub fn encoding<S: Into<String>>(message: S) -> Error {
+        Error::Encoding { path: None, message: message.into() }
+    }
+
+    /// Attributes the error to `path`, unless it already names the file it came from
+    pub fn in_file(self, file: &Path) -> Error {
+        let file = Some(file.to_path_buf());
+        match self {
+            Error::Io(None, e) => Error::Io(file, e),
+            Error::Parse { path: None, line, message } => Error::Parse { path: file, line, message },
+            Error::Scene { path: None, message } => Error::Scene { path: file, message },
+            Error::Encoding { path: None, message } => Error::Encoding { path: file, message },
+            e => e
+        }
+    }
+
+    /// Exit status for the command line, following the BSD sysexits convention
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Usage(_) => 64,
+            Error::Parse { .. } | Error::Scene { .. } => 65,
+            Error::Encoding { .. } => 70,
+            Error::Io(..) => 74
+        }
+    }
+}
+
+fn write_path(f: &mut fmt::Formatter, path: &Option<PathBuf>) -> fmt::Result {
+    match path {
+        Some(path) => write!(f, "{}: ", path.display()),
+        None => Ok(())
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(path, e) => {
+                write_path(f, path)?;
+                write!(f, "{}", e)
+            },
+            Error::Parse { path, line, message } => {
+                write_path(f, path)?;
+                if let Some(line) = line {
+                    write!(f, "line {}: ", line)?;
+                }
+                write!(f, "{}", message)
+            },
+            Error::Scene { path, message } => {
+                write_path(f, path)?;
+                write!(f, "invalid scene: {}", message)
+            },
+            Error::Encoding { path, message } => {
+                write_path(f, path)?;
+                write!(f, "could not encode the image: {}", message)
+            },
+            Error::Usage(message) => write!(f, "{}", message)
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io(_, e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(None, e)
+    }
+}
+
+impl From<png::EncodingError> for Error {
+    fn from(e: png::EncodingError) -> Error {
+        match e {
+            png::EncodingError::IoError(e) => Error::Io(None, e),
+            e => Error::encoding(e.to_string())
+        }
+    }
+}
+
+impl From<png::DecodingError> for Error {
+    fn from(e: png::DecodingError) -> Error {
+        match e {
+            png::DecodingError::IoError(e) => Error::Io(None, e),
+            e => Error::parse(e.to_string())
+        }
+    }
+}
+
+/// The obj crate counts lines from 0
+impl From<ObjError> for Error {
+    fn from(e: ObjError) -> Error {
+        match e {
+            ObjError::Io(e) => Error::Io(None, e),
+            ObjError::MalformedFaceGroup { line_number, group } => Error::parse_line(line_number + 1, format!("malformed face '{}'", group)),
+            ObjError::ArgumentListFailure { line_number, list } => Error::parse_line(line_number + 1, format!("invalid arguments '{}'", list)),
+            ObjError::UnexpectedCommand { line_number, command } => Error::parse_line(line_number + 1, format!("unknown command '{}'", command)),
+            ObjError::MissingMTLName { line_number } => Error::parse_line(line_number + 1, "mtllib needs a file name"),
+            ObjError::ZeroVertexNumber { line_number } => Error::parse_line(line_number + 1, "vertex indices start at 1, not 0")
+        }
+    }
+}
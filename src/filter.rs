@@ -0,0 +1,168 @@
+use std::f32::consts::PI;
+
+/// Pixel reconstruction filters. Each sample is weighted by the filter centred on every pixel
+/// within its radius and added to those pixels, so samples are shared between neighbours
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FilterKind {
+    Box,
+    Tent,
+    Gaussian,
+    /// Mitchell-Netravali with B = C = 1/3
+    Mitchell,
+    BlackmanHarris,
+    /// Sinc windowed by a wider sinc, with as many lobes as the radius
+    Lanczos
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Filter {
+    pub kind: FilterKind,
+    /// In pixels
+    pub radius: f32,
+    /// Integral of the profile across its width; dividing by it makes the filter integrate
+    /// to 1 over the square it covers
+    norm: f32
+}
+
+impl FilterKind {
+    pub fn from_name(name: &str) -> Option<FilterKind> {
+        match name {
+            "box" => Some(FilterKind::Box),
+            "tent" => Some(FilterKind::Tent),
+            "gaussian" => Some(FilterKind::Gaussian),
+            "mitchell" => Some(FilterKind::Mitchell),
+            "blackman-harris" => Some(FilterKind::BlackmanHarris),
+            "lanczos" => Some(FilterKind::Lanczos),
+            _ => None
+        }
+    }
+
+    pub fn default_radius(self) -> f32 {
+        match self {
+            FilterKind::Box => 0.5,
+            FilterKind::Tent => 1.0,
+            FilterKind::Gaussian => 1.5,
+            FilterKind::Mitchell => 2.0,
+            FilterKind::BlackmanHarris => 2.0,
+            FilterKind::Lanczos => 3.0
+        }
+    }
+}
+
+fn sinc(x: f32) -> f32 {
+    if x.abs() < 1e-5 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+impl Filter {
+    pub fn new(kind: FilterKind, radius: Option<f32>) -> Filter {
+        const STEPS: usize = 4096;
+        let radius = radius.unwrap_or_else(|| kind.default_radius()).max(0.5);
+        let filter = Filter {
+            kind,
+            radius,
+            norm: 1.0
+        };
+        let dx = 2.0 * radius / STEPS as f32;
+        let norm = (0..STEPS).map(|i| filter.profile(-radius + (i as f32 + 0.5) * dx) * dx).sum();
+        Filter {
+            norm,
+            ..filter
+        }
+    }
+
+    /// How many pixels either side of its own a sample can reach
+    pub fn pixel_reach(&self) -> i32 {
+        (self.radius - 0.5).ceil().max(0.0) as i32
+    }
+
+    /// One dimensional weight, normalised to integrate to 1; filters are separable
+    fn evaluate(&self, x: f32) -> f32 {
+        self.profile(x) / self.norm
+    }
+
+    fn profile(&self, x: f32) -> f32 {
+        let r = self.radius;
+        let x = x.abs();
+        if x > r {
+            return 0.0;
+        }
+        match self.kind {
+            FilterKind::Box => 1.0,
+            FilterKind::Tent => 1.0 - x / r,
+            FilterKind::Gaussian => {
+                //Sigma of a third of the radius, shifted down to reach zero at the edge
+                let sigma = r / 3.0;
+                let g = |x: f32| (-x * x / (2.0 * sigma * sigma)).exp();
+                g(x) - g(r)
+            },
+            FilterKind::Mitchell => {
+                let (b, c) = (1.0 / 3.0, 1.0 / 3.0);
+                let x = 2.0 * x / r;
+                if x > 1.0 {
+                    ((-b - 6.0 * c) * x * x * x + (6.0 * b + 30.0 * c) * x * x + (-12.0 * b - 48.0 * c) * x + (8.0 * b + 24.0 * c)) / 6.0
+                } else {
+                    ((12.0 - 9.0 * b - 6.0 * c) * x * x * x + (-18.0 + 12.0 * b + 6.0 * c) * x * x + (6.0 - 2.0 * b)) / 6.0
+                }
+            },
+            FilterKind::BlackmanHarris => {
+                let t = 0.5 + 0.5 * x / r;
+                let a = 2.0 * PI * t;
+                0.358_75 - 0.488_29 * a.cos() + 0.141_28 * (2.0 * a).cos() - 0.011_68 * (3.0 * a).cos()
+            },
+            FilterKind::Lanczos => sinc(x) * sinc(x / r)
+        }
+    }
+
+    /// Weights of a sample at offset `position` (in [0, 1)) inside its pixel for the pixels
+    /// from -pixel_reach to +pixel_reach along one axis
+    pub fn weights(&self, position: f32, weights: &mut Vec<f32>) {
+        let reach = self.pixel_reach();
+        weights.clear();
+        weights.extend((-reach..=reach).map(|i| self.evaluate(i as f32 + 0.5 - position)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KINDS: [FilterKind; 6] = [FilterKind::Box, FilterKind::Tent, FilterKind::Gaussian, FilterKind::Mitchell, FilterKind::BlackmanHarris, FilterKind::Lanczos];
+
+    #[test]
+    fn integrates_to_one() {
+        for &kind in KINDS.iter() {
+            for &radius in &[None, Some(0.7), Some(2.5)] {
+                let filter = Filter::new(kind, radius);
+                let r = filter.radius;
+                let steps = 400;
+                let dx = 2.0 * r / steps as f32;
+                let line: f32 = (0..steps).map(|i| filter.evaluate(-r + (i as f32 + 0.5) * dx) * dx).sum();
+                assert!((line - 1.0).abs() < 1e-3, "{:?} {:?} {}", kind, radius, line);
+                //Separable, so the square integrates to the line squared
+                let square: f64 = (0..steps).flat_map(|i| (0..steps).map(move |j| (i, j)))
+                    .map(|(i, j)| (filter.evaluate(-r + (i as f32 + 0.5) * dx) * filter.evaluate(-r + (j as f32 + 0.5) * dx) * dx * dx) as f64)
+                    .sum();
+                assert!((square - 1.0).abs() < 2e-3, "{:?} {:?} {}", kind, radius, square);
+            }
+        }
+    }
+
+    #[test]
+    fn weights_cover_the_reach() {
+        let mut weights = Vec::new();
+        for &kind in KINDS.iter() {
+            let filter = Filter::new(kind, None);
+            filter.weights(0.25, &mut weights);
+            assert_eq!(weights.len(), 2 * filter.pixel_reach() as usize + 1);
+            //Pixel i's centre sits at i + 0.5 - position from the sample
+            for (i, w) in weights.iter().enumerate() {
+                let x = (i as i32 - filter.pixel_reach()) as f32 + 0.25;
+                assert_eq!(*w, filter.evaluate(x), "{:?} {}", kind, i);
+            }
+        }
+    }
+}
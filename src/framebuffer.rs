@@ -0,0 +1,424 @@
+use vec3::Vec3;
+use tile::Tile;
+use aov::AovBuffer;
+use stats::RenderStats;
+
+/// Running totals for one pixel, enough to give its mean and an estimate of its error
+#[derive(Clone, Copy, Debug)]
+pub struct PixelStats {
+    pub sum: Vec3,
+    pub luminance_sum: f64,
+    pub luminance_squares: f64,
+    pub samples: u32
+}
+
+/// Filter weighted radiance and the total weight of one pixel, in fixed point. Samples land in
+/// neighbouring pixels from other tiles in whatever order the threads finish; integer sums
+/// don't depend on that order, which keeps renders repeatable
+pub type FilteredSum = [i64; 4];
+
+pub const FIXED_POINT_ONE: f64 = (1u64 << 24) as f64;
+
+/// Largest image rendered or read back, 16384 x 16384 pixels. Sizes from the command line or a
+/// file are checked against it before anything is allocated
+pub const MAX_PIXELS: u32 = 1 << 28;
+
+/// Pixels in a width x height image, or None above `MAX_PIXELS`
+pub fn pixel_count(width: u32, height: u32) -> Option<usize> {
+    width.checked_mul(height).filter(|n| *n <= MAX_PIXELS).map(|n| n as usize)
+}
+
+/// Row-major, top-down accumulation of linear radiance. Each pixel holds sums rather than a
+/// finished value, so more samples can be added at any time and a snapshot is always valid.
+/// `pixels` only counts the samples taken inside each pixel, for sample counts and noise
+/// estimates; the image itself comes from the filtered sums
+#[derive(Clone)]
+pub struct Framebuffer {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<PixelStats>,
+    pub filtered: Vec<FilteredSum>,
+    pub aovs: Option<AovBuffer>,
+    pub batches: Option<BatchSums>,
+    /// Counted by the tiles rendered into it since it was made or loaded
+    pub stats: RenderStats
+}
+
+/// Everything rendering a tile changes: a copy of its pixels and what its samples add around
+/// them, so the tile can be rendered without holding on to the framebuffer
+pub struct TileBuffer {
+    pub pixels: Vec<PixelStats>,
+    pub splats: Splats,
+    pub aovs: Option<AovBuffer>,
+    pub batches: Option<BatchSums>,
+    /// Samples taken rendering it, for progress reports
+    pub samples: u64,
+    pub stats: RenderStats
+}
+
+/// Radiance sums of each pixel's samples dealt round-robin into batches, sample i going to batch
+/// i % count, for the median of means estimator
+#[derive(Clone)]
+pub struct BatchSums {
+    pub count: usize,
+    /// `count` sums per pixel
+    pub sums: Vec<Vec3>
+}
+
+/// Filtered samples from one tile, covering the tile plus the filter's reach on every side
+pub struct Splats {
+    x0: i32,
+    y0: i32,
+    width: i32,
+    height: i32,
+    pub sums: Vec<FilteredSum>
+}
+
+impl PixelStats {
+    pub fn new() -> PixelStats {
+        PixelStats {
+            sum: Vec3::zero_vector(),
+            luminance_sum: 0.0,
+            luminance_squares: 0.0,
+            samples: 0
+        }
+    }
+
+    pub fn add(&mut self, radiance: Vec3) {
+        let luminance = radiance.luminance() as f64;
+        self.sum = self.sum + radiance;
+        self.luminance_sum += luminance;
+        self.luminance_squares += luminance * luminance;
+        self.samples += 1;
+    }
+
+    pub fn mean(&self) -> Vec3 {
+        if self.samples == 0 {
+            return Vec3::zero_vector();
+        }
+        self.sum / self.samples as f32
+    }
+
+    /// Sample variance of the luminance divided by the sample count, needs two samples
+    fn luminance_variance_of_mean(&self) -> f64 {
+        let n = self.samples as f64;
+        let mean = self.luminance_sum / n;
+        let variance = ((self.luminance_squares - self.luminance_sum * mean) / (n - 1.0)).max(0.0);
+        variance / n
+    }
+
+    /// Estimated variance of the mean luminance; with fewer than two samples there is nothing to
+    /// go on, so the mean's square stands in for it
+    pub fn variance_of_mean(&self) -> f32 {
+        if self.samples < 2 {
+            let mean = self.mean().luminance();
+            return mean * mean;
+        }
+        self.luminance_variance_of_mean() as f32
+    }
+
+    /// Standard error of the mean luminance, scaled by the slope of the square root display
+    /// curve so it reads roughly as a fraction of the displayed brightness
+    pub fn error(&self) -> f32 {
+        if self.samples < 2 {
+            return f32::INFINITY;
+        }
+        let mean = self.luminance_sum / self.samples as f64;
+        let standard_error = self.luminance_variance_of_mean().sqrt();
+        (standard_error / (2.0 * mean.max(1e-4).sqrt())) as f32
+    }
+}
+
+impl Default for PixelStats {
+    fn default() -> PixelStats {
+        PixelStats::new()
+    }
+}
+
+impl Splats {
+    pub fn new(tile: &Tile, reach: i32) -> Splats {
+        let width = tile.width() as i32 + 2 * reach;
+        let height = tile.height() as i32 + 2 * reach;
+        Splats {
+            x0: tile.x0 as i32 - reach,
+            y0: tile.y0 as i32 - reach,
+            width,
+            height,
+            sums: vec![[0; 4]; (width * height) as usize]
+        }
+    }
+
+    /// Adds weighted radiance to pixel (x, row); it must be within the tile's reach. Samples
+    /// with an infinite or NaN component are dropped whole, and sums saturate at the limits
+    /// of i64 rather than wrapping, so a single runaway sample can't flip a pixel's sign
+    pub fn add(&mut self, x: i32, row: i32, radiance: Vec3, weight: f32) {
+        let w = weight as f64;
+        let values = [radiance.x() as f64 * w, radiance.y() as f64 * w, radiance.z() as f64 * w, w];
+        if !values.iter().all(|v| v.is_finite()) {
+            return;
+        }
+        let index = ((row - self.y0) * self.width + (x - self.x0)) as usize;
+        let sum = &mut self.sums[index];
+        for (total, value) in sum.iter_mut().zip(values) {
+            //Casts saturate, so values past the range of i64 are held at its ends
+            *total = total.saturating_add((value * FIXED_POINT_ONE).round() as i64);
+        }
+    }
+}
+
+impl BatchSums {
+    pub fn new(pixels: usize, count: usize) -> BatchSums {
+        BatchSums {
+            count,
+            sums: vec![Vec3::zero_vector(); pixels * count]
+        }
+    }
+
+    pub fn add(&mut self, pixel: usize, sample: u32, radiance: Vec3) {
+        let sum = &mut self.sums[pixel * self.count + sample as usize % self.count];
+        *sum = *sum + radiance;
+    }
+
+    /// The batch mean with the median luminance, or the mean of the middle two, for a pixel
+    /// with `samples` samples. Unlike the plain mean, a handful of extreme samples can only
+    /// spoil the batches they fall in, so fireflies are dropped rather than smeared
+    pub fn median_of_means(&self, pixel: usize, samples: u32) -> Vec3 {
+        let sums = &self.sums[pixel * self.count..(pixel + 1) * self.count];
+        let mut means: Vec<Vec3> = sums.iter().enumerate().map(|(batch, sum)| {
+            let taken = (samples as usize + self.count - 1 - batch) / self.count;
+            *sum / taken.max(1) as f32
+        }).collect();
+        means.sort_by(|a, b| a.luminance().total_cmp(&b.luminance()));
+        let middle = means.len() / 2;
+        if means.len() % 2 == 1 {
+            means[middle]
+        } else {
+            0.5 * (means[middle - 1] + means[middle])
+        }
+    }
+}
+
+impl Framebuffer {
+    pub fn new(width: u32, height: u32) -> Framebuffer {
+        let size = width as usize * height as usize;
+        Framebuffer {
+            width,
+            height,
+            pixels: vec![PixelStats::new(); size],
+            filtered: vec![[0; 4]; size],
+            aovs: None,
+            batches: None,
+            stats: RenderStats::default()
+        }
+    }
+
+    /// Also keeps sums for the median of means estimator
+    pub fn with_batches(mut self, count: usize) -> Framebuffer {
+        self.batches = Some(BatchSums::new(self.pixels.len(), count));
+        self
+    }
+
+    /// Also gathers AOVs, with the given number of light groups
+    pub fn with_aovs(mut self, light_groups: usize) -> Framebuffer {
+        self.aovs = Some(AovBuffer::new(self.width, self.height, light_groups));
+        self
+    }
+
+    /// Sets up a tile for rendering, with room for splats `reach` pixels around it
+    pub fn start_tile(&self, tile: &Tile, reach: i32) -> TileBuffer {
+        TileBuffer {
+            pixels: self.read_tile(tile),
+            splats: Splats::new(tile, reach),
+            aovs: self.aovs.as_ref().map(|a| AovBuffer::new(tile.width(), tile.height(), a.groups)),
+            batches: self.batches.as_ref().map(|b| BatchSums::new(tile.pixel_count(), b.count)),
+            samples: 0,
+            stats: RenderStats::default()
+        }
+    }
+
+    /// Takes in a tile rendered from `start_tile`
+    pub fn finish_tile(&mut self, tile: &Tile, buffer: &TileBuffer) {
+        self.write_tile(tile, &buffer.pixels);
+        self.add_splats(&buffer.splats);
+        self.stats.add(&buffer.stats);
+        if let (Some(total), Some(aovs)) = (self.aovs.as_mut(), buffer.aovs.as_ref()) {
+            total.add_tile(tile, aovs);
+        }
+        if let (Some(total), Some(batches)) = (self.batches.as_mut(), buffer.batches.as_ref()) {
+            let row_sums = tile.width() as usize * total.count;
+            for (row, chunk) in batches.sums.chunks(row_sums).enumerate() {
+                let start = ((tile.y0 as usize + row) * self.width as usize + tile.x0 as usize) * total.count;
+                for (t, s) in total.sums[start..start + row_sums].iter_mut().zip(chunk.iter()) {
+                    *t = *t + *s;
+                }
+            }
+        }
+    }
+
+    /// Adds a tile's splats, dropping the parts that fall outside the image
+    pub fn add_splats(&mut self, splats: &Splats) {
+        for row in 0..splats.height {
+            let y = splats.y0 + row;
+            if y < 0 || y >= self.height as i32 {
+                continue;
+            }
+            for column in 0..splats.width {
+                let x = splats.x0 + column;
+                if x < 0 || x >= self.width as i32 {
+                    continue;
+                }
+                let source = &splats.sums[(row * splats.width + column) as usize];
+                let target = &mut self.filtered[(y * self.width as i32 + x) as usize];
+                for (t, s) in target.iter_mut().zip(source.iter()) {
+                    *t = t.saturating_add(*s);
+                }
+            }
+        }
+    }
+
+    /// Copies out a tile's pixels row by row
+    pub fn read_tile(&self, tile: &Tile) -> Vec<PixelStats> {
+        let mut pixels = Vec::with_capacity(tile.pixel_count());
+        for row in tile.y0..tile.y1 {
+            let start = (row * self.width + tile.x0) as usize;
+            pixels.extend_from_slice(&self.pixels[start..start + tile.width() as usize]);
+        }
+        pixels
+    }
+
+    /// Puts back pixels returned by `read_tile`
+    pub fn write_tile(&mut self, tile: &Tile, pixels: &[PixelStats]) {
+        let width = tile.width() as usize;
+        for (row, chunk) in pixels.chunks(width).enumerate() {
+            let start = (tile.y0 as usize + row) * self.width as usize + tile.x0 as usize;
+            self.pixels[start..start + width].copy_from_slice(chunk);
+        }
+    }
+
+    /// Filtered linear radiance of every pixel. Filters with negative lobes can leave a pixel
+    /// with next to no weight early on, those fall back to the mean of their own samples. With
+    /// batch sums, pixels with a sample in every batch take the median of means of their own
+    /// samples instead of the filtered value
+    pub fn radiance(&self) -> Vec<Vec3> {
+        self.pixels.iter().zip(self.filtered.iter()).enumerate().map(|(index, (p, f))| {
+            if let Some(batches) = self.batches.as_ref() {
+                if p.samples as usize >= batches.count {
+                    return batches.median_of_means(index, p.samples);
+                }
+            }
+            let weight = f[3] as f64;
+            if weight < 1e-3 * FIXED_POINT_ONE {
+                return p.mean();
+            }
+            Vec3::new((f[0] as f64 / weight) as f32, (f[1] as f64 / weight) as f32, (f[2] as f64 / weight) as f32)
+        }).collect()
+    }
+
+    pub fn mean_samples(&self) -> f32 {
+        let total: u64 = self.pixels.iter().map(|p| p.samples as u64).sum();
+        total as f32 / self.pixels.len().max(1) as f32
+    }
+
+    /// Sample counts as RGBA8 running black, red, yellow, white up to `max_samples`
+    pub fn sample_heatmap(&self, max_samples: u32) -> Vec<u8> {
+        let mut data = Vec::with_capacity(self.pixels.len() * 4);
+        for pixel in self.pixels.iter() {
+            let t = 3.0 * (pixel.samples as f32 / max_samples.max(1) as f32).min(1.0);
+            data.push((255.99 * t.min(1.0)) as u8);
+            data.push((255.99 * (t - 1.0).clamp(0.0, 1.0)) as u8);
+            data.push((255.99 * (t - 2.0).clamp(0.0, 1.0)) as u8);
+            data.push(255);
+        }
+        data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use filter::{Filter, FilterKind};
+
+    #[test]
+    fn spreads_a_splat_with_the_filter_weights() {
+        let (width, height) = (9, 9);
+        let tile = Tile { x0: 3, y0: 3, x1: 6, y1: 6 };
+        for &kind in &[FilterKind::Tent, FilterKind::Gaussian, FilterKind::Mitchell, FilterKind::Lanczos] {
+            let filter = Filter::new(kind, None);
+            let reach = filter.pixel_reach();
+            let mut splats = Splats::new(&tile, reach);
+            let (mut weights_x, mut weights_y) = (Vec::new(), Vec::new());
+            filter.weights(0.3, &mut weights_x);
+            filter.weights(0.6, &mut weights_y);
+            let radiance = Vec3::new(1.0, 2.0, 4.0);
+            for (j, wy) in weights_y.iter().enumerate() {
+                for (i, wx) in weights_x.iter().enumerate() {
+                    splats.add(4 + i as i32 - reach, 4 + j as i32 - reach, radiance, wx * wy);
+                }
+            }
+            let mut fb = Framebuffer::new(width, height);
+            fb.add_splats(&splats);
+
+            let mut total = 0;
+            for row in 0..height as i32 {
+                for x in 0..width as i32 {
+                    let (i, j) = (x - 4 + reach, row - 4 + reach);
+                    let inside = i >= 0 && j >= 0 && i < weights_x.len() as i32 && j < weights_y.len() as i32;
+                    let weight = if inside { weights_x[i as usize] * weights_y[j as usize] } else { 0.0 };
+                    let f = fb.filtered[(row * width as i32 + x) as usize];
+                    assert_eq!(f[3], (weight as f64 * FIXED_POINT_ONE).round() as i64, "{:?} {} {}", kind, x, row);
+                    assert_eq!(f[2], (4.0 * weight as f64 * FIXED_POINT_ONE).round() as i64, "{:?} {} {}", kind, x, row);
+                    total += f[3];
+                }
+            }
+            //Close to the filter's integral over a pixel wide square
+            let total = total as f64 / FIXED_POINT_ONE;
+            assert!(total > 0.8 && total < 1.2, "{:?} {}", kind, total);
+        }
+    }
+
+    #[test]
+    fn drops_non_finite_splats_and_saturates() {
+        let tile = Tile { x0: 0, y0: 0, x1: 1, y1: 1 };
+        let mut splats = Splats::new(&tile, 0);
+        splats.add(0, 0, Vec3::new(1.0, 1.0, 1.0), 1.0);
+        splats.add(0, 0, Vec3::new(f32::INFINITY, 0.0, 0.0), 1.0);
+        splats.add(0, 0, Vec3::new(f32::NAN, 0.0, 0.0), 1.0);
+        splats.add(0, 0, Vec3::new(1.0, 1.0, 1.0), f32::INFINITY);
+        let one = FIXED_POINT_ONE as i64;
+        assert_eq!(splats.sums[0], [one, one, one, one]);
+
+        splats.add(0, 0, Vec3::new(f32::MAX, 0.0, -f32::MAX), 1.0);
+        splats.add(0, 0, Vec3::new(f32::MAX, 0.0, -f32::MAX), 1.0);
+        assert_eq!(splats.sums[0], [i64::MAX, one, i64::MIN, 3 * one]);
+
+        let mut fb = Framebuffer::new(1, 1);
+        fb.add_splats(&splats);
+        fb.add_splats(&splats);
+        assert_eq!(fb.filtered[0], [i64::MAX, 2 * one, i64::MIN, 6 * one]);
+    }
+
+    #[test]
+    fn median_of_means_drops_a_firefly_batch() {
+        let grey = Vec3::new(0.5, 0.5, 0.5);
+        let firefly = Vec3::new(1000.0, 800.0, 900.0);
+        for &count in &[5, 4, 8] {
+            let mut batches = BatchSums::new(2, count);
+            let samples = 6 * count as u32;
+            for sample in 0..samples {
+                //Pixel 1 is the control without the firefly
+                batches.add(0, sample, if sample == 7 { firefly } else { grey });
+                batches.add(1, sample, grey);
+            }
+            let estimate = batches.median_of_means(0, samples);
+            assert!((estimate - grey).length() < 1e-6, "{} batches: {:?}", count, estimate);
+            assert!((batches.median_of_means(1, samples) - grey).length() < 1e-6);
+        }
+
+        //Fireflies in most of the batches are no longer outliers and come through
+        let mut batches = BatchSums::new(1, 5);
+        for sample in 0..10 {
+            batches.add(0, sample, if sample % 5 < 3 { firefly } else { grey });
+        }
+        assert!(batches.median_of_means(0, 10).x() > 100.0);
+    }
+}
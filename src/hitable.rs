@@ -5,20 +5,28 @@ use vec3::Vec3;
 use material::Material;
 use ray::Ray;
 use material::Isotropic;
+use light::Light;
+use transform::AnimatedTransform;
 use std::cmp::Ordering;
+use rng;
+use stats;
 
-extern crate rand;
 
 pub struct Hit<'a> {
     pub t: f32,
     pub p: Vec3,
     pub normal: Vec3,
-    pub material: &'a Box<(dyn Material + Sync)>
+    pub material: &'a (dyn Material + Sync),
+    /// Whether the surface is part of the light distribution, and so already sampled explicitly
+    pub explicit_light: bool
 }
 
 pub trait Hitable {
-    fn hit(&self, t_min: f32, t_max: f32, r: &Ray) -> Option<Hit>;
+    fn hit(&self, t_min: f32, t_max: f32, r: &Ray) -> Option<Hit<'_>>;
     fn bounding_box(&self) -> AABB;
+    fn as_light(&self) -> Option<Light> {
+        None
+    }
 }
 
 pub struct ConstantMedium {
@@ -27,6 +35,12 @@ pub struct ConstantMedium {
     material: Box<dyn Material + Sync>
 }
 
+/// Places an object in the world through a possibly animated transform
+pub struct Instance {
+    object: Box<dyn Hitable + Sync>,
+    transform: AnimatedTransform
+}
+
 pub struct BvhNode {
     left: Box<dyn Hitable + Sync>,
     right: Option<Box<dyn Hitable + Sync>>,
@@ -44,14 +58,9 @@ impl ConstantMedium {
 }
 
 impl Hitable for ConstantMedium {
-    fn hit(&self, t_min: f32, t_max: f32, r: &Ray) -> Option<Hit> {
-        let hit1 = self.boundary.hit(-1000.0, 1000.0, r);
-        if hit1.is_some() {
-            let mut hit1 = hit1.unwrap();
-            let hit2 = self.boundary.hit(hit1.t + 0.0001, 1000.0, r);
-
-            if hit2.is_some() {
-                let mut hit2 = hit2.unwrap();
+    fn hit(&self, t_min: f32, t_max: f32, r: &Ray) -> Option<Hit<'_>> {
+        if let Some(mut hit1) = self.boundary.hit(-1000.0, 1000.0, r) {
+            if let Some(mut hit2) = self.boundary.hit(hit1.t + 0.0001, 1000.0, r) {
                 if hit1.t < t_min {
                     hit1.t = t_min;
                 }
@@ -66,7 +75,7 @@ impl Hitable for ConstantMedium {
                 }
 
                 let distance_inside_boundary = (hit2.t - hit1.t) * r.direction().length();
-                let hit_distance = (-1.0/self.density) * rand::random::<f32>().ln();
+                let hit_distance = (-1.0/self.density) * rng::random().ln();
 
                 //println!("Distance inside boundary: {}, Hit distance: {}", distance_inside_boundary, hit_distance);
                 if hit_distance < distance_inside_boundary {
@@ -75,7 +84,8 @@ impl Hitable for ConstantMedium {
                         t,
                         p: r.point_at_parameter(t),
                         normal: Vec3::new(1.0, 0.0, 0.0), //arbitrary vector
-                        material: &self.material
+                        material: &*self.material,
+                        explicit_light: false
                     });
                 }
             }
@@ -88,8 +98,35 @@ impl Hitable for ConstantMedium {
     }
 }
 
+impl Instance {
+    pub fn new(object: Box<dyn Hitable + Sync>, transform: AnimatedTransform) -> Instance {
+        Instance {
+            object,
+            transform
+        }
+    }
+}
+
+impl Hitable for Instance {
+    fn hit(&self, t_min: f32, t_max: f32, r: &Ray) -> Option<Hit<'_>> {
+        let transform = self.transform.at(r.time());
+        let local = Ray::new(transform.inverse_point(r.origin()), transform.inverse_vector(r.direction()), r.time());
+        self.object.hit(t_min, t_max, &local).map(|mut hit| {
+            hit.p = r.point_at_parameter(hit.t);
+            hit.normal = Vec3::unit_vector(transform.apply_normal(hit.normal));
+            hit.explicit_light = false;
+            hit
+        })
+    }
+
+    fn bounding_box(&self) -> AABB {
+        self.transform.swept_bbox(self.object.bounding_box())
+    }
+}
+
 impl Hitable for BvhNode {
-    fn hit(&self, t_min: f32, t_max: f32, r: &Ray) -> Option<Hit> {
+    fn hit(&self, t_min: f32, t_max: f32, r: &Ray) -> Option<Hit<'_>> {
+        stats::count(|s| s.bvh_nodes += 1);
         let bbox_hit = self.bbox.hit(r, t_min, t_max);
         if bbox_hit {
             let left_hit = self.left.hit(t_min, t_max, r);
@@ -126,7 +163,7 @@ impl Hitable for BvhNode {
 
 impl BvhNode {
     pub fn new(mut list: Vec<Box<dyn Hitable + Sync>>) -> BvhNode {
-        let axis = (3.0 * rand::random::<f32>()) as u32;
+        let axis = (3.0 * rng::random()) as u32;
 
         //Sorting goes here
         if axis == 0 {
@@ -190,7 +227,7 @@ impl BvhNode {
             let left_bbox = left.bounding_box();
             let right_bbox = right.bounding_box();
             BvhNode {
-                left: left,
+                left,
                 right: Some(right),
                 bbox: surrounding_bbox(left_bbox, right_bbox)
             }
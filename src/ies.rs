@@ -0,0 +1,331 @@
+use vec3::Vec3;
+use error::{Error, Result};
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Candela distribution parsed from an IES LM-63 photometric file (type C photometry)
+#[derive(Debug)]
+pub struct IesProfile {
+    vertical_angles: Vec<f32>,
+    horizontal_angles: Vec<f32>,
+    /// Indexed [horizontal][vertical]
+    candela: Vec<Vec<f32>>,
+    max_candela: f32
+}
+
+/// An IES profile placed in the world. Vertical angle 0 points along `nadir`,
+/// horizontal angle 0 lies in the plane spanned by `nadir` and `c0`
+#[derive(Clone, Debug)]
+pub struct OrientedProfile {
+    profile: Arc<IesProfile>,
+    nadir: Vec3,
+    c0: Vec3,
+    c90: Vec3
+}
+
+/// More angles than any real photometric file has, so corrupt counts fail before allocating
+const MAX_COUNT: f32 = 10000.0;
+
+/// A count read from the file as a number
+fn count(value: f32, what: &str) -> Result<usize> {
+    if value.fract() != 0.0 || !(0.0..=MAX_COUNT).contains(&value) {
+        return Err(Error::parse(format!("IES file has an invalid {} count {}", what, value)));
+    }
+    Ok(value as usize)
+}
+
+/// Reads `count` angles in degrees, which must ascend within [0, max]
+fn angles(next: &mut dyn FnMut() -> Result<f32>, count: usize, max: f32, what: &str) -> Result<Vec<f32>> {
+    let mut angles = Vec::with_capacity(count);
+    for _ in 0..count {
+        let angle = next()?;
+        if !(0.0..=max).contains(&angle) {
+            return Err(Error::parse(format!("IES file has {} angle {} outside 0 to {} degrees", what, angle, max)));
+        }
+        if angles.last().is_some_and(|last| angle <= *last) {
+            return Err(Error::parse(format!("IES file's {} angles do not ascend", what)));
+        }
+        angles.push(angle);
+    }
+    Ok(angles)
+}
+
+/// Bracketing indices and blend factor of `angle` within the sorted `angles`, clamped at the ends
+fn lookup(angles: &[f32], angle: f32) -> (usize, usize, f32) {
+    if angles.len() == 1 || angle <= angles[0] {
+        return (0, 0, 0.0);
+    }
+    let last = angles.len() - 1;
+    if angle >= angles[last] {
+        return (last, last, 0.0);
+    }
+    let upper = angles.partition_point(|a| *a <= angle);
+    let lower = upper - 1;
+    let span = angles[upper] - angles[lower];
+    let t = if span > 0.0 { (angle - angles[lower]) / span } else { 0.0 };
+    (lower, upper, t)
+}
+
+impl IesProfile {
+    pub fn load(path: &Path) -> Result<IesProfile> {
+        let text = fs::read_to_string(path).map_err(|e| Error::io(path, e))?;
+        IesProfile::parse(&text).map_err(|e| e.in_file(path))
+    }
+
+    pub fn parse(text: &str) -> Result<IesProfile> {
+        let mut lines = text.lines();
+
+        //Skip the version line and [KEYWORD] block up to the TILT line
+        let tilt = loop {
+            match lines.next() {
+                Some(line) if line.trim_start().starts_with("TILT") => break line.trim().to_string(),
+                Some(_) => continue,
+                None => return Err(Error::parse("IES file has no TILT line"))
+            }
+        };
+
+        let rest: Vec<&str> = lines.collect();
+        let mut numbers = rest.iter()
+            .flat_map(|line| line.split(|c: char| c.is_whitespace() || c == ','))
+            .filter(|token| !token.is_empty())
+            .map(|token| token.parse::<f32>().map_err(|_| Error::parse(format!("IES file has invalid number '{}'", token))));
+        let mut next = || numbers.next().unwrap_or_else(|| Err(Error::parse("IES file ended unexpectedly")));
+
+        if tilt == "TILT=INCLUDE" {
+            let _lamp_to_luminaire_geometry = next()?;
+            let tilt_count = count(next()?, "tilt angle")?;
+            for _ in 0..(2 * tilt_count) {
+                next()?;
+            }
+        }
+
+        let _lamp_count = next()?;
+        let _lumens_per_lamp = next()?;
+        let multiplier = next()?;
+        let vertical_count = count(next()?, "vertical angle")?;
+        let horizontal_count = count(next()?, "horizontal angle")?;
+        let photometric_type = next()?;
+        let _units = next()?;
+        let _width = next()?;
+        let _length = next()?;
+        let _height = next()?;
+        let ballast_factor = next()?;
+        let _ballast_lamp_factor = next()?;
+        let _input_watts = next()?;
+
+        if photometric_type != 1.0 {
+            return Err(Error::parse(format!("IES photometric type {} is not supported, only type C", photometric_type)));
+        }
+        if vertical_count == 0 || horizontal_count == 0 {
+            return Err(Error::parse("IES file has no angles"));
+        }
+
+        let vertical_angles = angles(&mut next, vertical_count, 180.0, "vertical")?;
+        let horizontal_angles = angles(&mut next, horizontal_count, 360.0, "horizontal")?;
+        if horizontal_angles[0] != 0.0 && horizontal_angles[0] != 90.0 {
+            return Err(Error::parse("IES file's horizontal angles must start at 0 or 90 degrees"));
+        }
+
+        let scale = multiplier * ballast_factor;
+        let mut candela = Vec::with_capacity(horizontal_count);
+        let mut max_candela: f32 = 0.0;
+        for _ in 0..horizontal_count {
+            let mut row = Vec::with_capacity(vertical_count);
+            for _ in 0..vertical_count {
+                let value = next()? * scale;
+                max_candela = max_candela.max(value);
+                row.push(value);
+            }
+            candela.push(row);
+        }
+
+        Ok(IesProfile {
+            vertical_angles,
+            horizontal_angles,
+            candela,
+            max_candela
+        })
+    }
+
+    pub fn max_candela(&self) -> f32 {
+        self.max_candela
+    }
+
+    /// Candela at the given angles in degrees, applying the symmetry implied by the horizontal range
+    pub fn candela(&self, vertical: f32, horizontal: f32) -> f32 {
+        let first_h = self.horizontal_angles[0];
+        let last_h = self.horizontal_angles[self.horizontal_angles.len() - 1];
+        let mut h = horizontal.rem_euclid(360.0);
+        if self.horizontal_angles.len() == 1 {
+            h = self.horizontal_angles[0];
+        } else if first_h == 90.0 {
+            //Bilateral symmetry about the 90-270 plane
+            if !(90.0..=270.0).contains(&h) {
+                h = (180.0 - h).rem_euclid(360.0);
+            }
+        } else if last_h <= 90.0 {
+            //Quadrant symmetry
+            if h > 180.0 {
+                h = 360.0 - h;
+            }
+            if h > 90.0 {
+                h = 180.0 - h;
+            }
+        } else if last_h <= 180.0 {
+            //Bilateral symmetry about the 0-180 plane
+            if h > 180.0 {
+                h = 360.0 - h;
+            }
+        }
+
+        let first_v = self.vertical_angles[0];
+        let last_v = self.vertical_angles[self.vertical_angles.len() - 1];
+        if vertical < first_v || vertical > last_v {
+            return 0.0;
+        }
+
+        let (h0, h1, th) = lookup(&self.horizontal_angles, h);
+        let (v0, v1, tv) = lookup(&self.vertical_angles, vertical);
+        let a = self.candela[h0][v0] * (1.0 - tv) + self.candela[h0][v1] * tv;
+        let b = self.candela[h1][v0] * (1.0 - tv) + self.candela[h1][v1] * tv;
+        a * (1.0 - th) + b * th
+    }
+
+    /// Candela normalised to [0, 1] by the brightest direction
+    pub fn value(&self, vertical: f32, horizontal: f32) -> f32 {
+        if self.max_candela > 0.0 {
+            self.candela(vertical, horizontal) / self.max_candela
+        } else {
+            0.0
+        }
+    }
+}
+
+impl OrientedProfile {
+    pub fn new(profile: Arc<IesProfile>, nadir: Vec3, c0: Vec3) -> OrientedProfile {
+        let nadir = Vec3::unit_vector(nadir);
+        //Fall back to an arbitrary reference if c0 is parallel to the nadir
+        let hint = if c0.cross(nadir).squared_length() > 1e-6 {
+            c0
+        } else if nadir.x().abs() < 0.9 {
+            Vec3::new(1.0, 0.0, 0.0)
+        } else {
+            Vec3::new(0.0, 0.0, 1.0)
+        };
+        let c0 = Vec3::unit_vector(hint - hint.dot(nadir) * nadir);
+        let c90 = nadir.cross(c0);
+        OrientedProfile {
+            profile,
+            nadir,
+            c0,
+            c90
+        }
+    }
+
+    /// Normalised intensity emitted towards the world space direction `dir`
+    pub fn value(&self, dir: Vec3) -> f32 {
+        let dir = Vec3::unit_vector(dir);
+        let vertical = dir.dot(self.nadir).clamp(-1.0, 1.0).acos().to_degrees();
+        let horizontal = dir.dot(self.c90).atan2(dir.dot(self.c0)).to_degrees();
+        self.profile.value(vertical, horizontal)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn sample(name: &str) -> IesProfile {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(name);
+        IesProfile::load(&path).unwrap()
+    }
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-3
+    }
+
+    #[test]
+    fn interpolates_between_angles() {
+        let profile = sample("scenes/downlight.ies");
+        assert_eq!(profile.max_candela(), 1000.0);
+        assert!(close(profile.candela(0.0, 0.0), 1000.0));
+        assert!(close(profile.candela(5.0, 0.0), 975.0));
+        assert!(close(profile.candela(30.0, 45.0), 450.0));
+        assert_eq!(profile.candela(120.0, 0.0), 0.0);
+    }
+
+    #[test]
+    fn applies_multiplier_and_ballast_factor() {
+        let profile = sample("tests/ies/bilateral.ies");
+        assert!(close(profile.candela(0.0, 0.0), 100.0));
+        assert!(close(profile.max_candela(), 500.0));
+    }
+
+    #[test]
+    fn quadrant_symmetry() {
+        let profile = sample("tests/ies/quadrant.ies");
+        for v in [0.0, 30.0, 60.0, 90.0].iter() {
+            let h = profile.candela(*v, 30.0);
+            assert!(close(profile.candela(*v, 150.0), h));
+            assert!(close(profile.candela(*v, 210.0), h));
+            assert!(close(profile.candela(*v, 330.0), h));
+        }
+    }
+
+    #[test]
+    fn bilateral_symmetry() {
+        let profile = sample("tests/ies/bilateral.ies");
+        assert!(close(profile.candela(0.0, 270.0), profile.candela(0.0, 90.0)));
+        assert!(close(profile.candela(45.0, 200.0), profile.candela(45.0, 160.0)));
+    }
+
+    #[test]
+    fn bilateral_symmetry_from_90_degrees() {
+        let profile = sample("tests/ies/rotated.ies");
+        assert!(close(profile.candela(0.0, 0.0), 200.0));
+        assert!(close(profile.candela(0.0, 45.0), profile.candela(0.0, 135.0)));
+        assert!(close(profile.candela(0.0, 315.0), profile.candela(0.0, 225.0)));
+        assert!(close(profile.candela(0.0, 90.0), 100.0));
+        assert!(close(profile.candela(0.0, 270.0), 300.0));
+    }
+
+    #[test]
+    fn skips_included_tilt_data() {
+        let profile = sample("tests/ies/tilt.ies");
+        assert!(close(profile.candela(0.0, 0.0), 500.0));
+        assert!(close(profile.candela(45.0, 123.0), 375.0));
+    }
+
+    fn parse_error(header: &str, data: &str) -> String {
+        let text = format!("IESNA:LM-63-2002\nTILT=NONE\n{}\n1.0 1.0 10\n{}\n", header, data);
+        IesProfile::parse(&text).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn rejects_corrupt_counts() {
+        assert!(parse_error("1 1000 1.0 1e30 1 1 2 0.1 0.1 0.0", "0 0 100").contains("vertical angle count"));
+        assert!(parse_error("1 1000 1.0 2 1e30 1 2 0.1 0.1 0.0", "0 0 100").contains("horizontal angle count"));
+        assert!(parse_error("1 1000 1.0 2.5 1 1 2 0.1 0.1 0.0", "0 0 100").contains("vertical angle count"));
+        assert!(parse_error("1 1000 1.0 -2 1 1 2 0.1 0.1 0.0", "0 0 100").contains("vertical angle count"));
+        assert!(parse_error("1 1000 1.0 NaN 1 1 2 0.1 0.1 0.0", "0 0 100").contains("vertical angle count"));
+        let tilt = "IESNA:LM-63-2002\nTILT=INCLUDE\n1\n1e30\n";
+        assert!(IesProfile::parse(tilt).unwrap_err().to_string().contains("tilt angle count"));
+    }
+
+    #[test]
+    fn rejects_bad_angles() {
+        assert!(parse_error("1 1000 1.0 3 1 1 2 0.1 0.1 0.0", "0 60 30 0 1 2 3").contains("do not ascend"));
+        assert!(parse_error("1 1000 1.0 2 2 1 2 0.1 0.1 0.0", "0 90 90 0 1 2 3 4").contains("do not ascend"));
+        assert!(parse_error("1 1000 1.0 2 1 1 2 0.1 0.1 0.0", "0 200 0 1 2").contains("outside"));
+        assert!(parse_error("1 1000 1.0 2 1 1 2 0.1 0.1 0.0", "0 90 45 1 2").contains("start at 0 or 90"));
+    }
+
+    #[test]
+    fn rejects_unsupported_or_truncated_files() {
+        assert!(parse_error("1 1000 1.0 2 1 2 2 0.1 0.1 0.0", "0 90 0 1 2").contains("type 2"));
+        assert!(parse_error("1 1000 1.0 2 1 1 2 0.1 0.1 0.0", "0 90 0 1").contains("ended unexpectedly"));
+        assert!(IesProfile::parse("IESNA:LM-63-2002\n").is_err());
+    }
+}
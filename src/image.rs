@@ -0,0 +1,166 @@
+use vec3::Vec3;
+use tile::Tile;
+use framebuffer::Framebuffer;
+use output::{self, Channel, ExrPrecision, ImageFormat};
+use tonemap::{Operator, ToneMapSettings};
+use aov::{self, Aov};
+use denoise::{self, DenoiseSettings};
+use error::Result;
+use std::path::{Path, PathBuf};
+
+/// Options for the image files written out
+pub struct ImageOptions {
+    pub tonemap: ToneMapSettings,
+    pub exr_precision: ExrPrecision,
+    /// Adds the per pixel sample count and noise estimate as extra EXR layers
+    pub exr_layers: bool,
+    /// AOVs to write, as EXR layers or as files next to the image for the other formats
+    pub aovs: Vec<Aov>,
+    pub light_groups: Vec<String>,
+    /// Denoises the beauty image, keeping the noisy one as an EXR layer
+    pub denoise: Option<DenoiseSettings>,
+    /// Pixels the image covers, the whole frame unless cropping. EXR stores just these as its
+    /// data window, PNG leaves the rest transparent and PFM and HDR black
+    pub window: Tile
+}
+
+impl ImageOptions {
+    /// The whole width x height image with the default tone mapping, and no AOVs or denoising
+    pub fn new(width: u32, height: u32) -> ImageOptions {
+        ImageOptions {
+            tonemap: ToneMapSettings::default(),
+            exr_precision: ExrPrecision::Half,
+            exr_layers: false,
+            aovs: Vec::new(),
+            light_groups: vec!["default".to_string()],
+            denoise: None,
+            window: Tile { x0: 0, y0: 0, x1: width, y1: height }
+        }
+    }
+
+    /// Tone mapped 8-bit RGBA of the framebuffer, as a quick look that skips denoising
+    pub fn preview(&self, framebuffer: &Framebuffer) -> Vec<u8> {
+        let radiance = crop_radiance(framebuffer.radiance(), framebuffer.width, &self.window);
+        let mut data = ToneMapSettings { bit_depth: 8, ..self.tonemap }.encode(&radiance);
+        output::clear_outside(&mut data, framebuffer.width, 8, &self.window);
+        data
+    }
+}
+
+/// Radiance with the pixels outside the window set to black
+pub fn crop_radiance(mut radiance: Vec<Vec3>, width: u32, window: &Tile) -> Vec<Vec3> {
+    for (index, v) in radiance.iter_mut().enumerate() {
+        if !window.contains(index as u32 % width, index as u32 / width) {
+            *v = Vec3::zero_vector();
+        }
+    }
+    radiance
+}
+
+/// `name.png` becomes `name.<suffix>.png`
+pub fn sibling_path(path: &Path, suffix: &str) -> PathBuf {
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("");
+    let name = match path.extension().and_then(|e| e.to_str()) {
+        Some(extension) => format!("{}.{}.{}", stem, suffix, extension),
+        None => format!("{}.{}", stem, suffix)
+    };
+    path.with_file_name(name)
+}
+
+/// Writes the requested AOVs as separate images in the format of `path`: linear values for the
+/// HDR formats, a viewable version for PNG
+fn save_aov_images(path: &Path, framebuffer: &Framebuffer, options: &ImageOptions) -> Result<()> {
+    let aovs = match framebuffer.aovs.as_ref() {
+        Some(a) => a,
+        None => return Ok(())
+    };
+    let (width, height) = (framebuffer.width, framebuffer.height);
+    let format = ImageFormat::from_path(path)?;
+    let mut images = Vec::new();
+    for &aov in options.aovs.iter() {
+        if aov == Aov::LightGroups {
+            for (group, name) in options.light_groups.iter().enumerate() {
+                images.push((format!("light_{}", name), aov, aovs.light_group_image(group, &framebuffer.pixels)));
+            }
+        } else if format.is_hdr() || aov == Aov::Albedo {
+            images.push((aov.name().to_string(), aov, aovs.image(aov, &framebuffer.pixels)));
+        } else {
+            images.push((aov.name().to_string(), aov, aovs.visualise(aov, &framebuffer.pixels)));
+        }
+    }
+    for (name, aov, image) in images {
+        let path = sibling_path(path, &name);
+        let image = crop_radiance(image, width, &options.window);
+        match format {
+            ImageFormat::Pfm => output::write_pfm(&path, width, height, &image)?,
+            ImageFormat::Hdr => output::write_hdr(&path, width, height, &image)?,
+            _ => {
+                //Albedo is a colour and the light groups are radiance, so those go through the display transform
+                let mut data = match aov {
+                    Aov::Albedo => ToneMapSettings { operator: Operator::Clamp, exposure: 0.0, white_balance: None, bit_depth: 8, ..options.tonemap }.encode(&image),
+                    Aov::LightGroups => ToneMapSettings { bit_depth: 8, ..options.tonemap }.encode(&image),
+                    _ => aov::to_rgba8(&image)
+                };
+                output::clear_outside(&mut data, width, 8, &options.window);
+                output::write_png(&path, width, height, &data, 8)?
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Writes the framebuffer in the format given by the file extension: linear radiance for the
+/// HDR formats, tone mapped 8 or 16-bit sRGB for PNG
+pub fn save_image(path: &Path, framebuffer: &Framebuffer, options: &ImageOptions) -> Result<()> {
+    let (width, height) = (framebuffer.width, framebuffer.height);
+    let radiance = match options.denoise.as_ref() {
+        Some(settings) => denoise::denoise(framebuffer, settings),
+        None => framebuffer.radiance()
+    };
+    let radiance = crop_radiance(radiance, width, &options.window);
+    match ImageFormat::from_path(path)? {
+        ImageFormat::Png => {
+            let mut data = options.tonemap.encode(&radiance);
+            output::clear_outside(&mut data, width, options.tonemap.bit_depth, &options.window);
+            output::write_png(path, width, height, &data, options.tonemap.bit_depth)?;
+            save_aov_images(path, framebuffer, options)
+        },
+        ImageFormat::Pfm => {
+            output::write_pfm(path, width, height, &radiance)?;
+            save_aov_images(path, framebuffer, options)
+        },
+        ImageFormat::Hdr => {
+            output::write_hdr(path, width, height, &radiance)?;
+            save_aov_images(path, framebuffer, options)
+        },
+        ImageFormat::Exr => {
+            let mut channels = output::rgb_channels("", &radiance);
+            if options.denoise.is_some() {
+                channels.append(&mut output::rgb_channels("noisy", &framebuffer.radiance()));
+            }
+            if options.exr_layers {
+                channels.push(Channel {
+                    name: "samples.Y".to_string(),
+                    values: framebuffer.pixels.iter().map(|p| p.samples as f32).collect()
+                });
+                channels.push(Channel {
+                    name: "error.Y".to_string(),
+                    values: framebuffer.pixels.iter().map(|p| p.error().min(f32::MAX)).collect()
+                });
+            }
+            if let Some(aovs) = framebuffer.aovs.as_ref() {
+                for &aov in options.aovs.iter() {
+                    if aov == Aov::LightGroups {
+                        for (group, name) in options.light_groups.iter().enumerate() {
+                            let layer = format!("light_{}", name);
+                            channels.append(&mut output::rgb_channels(&layer, &aovs.light_group_image(group, &framebuffer.pixels)));
+                        }
+                    } else {
+                        channels.append(&mut aovs.channels(aov, &framebuffer.pixels));
+                    }
+                }
+            }
+            output::write_exr(path, width, height, &options.window, &channels, options.exr_precision)
+        }
+    }
+}
@@ -0,0 +1,753 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use vec3::Vec3;
+use camera::Projection;
+use lens::LensSystem;
+use scene::Scene;
+use tile::{Tile, TileOrder};
+use render::{render_distributed, CancelToken, Clamp, PreparedScene, Progress, RenderControl, RenderSettings};
+use sampler::SamplerKind;
+use framebuffer::Framebuffer;
+use output::{self, ExrPrecision, ImageFormat};
+use tonemap::{Operator, ToneMapSettings};
+use filter::{Filter, FilterKind};
+use aov::{self, Aov};
+use denoise::DenoiseSettings;
+use checkpoint::{self, Checkpoint, SceneHasher};
+use distributed::{self, RemoteWorker};
+use image::{save_image, ImageOptions};
+use error::{Error, Result};
+
+use clap::{Arg, App, AppSettings, ArgMatches, SubCommand};
+
+/// The value of option `name` parsed as a T, if it was given
+fn value<T: FromStr>(matches: &ArgMatches, name: &str) -> Result<Option<T>> {
+    match matches.value_of(name) {
+        Some(v) => v.trim().parse::<T>().map(Some)
+            .map_err(|_| Error::Usage(format!("--{} expects a number, found '{}'", name.replace('_', "-"), v))),
+        None => Ok(None)
+    }
+}
+
+/// `count` comma separated numbers, as `format` describes them
+fn parse_list<T: FromStr>(s: &str, count: usize, option: &str, format: &str) -> Result<Vec<T>> {
+    let values: std::result::Result<Vec<T>, _> = s.split(',').map(|c| c.trim().parse::<T>()).collect();
+    match values {
+        Ok(values) if values.len() == count => Ok(values),
+        _ => Err(Error::Usage(format!("--{} expects {}, found '{}'", option, format, s)))
+    }
+}
+
+fn parse_vec3(s: &str, option: &str) -> Result<Vec3> {
+    let c = parse_list::<f32>(s, 3, option, "a vector as x,y,z")?;
+    Ok(Vec3::new(c[0], c[1], c[2]))
+}
+
+/// `x0,y0,x1,y1` in pixels, or as fractions of the image size when any of them has a decimal point
+fn parse_crop(s: &str, width: u32, height: u32) -> Result<Tile> {
+    let c = parse_list::<f32>(s, 4, "crop", "x0,y0,x1,y1")?;
+    let (sx, sy) = if s.contains('.') { (width as f32, height as f32) } else { (1.0, 1.0) };
+    let crop = Tile {
+        x0: ((c[0] * sx).round() as u32).min(width),
+        y0: ((c[1] * sy).round() as u32).min(height),
+        x1: ((c[2] * sx).round() as u32).min(width),
+        y1: ((c[3] * sy).round() as u32).min(height)
+    };
+    if crop.x0 >= crop.x1 || crop.y0 >= crop.y1 {
+        return Err(Error::Usage(format!("--crop region {} is empty", s)));
+    }
+    Ok(crop)
+}
+
+/// Command line of the renderer and its subcommands. Workers and the HTTP server take jobs as
+/// command lines too, so they parse them with this
+pub fn app() -> App<'static, 'static> {
+    App::new("Pathtracer")
+                        .setting(AppSettings::SubcommandsNegateReqs)
+                        .after_help("EXIT STATUS:\n    0 on success, 1 for unknown options, 64 for bad option values, 65 for invalid scene or \
+                                     input files, 70 if an image can't be encoded and 74 if a file can't be read or written")
+                        .subcommand(SubCommand::with_name("merge")
+                                    .about("Stitches images rendered with --crop into one")
+                                    .arg(Arg::with_name("INPUTS")
+                                                .required(true)
+                                                .multiple(true))
+                                    .arg(Arg::with_name("output")
+                                                .short("o")
+                                                .long("output")
+                                                .help("Merged image path, .exr or .png like the inputs")
+                                                .required(true)
+                                                .takes_value(true)))
+                        .subcommand(SubCommand::with_name("serve")
+                                    .about("Renders tiles for coordinators started with --workers, loading their scene files \
+                                            from the same paths here")
+                                    .arg(Arg::with_name("listen")
+                                                .long("listen")
+                                                .help("Address to accept coordinators on, 127.0.0.1:7878 by default")
+                                                .takes_value(true)))
+                        .subcommand(SubCommand::with_name("server")
+                                    .about("Serves an HTTP API for submitting renders and following their progress")
+                                    .arg(Arg::with_name("listen")
+                                                .long("listen")
+                                                .help("Address to serve on, 127.0.0.1:8080 by default")
+                                                .takes_value(true))
+                                    .arg(Arg::with_name("directory")
+                                                .long("directory")
+                                                .help("Where uploaded scenes and rendered images go, ./jobs by default")
+                                                .takes_value(true)))
+                        .arg(Arg::with_name("INPUT")
+                                    .required(true))
+                        .arg(Arg::with_name("spp")
+                                    .short("s")
+                                    .long("spp")
+                                    .help("Number of samples per pixel")
+                                    .takes_value(true))
+                        .arg(Arg::with_name("max_spp")
+                                    .long("max-spp")
+                                    .help("Most samples a pixel can take with adaptive sampling; defaults to 8x --spp when --noise-threshold is set")
+                                    .takes_value(true))
+                        .arg(Arg::with_name("noise_threshold")
+                                    .long("noise-threshold")
+                                    .help("Keep sampling pixels whose estimated error is above this fraction of their displayed brightness, e.g. 0.01")
+                                    .takes_value(true))
+                        .arg(Arg::with_name("sample_heatmap")
+                                    .long("sample-heatmap")
+                                    .help("Also save an image of the number of samples taken by each pixel")
+                                    .takes_value(true))
+                        .arg(Arg::with_name("time_limit")
+                                    .long("time-limit")
+                                    .help("Stop after this many seconds, keeping the samples taken so far")
+                                    .takes_value(true))
+                        .arg(Arg::with_name("write_interval")
+                                    .long("write-interval")
+                                    .help("Write the partially rendered image every this many seconds")
+                                    .takes_value(true))
+                        .arg(Arg::with_name("checkpoint")
+                                    .long("checkpoint")
+                                    .help("Save the render state to this file periodically, on Ctrl-C and when done")
+                                    .takes_value(true))
+                        .arg(Arg::with_name("resume")
+                                    .long("resume")
+                                    .help("Carry on from a checkpoint file; keeps checkpointing to it unless --checkpoint is given")
+                                    .takes_value(true))
+                        .arg(Arg::with_name("tonemap")
+                                    .long("tonemap")
+                                    .help("Tone mapping operator for PNG output")
+                                    .possible_values(&["clamp", "reinhard", "aces", "agx", "hable"])
+                                    .takes_value(true))
+                        .arg(Arg::with_name("exposure")
+                                    .long("exposure")
+                                    .help("Exposure adjustment in stops, applied before tone mapping")
+                                    .allow_hyphen_values(true)
+                                    .takes_value(true))
+                        .arg(Arg::with_name("white_balance")
+                                    .long("white-balance")
+                                    .help("Colour temperature in Kelvin that should appear white")
+                                    .takes_value(true))
+                        .arg(Arg::with_name("white_point")
+                                    .long("white-point")
+                                    .help("Luminance mapped to white by the Reinhard operator")
+                                    .takes_value(true))
+                        .arg(Arg::with_name("no_dither")
+                                    .long("no-dither")
+                                    .help("Quantise PNG output without dithering"))
+                        .arg(Arg::with_name("bit_depth")
+                                    .long("bit-depth")
+                                    .help("Bits per channel of PNG output")
+                                    .possible_values(&["8", "16"])
+                                    .takes_value(true))
+                        .arg(Arg::with_name("exr_precision")
+                                    .long("exr-precision")
+                                    .help("Precision of the channels in EXR output")
+                                    .possible_values(&["half", "float"])
+                                    .takes_value(true))
+                        .arg(Arg::with_name("exr_layers")
+                                    .long("exr-layers")
+                                    .help("Add sample count and noise estimate layers to EXR output"))
+                        .arg(Arg::with_name("aovs")
+                                    .long("aovs")
+                                    .help("AOVs to write, comma separated. EXR output gets them as layers, other formats as files \
+                                           named like the output with the AOV before the extension")
+                                    .possible_values(&["albedo", "normal", "depth", "position", "object_id", "material_id", "light_groups", "all"])
+                                    .takes_value(true)
+                                    .multiple(true)
+                                    .require_delimiter(true))
+                        .arg(Arg::with_name("denoise")
+                                    .long("denoise")
+                                    .help("Denoise the image with an edge-avoiding wavelet filter guided by the albedo, normal and depth AOVs"))
+                        .arg(Arg::with_name("clamp_direct")
+                                    .long("clamp-direct")
+                                    .help("Largest colour component a sample's direct light may have; off by default")
+                                    .takes_value(true))
+                        .arg(Arg::with_name("clamp_indirect")
+                                    .long("clamp-indirect")
+                                    .help("Largest colour component a sample's indirect light may have; off by default")
+                                    .takes_value(true))
+                        .arg(Arg::with_name("median_of_means")
+                                    .long("median-of-means")
+                                    .help("Estimate each pixel as the median of the means of this many sample batches, which rejects \
+                                           fireflies; replaces the reconstruction filter")
+                                    .takes_value(true))
+                        .arg(Arg::with_name("crop")
+                                    .long("crop")
+                                    .help("Render only the region x0,y0,x1,y1, in pixels or as fractions of the image size like \
+                                           0.5,0,1,0.5; `merge` puts the pieces back together")
+                                    .takes_value(true))
+                        .arg(Arg::with_name("width")
+                                    .short("w")
+                                    .long("width")
+                                    .help("Rendered image width")
+                                    .takes_value(true))
+                        .arg(Arg::with_name("height")
+                                    .short("h")
+                                    .long("height")
+                                    .help("Rendered image height")
+                                    .takes_value(true))
+                        .arg(Arg::with_name("output")
+                                    .short("o")
+                                    .long("output")
+                                    .help("Output image path ending in .png, or .exr, .pfm or .hdr to keep linear HDR radiance")
+                                    .takes_value(true))
+                        .arg(Arg::with_name("projection")
+                                    .long("projection")
+                                    .help("Camera projection. A cubemap needs a 6:1 image for a row of faces or 3:2 for two rows of three")
+                                    .possible_values(&["perspective", "orthographic", "fisheye-equidistant", "fisheye-equisolid", "equirectangular", "cubemap"])
+                                    .takes_value(true))
+                        .arg(Arg::with_name("lens")
+                                    .long("lens")
+                                    .help("Lens prescription file; renders through the realistic lens system, whose stop sets the exposure like a real camera")
+                                    .takes_value(true))
+                        .arg(Arg::with_name("lookfrom")
+                                    .long("lookfrom")
+                                    .help("Camera position as x,y,z")
+                                    .takes_value(true))
+                        .arg(Arg::with_name("lookat")
+                                    .long("lookat")
+                                    .help("Point the camera looks at as x,y,z")
+                                    .takes_value(true))
+                        .arg(Arg::with_name("up")
+                                    .long("up")
+                                    .help("Camera up vector as x,y,z")
+                                    .takes_value(true))
+                        .arg(Arg::with_name("vfov")
+                                    .long("vfov")
+                                    .help("Vertical field of view in degrees")
+                                    .takes_value(true))
+                        .arg(Arg::with_name("aperture")
+                                    .long("aperture")
+                                    .help("Lens aperture diameter in scene units")
+                                    .takes_value(true))
+                        .arg(Arg::with_name("fstop")
+                                    .long("fstop")
+                                    .help("Lens f-number, assuming scene units are metres; overrides --aperture")
+                                    .takes_value(true))
+                        .arg(Arg::with_name("focus_dist")
+                                    .long("focus-dist")
+                                    .help("Distance to the plane in focus")
+                                    .takes_value(true))
+                        .arg(Arg::with_name("focus_pixel")
+                                    .long("focus-pixel")
+                                    .help("Autofocus on the surface visible through pixel x,y, counted from the top left")
+                                    .takes_value(true))
+                        .arg(Arg::with_name("blades")
+                                    .long("blades")
+                                    .help("Number of aperture blades for polygonal bokeh")
+                                    .takes_value(true))
+                        .arg(Arg::with_name("blade_rotation")
+                                    .long("blade-rotation")
+                                    .help("Rotation of the aperture polygon in degrees")
+                                    .takes_value(true))
+                        .arg(Arg::with_name("shutter")
+                                    .long("shutter")
+                                    .help("Shutter open and close times as open,close for motion blur")
+                                    .takes_value(true))
+                        .arg(Arg::with_name("tile_size")
+                                    .long("tile-size")
+                                    .help("Edge length of the square tiles handed to render threads")
+                                    .takes_value(true))
+                        .arg(Arg::with_name("tile_order")
+                                    .long("tile-order")
+                                    .help("Order in which tiles are rendered")
+                                    .possible_values(&["spiral", "hilbert", "scanline"])
+                                    .takes_value(true))
+                        .arg(Arg::with_name("seed")
+                                    .long("seed")
+                                    .help("Seed for the random streams; the same seed renders the same image")
+                                    .takes_value(true))
+                        .arg(Arg::with_name("sampler")
+                                    .long("sampler")
+                                    .help("Sample pattern used for the pixel, lens and every bounce")
+                                    .possible_values(&["sobol", "halton", "stratified", "independent"])
+                                    .takes_value(true))
+                        .arg(Arg::with_name("filter")
+                                    .long("filter")
+                                    .help("Pixel reconstruction filter; samples are splatted to every pixel within its radius")
+                                    .possible_values(&["box", "tent", "gaussian", "mitchell", "blackman-harris", "lanczos"])
+                                    .takes_value(true))
+                        .arg(Arg::with_name("filter_radius")
+                                    .long("filter-radius")
+                                    .help("Filter radius in pixels, defaults to a width suited to the filter")
+                                    .takes_value(true))
+                        .arg(Arg::with_name("workers")
+                                    .long("workers")
+                                    .help("Worker processes started with `serve` to share the tiles with, as host:port, comma separated")
+                                    .takes_value(true)
+                                    .multiple(true)
+                                    .require_delimiter(true))
+                        .arg(Arg::with_name("light_stats")
+                                    .long("light-stats")
+                                    .help("Print the emitted power fraction of each light"))
+                        .arg(Arg::with_name("stats")
+                                    .long("stats")
+                                    .value_name("FORMAT")
+                                    .help("Print ray and BVH counts, how many paths escaped, were absorbed or hit the depth limit, and the build and render \
+                                           times after rendering, as text or one line of JSON")
+                                    .takes_value(true)
+                                    .min_values(0)
+                                    .require_equals(true)
+                                    .possible_values(&["text", "json"]))
+                        .arg(Arg::with_name("quiet")
+                                    .short("q")
+                                    .long("quiet")
+                                    .help("Print nothing but errors"))
+                        .arg(Arg::with_name("json_progress")
+                                    .long("json-progress")
+                                    .help("Print progress as one JSON object per line on stdout instead of a progress bar")
+                                    .conflicts_with("quiet"))
+                        .arg(Arg::with_name("root")
+                                    .long("root")
+                                    .help("Refuse files the scene names outside this directory; the HTTP server sets it for its jobs")
+                                    .takes_value(true)
+                                    .hidden(true))
+}
+
+/// The scene, camera and settings a command line describes, ready to render. Workers build the
+/// same from the coordinator's command line
+pub struct RenderJob {
+    pub filename: String,
+    /// Identifies the image being rendered: the scene files, camera and settings that shape it.
+    /// Checkpoints only resume into, and workers only help with, a job with the same hash
+    pub scene_hash: u64,
+    pub scene: PreparedScene,
+    pub scene_tonemap: ToneMapSettings,
+    pub light_groups: Vec<String>,
+    pub aovs: Vec<Aov>,
+    pub denoise: bool,
+    /// Median of means batches, if the estimator is used
+    pub batches: Option<usize>,
+    pub crop: Option<Tile>,
+    pub settings: RenderSettings
+}
+
+impl RenderJob {
+    /// Loads and prepares the scene a command line names, rendering with the given seed
+    pub fn from_args(args: &[String], seed: u64) -> Result<RenderJob> {
+        let matches = app().get_matches_from_safe(args).map_err(|e| Error::Usage(e.message))?;
+        RenderJob::from_matches(&matches, seed)
+    }
+
+    /// As `from_args`, for an already parsed command line
+    pub fn from_matches(matches: &ArgMatches, seed: u64) -> Result<RenderJob> {
+        prepare(matches, seed)
+    }
+
+    /// An empty framebuffer of the image size, gathering AOVs and batch sums if the job needs them
+    pub fn framebuffer(&self) -> Framebuffer {
+        let mut framebuffer = Framebuffer::new(self.settings.width, self.settings.height);
+        if !self.aovs.is_empty() || self.denoise {
+            framebuffer = framebuffer.with_aovs(self.light_groups.len());
+        }
+        if let Some(count) = self.batches {
+            framebuffer = framebuffer.with_batches(count);
+        }
+        framebuffer
+    }
+}
+
+fn prepare(matches: &ArgMatches, seed: u64) -> Result<RenderJob> {
+    let filename = matches.value_of("INPUT").unwrap();
+    let samples_per_pixel = value::<usize>(matches, "spp")?.unwrap_or(100);
+    let image_width = value::<u32>(matches, "width")?.unwrap_or(480);
+    let image_height = value::<u32>(matches, "height")?.unwrap_or(270);
+
+    let noise_threshold = value::<f32>(matches, "noise_threshold")?;
+    let max_samples_per_pixel = match value::<usize>(matches, "max_spp")? {
+        Some(v) => v,
+        None if noise_threshold.is_some() => 8 * samples_per_pixel,
+        None => samples_per_pixel
+    };
+    let seconds = |name| -> Result<Option<Duration>> {
+        match value::<f64>(matches, name)? {
+            Some(v) => Duration::try_from_secs_f64(v).map(Some)
+                .map_err(|_| Error::Usage(format!("--{} expects a number of seconds, found {}", name.replace('_', "-"), matches.value_of(name).unwrap_or("")))),
+            None => Ok(None)
+        }
+    };
+    let time_limit = seconds("time_limit")?;
+    let write_interval = seconds("write_interval")?;
+    let tile_size = value::<u32>(matches, "tile_size")?.unwrap_or(32).max(1);
+    let tile_order = TileOrder::from_name(matches.value_of("tile_order").unwrap_or("spiral")).unwrap();
+    let sampler = SamplerKind::from_name(matches.value_of("sampler").unwrap_or("sobol")).unwrap();
+    let filter = Filter::new(FilterKind::from_name(matches.value_of("filter").unwrap_or("box")).unwrap(),
+                             value::<f32>(matches, "filter_radius")?);
+    let denoise = matches.is_present("denoise");
+    let clamp = Clamp {
+        direct: value::<f32>(matches, "clamp_direct")?,
+        indirect: value::<f32>(matches, "clamp_indirect")?
+    };
+    let crop = match matches.value_of("crop") {
+        Some(v) => Some(parse_crop(v, image_width, image_height)?),
+        None => None
+    };
+    let batches = value::<usize>(matches, "median_of_means")?.map(|v| v.max(1));
+    let mut aovs: Vec<Aov> = Vec::new();
+    for name in matches.values_of("aovs").into_iter().flatten() {
+        let requested = match name {
+            "all" => aov::ALL.to_vec(),
+            name => vec![Aov::from_name(name).unwrap()]
+        };
+        for aov in requested {
+            if !aovs.contains(&aov) {
+                aovs.push(aov);
+            }
+        }
+    }
+
+    let path = Path::new(filename);
+    let scene = if path.extension() == Some(OsStr::new("scene")) {
+        match matches.value_of("root") {
+            Some(root) => Scene::load_inside(path, Path::new(root))?,
+            None => Scene::load(path)?
+        }
+    } else {
+        Scene::from_obj(path)?
+    };
+    let scene_tonemap = scene.tonemap;
+    let light_groups = scene.light_groups;
+    let mut files = scene.files;
+
+    let mut camera_settings = scene.camera;
+    if let Some(v) = matches.value_of("projection") {
+        camera_settings.projection = Projection::from_name(v).unwrap();
+    }
+    if let Some(v) = matches.value_of("lens") {
+        let lens = LensSystem::load(Path::new(v))?;
+        files.push(PathBuf::from(v));
+        camera_settings.lens = Some(Arc::new(lens));
+        camera_settings.projection = Projection::Realistic;
+    }
+    if let Some(v) = matches.value_of("lookfrom") {
+        camera_settings.lookfrom = parse_vec3(v, "lookfrom")?;
+    }
+    if let Some(v) = matches.value_of("lookat") {
+        camera_settings.lookat = parse_vec3(v, "lookat")?;
+    }
+    if let Some(v) = matches.value_of("up") {
+        camera_settings.vup = parse_vec3(v, "up")?;
+    }
+    if let Some(v) = value::<f32>(matches, "vfov")? {
+        camera_settings.vfov = v;
+    }
+    if let Some(v) = value::<f32>(matches, "aperture")? {
+        camera_settings.aperture = v;
+        camera_settings.fstop = None;
+    }
+    if let Some(v) = value::<f32>(matches, "fstop")? {
+        camera_settings.fstop = Some(v);
+    }
+    if let Some(v) = value::<f32>(matches, "focus_dist")? {
+        camera_settings.focus_dist = Some(v);
+        camera_settings.focus_pixel = None;
+    }
+    if let Some(v) = matches.value_of("focus_pixel") {
+        let pixel = parse_list::<u32>(v, 2, "focus-pixel", "x,y")?;
+        if pixel[0] >= image_width || pixel[1] >= image_height {
+            return Err(Error::Usage(format!("--focus-pixel {} is outside the {}x{} image", v, image_width, image_height)));
+        }
+        camera_settings.focus_pixel = Some((pixel[0], pixel[1]));
+    }
+    if let Some(v) = matches.value_of("shutter") {
+        let times = parse_list::<f32>(v, 2, "shutter", "open,close")?;
+        if !times.iter().all(|t| t.is_finite()) {
+            return Err(Error::Usage(format!("--shutter expects finite times, found '{}'", v)));
+        }
+        camera_settings.shutter_open = times[0];
+        camera_settings.shutter_close = times[1];
+    }
+    if let Some(v) = value::<u32>(matches, "blades")? {
+        camera_settings.blades = v;
+    }
+    if let Some(v) = value::<f32>(matches, "blade_rotation")? {
+        camera_settings.blade_rotation = v;
+    }
+
+    //Identifies the image being rendered, so a checkpoint is only resumed into the same one and
+    //workers only help with it if they loaded the same. Covers every file the scene came from,
+    //so an edited mesh, profile or lens counts as a different scene
+    let mut hasher = SceneHasher::new();
+    for file in &files {
+        let contents = fs::read(file).map_err(|e| Error::io(file, e))?;
+        hasher.write(&(contents.len() as u64).to_le_bytes());
+        hasher.write(&contents);
+    }
+    hasher.write(format!("{}x{} {:?} {:?} {:?}", image_width, image_height, sampler, filter, camera_settings).as_bytes());
+    //AOV sums are only in the checkpoint if they were being gathered
+    hasher.write(&[(!aovs.is_empty() || denoise) as u8]);
+    hasher.write(format!("{:?} {:?} {:?}", clamp, batches, crop).as_bytes());
+    //Only the stratified sampler's pattern depends on the sample count. Halton and Sobol points
+    //are the same prefix of one sequence whatever the count, which is what lets a checkpoint
+    //carry on with more samples than it was started with
+    if sampler == SamplerKind::Stratified {
+        hasher.write(&samples_per_pixel.to_le_bytes());
+    }
+    let scene_hash = hasher.finish();
+
+    let prepared = PreparedScene::new(scene.objects, scene.lights, &camera_settings, image_width, image_height).map_err(|e| e.in_file(path))?;
+
+    let settings = RenderSettings {
+        width: image_width,
+        height: image_height,
+        samples_per_pixel,
+        max_samples_per_pixel,
+        noise_threshold,
+        tile_size,
+        tile_order,
+        seed,
+        sampler: Arc::from(sampler.build(seed, samples_per_pixel)),
+        filter,
+        clamp,
+        //Filters splat across the crop border, so pixels just outside it are rendered too
+        region: match crop {
+            Some(crop) => crop.expand(filter.pixel_reach() as u32, image_width, image_height),
+            None => Tile { x0: 0, y0: 0, x1: image_width, y1: image_height }
+        },
+        time_limit,
+        write_interval,
+        cancel: CancelToken::new(),
+        progress: Arc::new(Progress::default()),
+        observer: None
+    };
+    Ok(RenderJob {
+        filename: filename.to_string(),
+        scene_hash,
+        scene: prepared,
+        scene_tonemap,
+        light_groups,
+        aovs,
+        denoise,
+        batches,
+        crop,
+        settings
+    })
+}
+
+/// Renders the image a command line describes and saves it, with checkpoints and workers if it
+/// asks for them. Prints what it is doing unless the command line asks for quiet. `args` starts
+/// with the program name, as `std::env::args` does
+pub fn run(args: &[String], control: &RenderControl) -> Result<()> {
+    let matches = app().get_matches_from_safe(args).map_err(|e| Error::Usage(e.message))?;
+    run_matches(&matches, args, control)
+}
+
+fn run_matches(matches: &ArgMatches, args: &[String], control: &RenderControl) -> Result<()> {
+    let output_filename = matches.value_of("output").unwrap_or("output.png");
+    //Refuse an unknown extension before spending time on the render
+    ImageFormat::from_path(Path::new(output_filename))?;
+    let resume = matches.value_of("resume").map(Path::new);
+    let checkpoint_path = matches.value_of("checkpoint").map(Path::new).or(resume);
+    let seed = match (value::<u64>(matches, "seed")?, resume) {
+        (None, Some(path)) => Checkpoint::read_seed(path).map_err(|e| Error::io(path, e))?,
+        (seed, _) => seed.unwrap_or(0)
+    };
+    let build_start = Instant::now();
+    let mut job = prepare(matches, seed)?;
+    let build_time = build_start.elapsed();
+    job.settings.cancel = control.cancel.clone();
+    job.settings.progress = control.progress.clone();
+    job.settings.observer = control.observer.clone();
+    let verbose = !matches.is_present("quiet") && !matches.is_present("json_progress");
+    if job.settings.write_interval.is_none() && checkpoint_path.is_some() {
+        job.settings.write_interval = Some(Duration::from_secs(60));
+    }
+    let (image_width, image_height) = (job.settings.width, job.settings.height);
+    let (samples_per_pixel, max_samples_per_pixel) = (job.settings.samples_per_pixel, job.settings.max_samples_per_pixel);
+
+    if verbose {
+        println!("Generating a {}x{}@{}spp render of {}, saving to {}", image_width, image_height, samples_per_pixel, job.filename, output_filename);
+    }
+    if matches.is_present("light_stats") {
+        job.scene.lights.print_stats();
+    }
+    let initial = match resume {
+        Some(path) => {
+            let checkpoint = Checkpoint::load(path, job.framebuffer()).map_err(|e| Error::io(path, e))?;
+            if checkpoint.scene_hash != job.scene_hash || checkpoint.seed != seed {
+                return Err(Error::Usage("Checkpoint was made for a different scene, camera, resolution, sampler or seed".to_string()));
+            }
+            if verbose {
+                println!("Resuming from {:.1} samples per pixel", checkpoint.framebuffer.mean_samples());
+            }
+            checkpoint.framebuffer
+        },
+        None => job.framebuffer()
+    };
+
+    //Workers check the scene hash against their own copy of the files
+    let request = distributed::Job {
+        scene_hash: job.scene_hash,
+        seed,
+        args: args.to_vec()
+    };
+    let mut workers = Vec::new();
+    for address in matches.values_of("workers").into_iter().flatten() {
+        match RemoteWorker::connect(address, &request) {
+            Ok(worker) => {
+                if verbose {
+                    println!("Worker {} joined with {} threads", address, worker.threads);
+                }
+                workers.push(worker);
+            },
+            Err(e) => eprintln!("Worker {} is not helping: {}", address, e)
+        }
+    }
+
+    let start_time = Instant::now();
+
+    let context = job.scene.context();
+    let settings = &job.settings;
+
+    let save_checkpoint = |framebuffer: &Framebuffer| {
+        if let Some(path) = checkpoint_path {
+            if let Err(e) = checkpoint::save(path, job.scene_hash, seed, framebuffer) {
+                eprintln!("Failed to save checkpoint {}: {}", path.display(), e);
+            }
+        }
+    };
+    let output_path = Path::new(output_filename);
+    let mut tonemap = job.scene_tonemap;
+    if let Some(v) = matches.value_of("tonemap") {
+        tonemap.operator = Operator::from_name(v).unwrap();
+    }
+    if let Some(v) = value::<f32>(matches, "exposure")? {
+        tonemap.exposure = v;
+    }
+    if let Some(v) = value::<f32>(matches, "white_balance")? {
+        tonemap.white_balance = Some(v);
+    }
+    if let Some(v) = value::<f32>(matches, "white_point")? {
+        tonemap.white_point = v;
+    }
+    if matches.is_present("no_dither") {
+        tonemap.dither = false;
+    }
+    if let Some(v) = value::<u32>(matches, "bit_depth")? {
+        tonemap.bit_depth = v;
+    }
+    let image_options = ImageOptions {
+        tonemap,
+        exr_precision: ExrPrecision::from_name(matches.value_of("exr_precision").unwrap_or("half")).unwrap(),
+        exr_layers: matches.is_present("exr_layers"),
+        aovs: job.aovs.clone(),
+        light_groups: job.light_groups.clone(),
+        denoise: if job.denoise { Some(DenoiseSettings::default()) } else { None },
+        window: job.crop.unwrap_or(Tile { x0: 0, y0: 0, x1: image_width, y1: image_height })
+    };
+    let show_preview = |framebuffer: &Framebuffer| {
+        if let Some(preview) = control.preview.as_ref() {
+            preview(image_width, image_height, image_options.preview(framebuffer));
+        }
+    };
+    let framebuffer = render_distributed(&context, settings, initial, &mut workers, &|partial| {
+        if let Err(e) = save_image(output_path, partial, &image_options) {
+            eprintln!("Failed to write the partial image: {}", e);
+        }
+        save_checkpoint(partial);
+        show_preview(partial);
+    });
+    save_checkpoint(&framebuffer);
+    show_preview(&framebuffer);
+
+    let render_duration = start_time.elapsed();
+    if verbose {
+        if control.cancel.is_cancelled() {
+            println!("Render cancelled");
+        } else if settings.time_limit.is_some_and(|limit| render_duration >= limit) {
+            println!("Time limit reached");
+        }
+        println!("Render took {:.3} seconds", render_duration.as_secs_f64());
+        if max_samples_per_pixel > samples_per_pixel || settings.time_limit.is_some() {
+            println!("Took {:.1} samples per pixel on average", framebuffer.mean_samples());
+        }
+    }
+    if matches.is_present("stats") {
+        let mut stats = framebuffer.stats;
+        stats.build_time = build_time;
+        match matches.value_of("stats") {
+            Some("json") => println!("{}", stats.to_json()),
+            _ => stats.print()
+        }
+    }
+
+    //Store image to file
+    save_image(output_path, &framebuffer, &image_options)?;
+    if let Some(path) = matches.value_of("sample_heatmap") {
+        let heatmap = framebuffer.sample_heatmap(max_samples_per_pixel as u32);
+        output::write_png(Path::new(path), image_width, image_height, &heatmap, 8)?;
+    }
+
+    if verbose {
+        println!("Done");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(extra: &[&str]) -> Vec<String> {
+        let scene = format!("{}/scenes/ies_demo.scene", env!("CARGO_MANIFEST_DIR"));
+        ["pathtracer", scene.as_str(), "-w", "8", "-h", "6", "-s", "1", "-q"].iter().chain(extra.iter()).map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn refuses_unknown_output_extensions() {
+        for name in &["out.jpg", "out"] {
+            let result = run(&args(&["-o", name]), &RenderControl::default());
+            assert!(matches!(result, Err(Error::Usage(_))), "{} {:?}", name, result.err());
+            assert!(!Path::new(name).exists());
+        }
+    }
+
+    #[test]
+    fn parses_crops() {
+        let crop = |s: &str| parse_crop(s, 200, 100).map(|t| (t.x0, t.y0, t.x1, t.y1));
+        assert_eq!(crop("10,20,110,70").unwrap(), (10, 20, 110, 70));
+        //Any decimal point makes every value a fraction of the image size
+        assert_eq!(crop("0.5,0,1,0.25").unwrap(), (100, 0, 200, 25));
+        assert_eq!(crop("0.125,0.1,0.5,1.0").unwrap(), (25, 10, 100, 100));
+        //The far corner is held to the image edge
+        assert_eq!(crop("150,50,400,900").unwrap(), (150, 50, 200, 100));
+        assert_eq!(crop("0.5,0.5,1.5,2").unwrap(), (100, 50, 200, 100));
+        for empty in &["10,20,10,70", "50,20,10,70", "0.5,0,0.5,1", "200,0,300,100", "0,100,200,150"] {
+            assert!(matches!(crop(empty), Err(Error::Usage(ref m)) if m.contains("is empty")), "{}", empty);
+        }
+        for malformed in &["1,2,3", "a,b,c,d"] {
+            assert!(matches!(crop(malformed), Err(Error::Usage(_))), "{}", malformed);
+        }
+    }
+
+    #[test]
+    fn refuses_focus_pixels_outside_the_image() {
+        for pixel in &["8,0", "0,6", "100,100"] {
+            let result = run(&args(&["--focus-pixel", pixel, "-o", "unused.png"]), &RenderControl::default());
+            assert!(matches!(result, Err(Error::Usage(ref m)) if m.contains("outside the 8x6 image")), "{} {:?}", pixel, result.err());
+        }
+        assert!(!Path::new("unused.png").exists());
+    }
+}
+
@@ -0,0 +1,312 @@
+use vec3::Vec3;
+use ray::Ray;
+use camera::{Basis, Camera};
+use error::{Error, Result};
+use std::fs;
+use std::result;
+use std::path::Path;
+use std::sync::Arc;
+use rng;
+use sampler;
+
+
+/// One refracting surface (or the aperture stop when `curvature_radius` is 0), in metres
+#[derive(Clone, Copy, Debug)]
+pub struct LensElement {
+    curvature_radius: f32,
+    /// Distance to the next element towards the film
+    thickness: f32,
+    eta: f32,
+    aperture_radius: f32
+}
+
+/// Lens prescription ordered from the front (scene side) element to the rear element.
+/// Lens space has the film at z = 0 with the scene towards -z
+#[derive(Debug)]
+pub struct LensSystem {
+    elements: Vec<LensElement>
+}
+
+pub struct RealisticCamera {
+    lens: Arc<LensSystem>,
+    origin: Vec3,
+    basis: Basis,
+    /// Distance from the rear element to the film
+    film_distance: f32,
+    film_width: f32,
+    film_height: f32
+}
+
+fn refract(wi: Vec3, n: Vec3, eta: f32) -> Option<Vec3> {
+    let cos_i = n.dot(wi);
+    let sin2_t = eta * eta * (1.0 - cos_i * cos_i).max(0.0);
+    if sin2_t >= 1.0 {
+        return None;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    Some(-eta * wi + (eta * cos_i - cos_t) * n)
+}
+
+impl LensSystem {
+    /// Reads a pbrt style prescription: one element per line as
+    /// `radius thickness ior aperture_diameter` in millimetres, '#' starts a comment
+    pub fn load(path: &Path) -> Result<LensSystem> {
+        let text = fs::read_to_string(path).map_err(|e| Error::io(path, e))?;
+        LensSystem::parse(&text).map_err(|e| e.in_file(path))
+    }
+
+    pub fn parse(text: &str) -> Result<LensSystem> {
+        let mut elements = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let values: result::Result<Vec<f32>, _> = line.split_whitespace().map(|v| v.parse::<f32>()).collect();
+            let values = values.map_err(|_| Error::parse_line(index + 1, "invalid number"))?;
+            if values.len() != 4 {
+                return Err(Error::parse_line(index + 1, format!("expected 4 columns, found {}", values.len())));
+            }
+            elements.push(LensElement {
+                curvature_radius: values[0] / 1000.0,
+                thickness: values[1] / 1000.0,
+                //An index of 0 means air, as in the stop line of most prescriptions
+                eta: if values[2] == 0.0 { 1.0 } else { values[2] },
+                aperture_radius: values[3] / 2000.0
+            });
+        }
+        if elements.is_empty() {
+            return Err(Error::parse("lens file has no elements"));
+        }
+        Ok(LensSystem {
+            elements
+        })
+    }
+
+    pub fn rear_aperture_radius(&self) -> f32 {
+        self.elements[self.elements.len() - 1].aperture_radius
+    }
+
+    /// Rescales the aperture stop to the given diameter in metres
+    pub fn with_stop_diameter(&self, diameter: f32) -> LensSystem {
+        let elements = self.elements.iter().map(|e| {
+            let mut e = *e;
+            if e.curvature_radius == 0.0 {
+                e.aperture_radius = 0.5 * diameter;
+            }
+            e
+        }).collect();
+        LensSystem {
+            elements
+        }
+    }
+
+    /// Traces a lens space ray from the film out of the front element, None if it is blocked
+    fn trace_from_film(&self, origin: Vec3, direction: Vec3, film_distance: f32) -> Option<(Vec3, Vec3)> {
+        let mut o = origin;
+        let mut d = Vec3::unit_vector(direction);
+        let mut element_z = 0.0;
+        let count = self.elements.len();
+        for i in (0..count).rev() {
+            let element = &self.elements[i];
+            element_z -= if i == count - 1 { film_distance } else { element.thickness };
+
+            let is_stop = element.curvature_radius == 0.0;
+            let (t, normal) = if is_stop {
+                if d.z() == 0.0 {
+                    return None;
+                }
+                ((element_z - o.z()) / d.z(), Vec3::new(0.0, 0.0, 1.0))
+            } else {
+                let radius = element.curvature_radius;
+                let center = Vec3::new(0.0, 0.0, element_z + radius);
+                let oc = o - center;
+                let b = oc.dot(d);
+                let c = oc.dot(oc) - radius * radius;
+                let discriminant = b * b - c;
+                if discriminant < 0.0 {
+                    return None;
+                }
+                let root = discriminant.sqrt();
+                let (t0, t1) = (-b - root, -b + root);
+                let use_closer = (d.z() > 0.0) ^ (radius < 0.0);
+                let t = if use_closer { t0.min(t1) } else { t0.max(t1) };
+                let mut n = Vec3::unit_vector(oc + t * d);
+                if n.dot(d) > 0.0 {
+                    n = -1.0 * n;
+                }
+                (t, n)
+            };
+            if t < 0.0 {
+                return None;
+            }
+
+            let p = o + t * d;
+            if p.x() * p.x() + p.y() * p.y() > element.aperture_radius * element.aperture_radius {
+                return None;
+            }
+            o = p;
+
+            if !is_stop {
+                let eta_t = if i > 0 { self.elements[i - 1].eta } else { 1.0 };
+                d = Vec3::unit_vector(refract(-1.0 * d, normal, element.eta / eta_t)?);
+            }
+        }
+        Some((o, d))
+    }
+
+    /// Inverse distance from the film to the plane in focus for a given film distance,
+    /// found with a paraxial ray from the centre of the film. 0 means focused at infinity
+    fn focus_reciprocal(&self, film_distance: f32) -> Option<f32> {
+        let h = 0.001 * self.rear_aperture_radius();
+        let rear_z = -film_distance;
+        let (o, d) = self.trace_from_film(Vec3::zero_vector(), Vec3::new(h, 0.0, rear_z), film_distance)?;
+        let slope = d.x() / d.z();
+        let denominator = o.x() - o.z() * slope;
+        if denominator == 0.0 {
+            return None;
+        }
+        Some(slope / denominator)
+    }
+
+    /// Film distance that brings the plane `focus_dist` in front of the film into focus
+    pub fn focus(&self, focus_dist: f32) -> Option<f32> {
+        let target = 1.0 / focus_dist;
+        let length: f32 = self.elements.iter().map(|e| e.thickness).sum();
+        let (mut lo, mut hi) = (1e-4, 4.0 * length);
+        let f_lo = self.focus_reciprocal(lo)? - target;
+        let f_hi = self.focus_reciprocal(hi)? - target;
+        if f_lo * f_hi > 0.0 {
+            return None;
+        }
+        for _ in 0..64 {
+            let mid = 0.5 * (lo + hi);
+            let f_mid = match self.focus_reciprocal(mid) {
+                Some(g) => g - target,
+                None => return None
+            };
+            if (f_mid < 0.0) == (f_lo < 0.0) {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
+        Some(0.5 * (lo + hi))
+    }
+
+    /// Film distance given in the prescription, used when focusing fails
+    pub fn default_film_distance(&self) -> f32 {
+        self.elements[self.elements.len() - 1].thickness
+    }
+}
+
+impl RealisticCamera {
+    /// `film_height` is in metres; the film width follows from the aspect ratio
+    pub fn new(lens: Arc<LensSystem>, lookfrom: Vec3, lookat: Vec3, vup: Vec3, film_height: f32, aspect: f32, focus_dist: f32) -> RealisticCamera {
+        let film_distance = lens.focus(focus_dist).unwrap_or_else(|| lens.default_film_distance());
+        RealisticCamera {
+            lens,
+            origin: lookfrom,
+            basis: Basis::new(lookfrom, lookat, vup),
+            film_distance,
+            film_width: film_height * aspect,
+            film_height
+        }
+    }
+}
+
+impl Camera for RealisticCamera {
+    fn get_ray(&self, s: f32, t: f32) -> Option<Ray> {
+        self.get_weighted_ray(s, t).map(|(r, _)| r)
+    }
+
+    /// Samples the rear element through the sampler's lens dimensions. As in pbrt, the weight is
+    /// cos^4 of the angle to the axis times the rear disk's area over the squared film distance,
+    /// so off axis pixels fall off and wider stops gather more light
+    fn get_weighted_ray(&self, s: f32, t: f32) -> Option<(Ray, f32)> {
+        //The lens inverts the image, so the film is mirrored about its centre
+        let film = Vec3::new((0.5 - s) * self.film_width, (0.5 - t) * self.film_height, 0.0);
+
+        let rear = self.lens.rear_aperture_radius();
+        rng::start_dimensions(sampler::CAMERA_DIMENSION + sampler::LENS_OFFSET, sampler::LENS_DIMENSIONS);
+        let (r, phi) = (rear * rng::random().sqrt(), 2.0 * std::f32::consts::PI * rng::random());
+        let on_rear = Vec3::new(r * phi.cos(), r * phi.sin(), -self.film_distance);
+
+        let (o, d) = self.lens.trace_from_film(film, on_rear - film, self.film_distance)?;
+        let b = &self.basis;
+        let origin = self.origin + o.x() * b.u + o.y() * b.v + o.z() * b.w;
+        let direction = d.x() * b.u + d.y() * b.v + d.z() * b.w;
+
+        let cos_theta = Vec3::unit_vector(on_rear - film).z().abs();
+        let area = std::f32::consts::PI * rear * rear;
+        let weight = cos_theta.powi(4) * area / (self.film_distance * self.film_distance);
+        Some((Ray::new(origin, direction, 0.0), weight))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn double_gauss() -> LensSystem {
+        LensSystem::load(&PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("scenes/dgauss50.lens")).unwrap()
+    }
+
+    fn parse_error(text: &str) -> (Option<usize>, String) {
+        match LensSystem::parse(text) {
+            Err(Error::Parse { line, message, .. }) => (line, message),
+            other => panic!("expected a parse error, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn reports_malformed_prescriptions() {
+        assert_eq!(parse_error("# radius thickness ior aperture\n29.5 3.8 1.67\n"), (Some(2), "expected 4 columns, found 3".to_string()));
+        assert_eq!(parse_error("29.5 3.8 1.67 25\n84.8 0.1 one 25\n"), (Some(2), "invalid number".to_string()));
+        assert_eq!(parse_error("# only a comment\n\n"), (None, "lens file has no elements".to_string()));
+    }
+
+    #[test]
+    fn focuses_the_double_gauss() {
+        let lens = double_gauss();
+        //The prescription's back focal distance is about 36mm
+        let infinity = lens.focus(1000.0).unwrap();
+        assert!((infinity - 0.0361).abs() < 0.0005, "{}", infinity);
+        //Closer planes need the film further back, roughly f^2 / (d - f) for a thin lens
+        let one_metre = lens.focus(1.0).unwrap();
+        assert!(one_metre > infinity && (one_metre - infinity - 0.0027).abs() < 0.0005, "{}", one_metre);
+        for &distance in &[10.0, 2.0, 1.0, 0.5] {
+            let reciprocal = lens.focus_reciprocal(lens.focus(distance).unwrap()).unwrap();
+            assert!((reciprocal * distance - 1.0).abs() < 1e-3, "{} {}", distance, reciprocal);
+        }
+    }
+
+    #[test]
+    fn paraxial_rays_from_the_film_centre_leave_along_the_axis() {
+        let lens = double_gauss();
+        let film_distance = lens.focus(1000.0).unwrap();
+        let (o, d) = lens.trace_from_film(Vec3::zero_vector(), Vec3::new(0.0, 0.0, -1.0), film_distance).unwrap();
+        assert_eq!((o.x(), o.y(), d.x(), d.y()), (0.0, 0.0, 0.0, 0.0));
+        assert!(d.z() < 0.0);
+        //Focused at infinity, a slightly tilted ray from the centre leaves parallel to the axis
+        let h = 0.01 * lens.rear_aperture_radius();
+        let (o, d) = lens.trace_from_film(Vec3::zero_vector(), Vec3::new(h, 0.0, -film_distance), film_distance).unwrap();
+        assert!(o.x() > 0.0 && d.x().abs() < 1e-4 && d.y() == 0.0, "{:?} {:?}", o, d);
+    }
+
+    #[test]
+    fn weights_rays_by_cos4_and_the_rear_disk() {
+        let lens = Arc::new(double_gauss());
+        let camera = RealisticCamera::new(lens.clone(), Vec3::zero_vector(), Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.0, 1.0, 0.0), 0.024, 1.5, 1000.0);
+        let rear = lens.rear_aperture_radius();
+        let full = std::f32::consts::PI * rear * rear / (camera.film_distance * camera.film_distance);
+        let (centre, corner) = (0..64).fold((0.0f32, 1.0f32), |(centre, corner), _| {
+            let c = camera.get_weighted_ray(0.5, 0.5).map_or(centre, |(_, w)| centre.max(w));
+            let e = camera.get_weighted_ray(0.0, 0.0).map_or(corner, |(_, w)| corner.min(w));
+            (c, e)
+        });
+        assert!(centre <= full && centre > 0.95 * full, "{} {}", centre, full);
+        assert!(corner < 0.9 * centre, "{} {}", corner, centre);
+    }
+}
@@ -0,0 +1,118 @@
+//! A physically based path tracer: scenes of spheres, triangle meshes and lights with
+//! next event estimation, progressive and adaptive sampling, reconstruction filters, AOVs,
+//! denoising, checkpoints and distributed rendering.
+//!
+//! Rendering takes a [`Scene`], prepared into a BVH, light distribution and camera by
+//! [`PreparedScene`], and adds samples to a [`Framebuffer`] following [`RenderSettings`].
+//! The framebuffer can be rendered into again for more samples, saved with
+//! [`image::save_image`] or checkpointed. [`render::render_distributed`] shares the tiles with
+//! remote workers as well.
+//!
+//! [`job::RenderJob`] builds all of this from a command line, the way the
+//! `rust-pathtracer-demo` program and its workers and server do, and [`job::run`] renders one.
+//!
+//! ```no_run
+//! use std::path::Path;
+//! use pathtracer::{render, ImageOptions, Framebuffer, PreparedScene, RenderSettings, Scene};
+//!
+//! # fn main() -> pathtracer::Result<()> {
+//! let (width, height) = (320, 180);
+//! let scene = Scene::load(Path::new("scenes/ies_demo.scene"))?;
+//! let prepared = PreparedScene::new(scene.objects, scene.lights, &scene.camera, width, height)?;
+//! let settings = RenderSettings::new(width, height, 64);
+//! let framebuffer = render(&prepared.context(), &settings, Framebuffer::new(width, height));
+//! pathtracer::image::save_image(Path::new("out.png"), &framebuffer, &ImageOptions::new(width, height))?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Loading, preparing and saving report failures as an [`Error`], which names the file and
+//! line at fault where there is one.
+//!
+//! Settings can be changed between construction and rendering. `settings.cancel` is a
+//! [`CancelToken`] that stops a render from another thread, `settings.progress` tells other
+//! threads how far it has got and `settings.observer` is handed a [`ProgressReport`] with the
+//! ETA and sample and ray rates as tiles finish.
+//!
+//! Rendering also counts rays, BVH nodes visited, primitive tests and how paths end into the
+//! framebuffer's [`RenderStats`], per thread and merged tile by tile.
+
+extern crate png;
+extern crate rayon;
+extern crate obj;
+extern crate clap;
+
+/// Three component vectors used for points, directions and colours.
+pub mod vec3;
+/// Rays with a time for motion blur.
+pub mod ray;
+/// The [`Hitable`] trait, hit records, instances and the BVH.
+pub mod hitable;
+/// Static and moving spheres.
+pub mod sphere;
+/// Triangles with optional shading normals.
+pub mod triangle;
+/// Materials, their scattering and the labels used for ID AOVs and light groups.
+pub mod material;
+/// Camera projections, depth of field, shutter and the settings that build them.
+pub mod camera;
+/// Realistic multi-element lenses loaded from prescription files.
+pub mod lens;
+/// Textures sampled by materials.
+pub mod texture;
+/// Axis aligned bounding boxes.
+pub mod aabb;
+/// Affine and animated transforms.
+pub mod transform;
+/// Lights and the power weighted distribution they are sampled from.
+pub mod light;
+/// IES photometric profiles for point and spot lights.
+pub mod ies;
+/// The scene file format and OBJ loading.
+pub mod scene;
+/// Image tiles and the orders they are rendered in.
+pub mod tile;
+/// The integrator and the progressive, tiled render loop.
+pub mod render;
+/// The per thread random streams every sample draws from.
+pub mod rng;
+/// Low discrepancy and stratified sample patterns.
+pub mod sampler;
+/// Per pixel sums that samples accumulate into.
+pub mod framebuffer;
+/// PNG, EXR, PFM and Radiance HDR reading and writing.
+pub mod output;
+/// Tone mapping and display encoding.
+pub mod tonemap;
+/// Pixel reconstruction filters.
+pub mod filter;
+/// Arbitrary output variables: albedo, normals, depth, IDs and light groups.
+pub mod aov;
+/// The AOV guided wavelet denoiser.
+pub mod denoise;
+/// Saving render state to resume later.
+pub mod checkpoint;
+/// Stitching cropped renders back together.
+pub mod merge;
+/// Sharing tiles with worker processes over TCP.
+pub mod distributed;
+/// An HTTP API for queueing and following renders.
+pub mod server;
+/// Writing framebuffers out as images.
+pub mod image;
+/// The error type shared by loading, rendering and saving.
+pub mod error;
+/// Counters gathered while rendering.
+pub mod stats;
+/// Render jobs described by a command line, as the program, its workers and its server take them.
+pub mod job;
+
+pub use error::{Error, Result};
+pub use vec3::Vec3;
+pub use hitable::{BvhNode, Hitable};
+pub use camera::{Camera, CameraSettings};
+pub use scene::Scene;
+pub use framebuffer::Framebuffer;
+pub use render::{render, render_distributed, CancelToken, PreparedScene, Progress, ProgressFn, ProgressReport, RenderControl, RenderSettings, SceneContext};
+pub use image::{save_image, ImageOptions};
+pub use stats::RenderStats;
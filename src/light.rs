@@ -0,0 +1,414 @@
+use vec3::Vec3;
+use ies::OrientedProfile;
+use std::f32::consts::PI;
+
+#[derive(Clone, Copy, Debug)]
+pub enum LightShape {
+    Sphere {
+        center: Vec3,
+        radius: f32
+    },
+    Triangle {
+        p1: Vec3,
+        p2: Vec3,
+        p3: Vec3
+    },
+    Point {
+        position: Vec3
+    },
+    Spot {
+        position: Vec3,
+        direction: Vec3,
+        cos_total: f32,
+        cos_falloff: f32
+    }
+}
+
+/// Emissive geometry extracted from the world before it is moved into the BVH, or a
+/// point/spot light. For area lights `radiance` is emitted radiance, for point and spot
+/// lights it is radiant intensity
+#[derive(Clone, Debug)]
+pub struct Light {
+    shape: LightShape,
+    radiance: Vec3,
+    profile: Option<OrientedProfile>,
+    /// Index of the scene light group its contribution is written to
+    group: usize
+}
+
+pub struct LightSample {
+    pub point: Vec3,
+    /// Incident radiance divided by the solid angle pdf of the sample
+    pub weight: Vec3
+}
+
+/// Walker/Vose alias table for O(1) sampling of a discrete distribution
+pub struct AliasTable {
+    prob: Vec<f32>,
+    alias: Vec<usize>,
+    pmf: Vec<f32>
+}
+
+pub struct LightDistribution {
+    lights: Vec<Light>,
+    table: AliasTable
+}
+
+impl Light {
+    pub fn new(shape: LightShape, radiance: Vec3) -> Light {
+        Light {
+            shape,
+            radiance,
+            profile: None,
+            group: 0
+        }
+    }
+
+    pub fn point(position: Vec3, intensity: Vec3) -> Light {
+        Light::new(LightShape::Point { position }, intensity)
+    }
+
+    pub fn spot(position: Vec3, direction: Vec3, intensity: Vec3, total_width: f32, falloff_start: f32) -> Light {
+        Light::new(LightShape::Spot {
+            position,
+            direction: Vec3::unit_vector(direction),
+            cos_total: total_width.to_radians().cos(),
+            cos_falloff: falloff_start.min(total_width).to_radians().cos()
+        }, intensity)
+    }
+
+    /// Modulates the intensity of a point or spot light by a photometric profile
+    pub fn with_profile(mut self, profile: OrientedProfile) -> Light {
+        self.profile = Some(profile);
+        self
+    }
+
+    pub fn with_group(mut self, group: usize) -> Light {
+        self.group = group;
+        self
+    }
+
+    pub fn group(&self) -> usize {
+        self.group
+    }
+
+    pub fn shape(&self) -> LightShape {
+        self.shape
+    }
+
+    pub fn radiance(&self) -> Vec3 {
+        self.radiance
+    }
+
+    pub fn is_delta(&self) -> bool {
+        matches!(self.shape, LightShape::Point { .. } | LightShape::Spot { .. })
+    }
+
+    pub fn area(&self) -> f32 {
+        match self.shape {
+            LightShape::Sphere { radius, .. } => 4.0 * PI * radius * radius,
+            LightShape::Triangle { p1, p2, p3 } => 0.5 * (p2 - p1).cross(p3 - p1).length(),
+            _ => 0.0
+        }
+    }
+
+    /// Fraction of the intensity of a point or spot light emitted towards `dir`
+    fn intensity_scale(&self, dir: Vec3) -> f32 {
+        let dir = Vec3::unit_vector(dir);
+        let cone = match self.shape {
+            LightShape::Spot { direction, cos_total, cos_falloff, .. } => {
+                let cos_theta = dir.dot(direction);
+                if cos_theta < cos_total {
+                    0.0
+                } else if cos_theta >= cos_falloff {
+                    1.0
+                } else {
+                    let delta = (cos_theta - cos_total) / (cos_falloff - cos_total);
+                    delta * delta * (3.0 - 2.0 * delta)
+                }
+            },
+            _ => 1.0
+        };
+        match self.profile {
+            Some(ref profile) if cone > 0.0 => cone * profile.value(dir),
+            _ => cone
+        }
+    }
+
+    /// Integral of the intensity scale over the sphere of directions
+    fn solid_angle_integral(&self) -> f32 {
+        const THETA_STEPS: usize = 90;
+        const PHI_STEPS: usize = 180;
+        let d_theta = PI / THETA_STEPS as f32;
+        let d_phi = 2.0 * PI / PHI_STEPS as f32;
+        let mut total = 0.0;
+        for i in 0..THETA_STEPS {
+            let theta = (i as f32 + 0.5) * d_theta;
+            for j in 0..PHI_STEPS {
+                let phi = (j as f32 + 0.5) * d_phi;
+                let dir = Vec3::new(theta.sin() * phi.cos(), theta.cos(), theta.sin() * phi.sin());
+                total += self.intensity_scale(dir) * theta.sin() * d_theta * d_phi;
+            }
+        }
+        total
+    }
+
+    /// Total emitted power. Area lights are Lambertian emitters (L * A * pi), doubled for
+    /// triangles as they emit from both faces. Point and spot lights integrate their intensity
+    /// over all directions
+    pub fn power(&self) -> f32 {
+        match self.shape {
+            LightShape::Point { .. } | LightShape::Spot { .. } => self.radiance.luminance() * self.solid_angle_integral(),
+            LightShape::Triangle { .. } => 2.0 * self.radiance.luminance() * self.area() * PI,
+            LightShape::Sphere { .. } => self.radiance.luminance() * self.area() * PI
+        }
+    }
+
+    /// Samples the light as seen from `p`, uniformly by area for area lights
+    pub fn sample(&self, p: Vec3, u1: f32, u2: f32) -> Option<LightSample> {
+        let (point, normal) = match self.shape {
+            LightShape::Sphere { center, radius } => {
+                let z = 1.0 - 2.0 * u1;
+                let r = (1.0 - z * z).max(0.0).sqrt();
+                let phi = 2.0 * PI * u2;
+                let normal = Vec3::new(r * phi.cos(), r * phi.sin(), z);
+                (center + radius * normal, normal)
+            },
+            LightShape::Triangle { p1, p2, p3 } => {
+                let su1 = u1.sqrt();
+                let b0 = 1.0 - su1;
+                let b1 = u2 * su1;
+                let point = b0 * p1 + b1 * p2 + (1.0 - b0 - b1) * p3;
+                (point, Vec3::unit_vector((p2 - p1).cross(p3 - p1)))
+            },
+            LightShape::Point { position } | LightShape::Spot { position, .. } => {
+                let dist_squared = (position - p).squared_length();
+                let scale = self.intensity_scale(p - position);
+                if scale <= 0.0 || dist_squared <= 0.0 {
+                    return None;
+                }
+                return Some(LightSample {
+                    point: position,
+                    weight: (scale / dist_squared) * self.radiance
+                });
+            }
+        };
+
+        let to_light = point - p;
+        let dist_squared = to_light.squared_length();
+        //Triangles emit from both faces, and the far side of a sphere light is occluded by the sphere itself
+        let cos_light = Vec3::unit_vector(to_light).dot(normal).abs();
+        if cos_light <= 0.0 {
+            return None;
+        }
+        Some(LightSample {
+            point,
+            weight: (cos_light * self.area() / dist_squared) * self.radiance
+        })
+    }
+
+    pub fn describe(&self) -> String {
+        let shape = match self.shape {
+            LightShape::Sphere { center, radius } => format!("sphere at ({}, {}, {}) r={}", center.x(), center.y(), center.z(), radius),
+            LightShape::Triangle { p1, p2, p3 } => {
+                let c = (p1 + p2 + p3) / 3.0;
+                format!("triangle at ({}, {}, {})", c.x(), c.y(), c.z())
+            },
+            LightShape::Point { position } => format!("point at ({}, {}, {})", position.x(), position.y(), position.z()),
+            LightShape::Spot { position, direction, cos_total, .. } => format!("spot at ({}, {}, {}) towards ({}, {}, {}), {} deg",
+                position.x(), position.y(), position.z(), direction.x(), direction.y(), direction.z(), cos_total.acos().to_degrees())
+        };
+        match self.profile {
+            Some(_) => format!("{} with IES profile", shape),
+            None => shape
+        }
+    }
+}
+
+impl AliasTable {
+    pub fn new(weights: &[f32]) -> AliasTable {
+        let n = weights.len();
+        let total: f32 = weights.iter().sum();
+        let pmf: Vec<f32> = if total > 0.0 {
+            weights.iter().map(|w| w / total).collect()
+        } else {
+            vec![1.0 / n as f32; n]
+        };
+
+        let mut prob = vec![0.0; n];
+        let mut alias = vec![0; n];
+        let mut scaled: Vec<f32> = pmf.iter().map(|p| p * n as f32).collect();
+        let mut small: Vec<usize> = Vec::new();
+        let mut large: Vec<usize> = Vec::new();
+        for (i, p) in scaled.iter().enumerate() {
+            if *p < 1.0 {
+                small.push(i);
+            } else {
+                large.push(i);
+            }
+        }
+
+        while !small.is_empty() && !large.is_empty() {
+            let s = small.pop().unwrap();
+            let l = large.pop().unwrap();
+            prob[s] = scaled[s];
+            alias[s] = l;
+            scaled[l] = (scaled[l] + scaled[s]) - 1.0;
+            if scaled[l] < 1.0 {
+                small.push(l);
+            } else {
+                large.push(l);
+            }
+        }
+
+        //Leftovers are only off from 1.0 by rounding error
+        for i in small.into_iter().chain(large) {
+            prob[i] = 1.0;
+            alias[i] = i;
+        }
+
+        AliasTable {
+            prob,
+            alias,
+            pmf
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.prob.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.prob.is_empty()
+    }
+
+    pub fn pmf(&self, index: usize) -> f32 {
+        self.pmf[index]
+    }
+
+    /// Returns the sampled index and its probability
+    pub fn sample(&self, u: f32) -> (usize, f32) {
+        let n = self.prob.len();
+        let scaled = u * n as f32;
+        let bucket = (scaled as usize).min(n - 1);
+        let remainder = scaled - bucket as f32;
+        let index = if remainder < self.prob[bucket] {
+            bucket
+        } else {
+            self.alias[bucket]
+        };
+        (index, self.pmf[index])
+    }
+}
+
+impl LightDistribution {
+    pub fn new(lights: Vec<Light>) -> LightDistribution {
+        let powers: Vec<f32> = lights.iter().map(|l| l.power()).collect();
+        LightDistribution {
+            lights,
+            table: AliasTable::new(&powers)
+        }
+    }
+
+    pub fn lights(&self) -> &[Light] {
+        &self.lights
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lights.is_empty()
+    }
+
+    pub fn pmf(&self, index: usize) -> f32 {
+        self.table.pmf(index)
+    }
+
+    pub fn sample(&self, u: f32) -> Option<(&Light, f32)> {
+        if self.lights.is_empty() {
+            return None;
+        }
+        let (index, pmf) = self.table.sample(u);
+        Some((&self.lights[index], pmf))
+    }
+
+    pub fn print_stats(&self) {
+        let total: f32 = self.lights.iter().map(|l| l.power()).sum();
+        println!("{} light(s), total power {:.3}", self.lights.len(), total);
+        for (i, light) in self.lights.iter().enumerate() {
+            println!("  [{}] {}: power {:.3} ({:.2}%)", i, light.describe(), light.power(), 100.0 * self.pmf(i));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fraction of a fine, evenly spaced sweep of `u` that lands on each index
+    fn sweep(table: &AliasTable) -> Vec<f32> {
+        const STEPS: usize = 100_000;
+        let mut counts = vec![0; table.len()];
+        for i in 0..STEPS {
+            let (index, pmf) = table.sample((i as f32 + 0.5) / STEPS as f32);
+            assert_eq!(pmf, table.pmf(index));
+            counts[index] += 1;
+        }
+        counts.into_iter().map(|c| c as f32 / STEPS as f32).collect()
+    }
+
+    #[test]
+    fn samples_each_index_with_its_pmf() {
+        let table = AliasTable::new(&[1.0, 2.0, 3.0, 4.0]);
+        for (index, fraction) in sweep(&table).into_iter().enumerate() {
+            let expected = (index + 1) as f32 / 10.0;
+            assert!((table.pmf(index) - expected).abs() < 1e-6, "{} {}", index, table.pmf(index));
+            assert!((fraction - expected).abs() < 1e-3, "{} {}", index, fraction);
+        }
+    }
+
+    #[test]
+    fn falls_back_to_uniform_without_weight() {
+        let table = AliasTable::new(&[0.0, 0.0, 0.0]);
+        for (index, fraction) in sweep(&table).into_iter().enumerate() {
+            assert_eq!(table.pmf(index), 1.0 / 3.0);
+            assert!((fraction - 1.0 / 3.0).abs() < 1e-3, "{} {}", index, fraction);
+        }
+    }
+
+    #[test]
+    fn triangles_emit_from_both_faces() {
+        let white = Vec3::new(1.0, 1.0, 1.0);
+        let triangle = Light::new(LightShape::Triangle {
+            p1: Vec3::new(0.0, 0.0, 0.0),
+            p2: Vec3::new(2.0, 0.0, 0.0),
+            p3: Vec3::new(0.0, 1.0, 0.0)
+        }, white);
+        let sphere = Light::new(LightShape::Sphere { center: Vec3::zero_vector(), radius: 0.5 }, white);
+        assert!((triangle.power() - 2.0 * PI).abs() < 1e-4, "{}", triangle.power());
+        assert!((sphere.power() - PI * PI).abs() < 1e-4, "{}", sphere.power());
+        //A point light's intensity integrates to 4 pi over the sphere
+        let point = Light::point(Vec3::zero_vector(), white);
+        assert!((point.power() - 4.0 * PI).abs() < 1e-2, "{}", point.power());
+    }
+
+    #[test]
+    fn light_pmfs_sum_to_one() {
+        let lights = vec![
+            Light::new(LightShape::Sphere { center: Vec3::zero_vector(), radius: 1.0 }, Vec3::new(4.0, 4.0, 4.0)),
+            Light::new(LightShape::Triangle {
+                p1: Vec3::zero_vector(),
+                p2: Vec3::new(1.0, 0.0, 0.0),
+                p3: Vec3::new(0.0, 0.0, 1.0)
+            }, Vec3::new(1.0, 0.5, 0.2)),
+            Light::point(Vec3::new(0.0, 3.0, 0.0), Vec3::new(10.0, 10.0, 10.0)),
+            Light::spot(Vec3::zero_vector(), Vec3::new(0.0, -1.0, 0.0), Vec3::new(20.0, 20.0, 20.0), 30.0, 20.0)
+        ];
+        let distribution = LightDistribution::new(lights);
+        let total: f32 = (0..distribution.lights().len()).map(|i| distribution.pmf(i)).sum();
+        assert!((total - 1.0).abs() < 1e-5, "{}", total);
+        let powers: Vec<f32> = distribution.lights().iter().map(|l| l.power()).collect();
+        let sum: f32 = powers.iter().sum();
+        for (i, power) in powers.iter().enumerate() {
+            assert!((distribution.pmf(i) - power / sum).abs() < 1e-5);
+        }
+    }
+}
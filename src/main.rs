@@ -1,234 +1,128 @@
-#![allow(dead_code)]
-use std::path::Path;
-use std::fs::File;
-use std::io::BufWriter;
-
-extern crate png;
-
-extern crate rand;
-
-mod vec3;
-use vec3::Vec3;
-
-mod ray;
-use ray::Ray;
-
-mod hitable;
-use hitable::Hitable;
-use hitable::BvhNode;
-
-mod sphere;
-use sphere::Sphere;
-
-mod triangle;
-use triangle::Triangle;
-
-mod material;
-use material::{DiffuseLight, Lambertian};
-
-mod camera;
-use camera::Camera;
-
-mod texture;
-use texture::ConstantTexture;
-
-mod aabb;
-
-extern crate rayon;
-use rayon::prelude::*;
-
-extern crate obj;
-use obj::Obj;
-
-extern crate clap;
-use clap::{Arg, App};
-
-fn triangulate(vertices: Vec<Vec3>) -> Vec<Box<dyn Hitable + Sync>> {
-    assert!(vertices.len() >= 3, "Input face must have at least 3 vertices!");
-    let mut output: Vec<Box<dyn Hitable + Sync>> = Vec::new();
-
-    //Trivial case: exactly 3 vertices are passed in
-    if vertices.len() == 3 {
-        let edge1 = vertices[1] - vertices[0];
-        let edge2 = vertices[2] - vertices[0];
-        let normal = Vec3::unit_vector(edge1.cross(edge2));
-        let material = Lambertian::new(Box::new(ConstantTexture::new(Vec3::new(1.0, 1.0, 1.0))));
-        output.push(Box::new(Triangle::new(vertices[0], vertices[1], vertices[2], normal, Box::new(material))));
-    } else { //Non trivial case - parse vertices as triangle fan
-        let common_idx = 0;
-        let mut first_idx = 1;
-        let mut second_idx = 2;
-
-        let common_v = vertices[common_idx];
-
-        while second_idx < vertices.len() {
-            let v1 = vertices[first_idx];
-            let v2 = vertices[second_idx];
-            let edge1 = v1 - common_v;
-            let edge2 = v2 - common_v;
-            let normal = Vec3::unit_vector(edge1.cross(edge2));
-            let material = Lambertian::new(Box::new(ConstantTexture::new(Vec3::new(1.0, 1.0, 1.0))));
-            output.push(Box::new(Triangle::new(common_v, v1, v2, normal, Box::new(material))));
-            first_idx = first_idx + 1;
-            second_idx = second_idx + 1;
-        }
+use std::path::{Path, PathBuf};
+use std::io::{IsTerminal, Write};
+use std::time::{Duration, Instant};
+use std::sync::{Arc, Mutex};
+
+extern crate pathtracer;
+use pathtracer::{distributed, job, merge, server, Error};
+use pathtracer::render::{ProgressFn, ProgressReport, RenderControl};
+
+extern crate ctrlc;
+
+//Hours, minutes and seconds, leaving out the hours when there are none
+fn format_duration(d: Duration) -> String {
+    let seconds = d.as_secs();
+    if seconds >= 3600 {
+        format!("{}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60)
+    } else {
+        format!("{}:{:02}", seconds / 60, seconds % 60)
     }
-    output
 }
 
-fn color(r : &Ray, world: &Box<dyn Hitable + Sync>, depth: u32) -> Vec3 {
-    let hit_rec = world.hit(0.001, 50.0, r);
-    if hit_rec.is_some() {
-        let hit_rec = hit_rec.unwrap();
-        let material = hit_rec.material;
-        let normal = hit_rec.normal;
-        let point = hit_rec.p;
-        let t = hit_rec.t;
-        let scatter_rec = material.scatter(r, t, point, normal);
-        let emitted = material.emitted(0.0, 0.0, &point);
-        if scatter_rec.is_some() && depth < 50 {
-            let scatter_rec = scatter_rec.unwrap();
-            return emitted + scatter_rec.attenuation * color(&scatter_rec.scattered, world, depth + 1);
-        } else {
-            return emitted;
-        }
+//A rate with a metric prefix, like 12.3M
+fn format_rate(rate: f64) -> String {
+    match rate {
+        r if r >= 1e9 => format!("{:.1}G", r / 1e9),
+        r if r >= 1e6 => format!("{:.1}M", r / 1e6),
+        r if r >= 1e3 => format!("{:.1}k", r / 1e3),
+        r => format!("{:.0}", r)
     }
-    Vec3::new(0.0, 0.0, 0.0)
-    //let unit_direction = Vec3::unit_vector(r.direction());
-    //let t = 0.5 * (unit_direction.y() + 1.0);
-    //(1.0 - t) * Vec3::new(1.0, 1.0, 1.0) + t * Vec3::new(0.5, 0.7, 1.0)
 }
 
-fn main() {
-    //Setup args
-    let matches = App::new("Pathtracer")
-                        .arg(Arg::with_name("INPUT")
-                                    .required(true))
-                        .arg(Arg::with_name("samples_per_pixel")
-                                    .short("s")
-                                    .long("spp")
-                                    .help("Number of samples per pixel")
-                                    .takes_value(true))
-                        .arg(Arg::with_name("width")
-                                    .short("w")
-                                    .long("width")
-                                    .help("Rendered image width")
-                                    .takes_value(true))
-                        .arg(Arg::with_name("height")
-                                    .short("h")
-                                    .long("height")
-                                    .help("Rendered image height")
-                                    .takes_value(true))
-                        .arg(Arg::with_name("output")
-                                    .short("o")
-                                    .long("output")
-                                    .help("Output image path")
-                                    .takes_value(true))
-                        .get_matches();
-
-    let filename = matches.value_of("INPUT").unwrap();
-    let samples_per_pixel = matches.value_of("samples_per_pixel").unwrap_or("100");
-    let image_width = matches.value_of("width").unwrap_or("480");
-    let image_height = matches.value_of("height").unwrap_or("270");
-    let output_filename = matches.value_of("output").unwrap_or("output.png");
+//Calls `show` with at most ten reports a second, but always with the last one
+fn throttled<F: Fn(&ProgressReport) + Send + Sync + 'static>(show: F) -> ProgressFn {
+    let last_shown: Mutex<Option<Instant>> = Mutex::new(None);
+    Arc::new(move |report: &ProgressReport| {
+        let mut last = last_shown.lock().unwrap();
+        if !report.done && last.is_some_and(|t| t.elapsed() < Duration::from_millis(100)) {
+            return;
+        }
+        *last = Some(Instant::now());
+        show(report);
+    })
+}
 
-    let samples_per_pixel = samples_per_pixel.parse::<usize>().unwrap();
-    let image_width = image_width.parse::<u32>().unwrap();
-    let image_height = image_height.parse::<u32>().unwrap();
+//A bar redrawn in place on stderr
+fn progress_bar() -> ProgressFn {
+    throttled(|report| {
+        const WIDTH: usize = 30;
+        let filled = ((report.fraction * WIDTH as f32) as usize).min(WIDTH);
+        let eta = report.eta.map_or("--:--".to_string(), format_duration);
+        eprint!("\r[{}{}] {:5.1}%  {} spp pass {}/{} tiles  {} elapsed, {} left  {} samples/s  {} rays/s   ",
+                "#".repeat(filled), "-".repeat(WIDTH - filled), 100.0 * report.fraction, report.pass_total,
+                report.tiles_done, report.tiles, format_duration(report.elapsed), eta,
+                format_rate(report.samples_per_second), format_rate(report.rays_per_second));
+        if report.done {
+            eprintln!();
+        }
+    })
+}
 
-    println!("Generating a {}x{}@{}spp render of {}, saving to {}", image_width, image_height, samples_per_pixel, filename, output_filename);
+//One JSON object per report on stdout, for other programs to follow
+fn json_progress() -> ProgressFn {
+    throttled(|report| {
+        let eta = report.eta.map_or("null".to_string(), |d| format!("{:.3}", d.as_secs_f64()));
+        let mut stdout = std::io::stdout().lock();
+        let _ = writeln!(stdout, "{{\"progress\": {:.4}, \"elapsed\": {:.3}, \"eta\": {}, \"samples_per_second\": {:.0}, \"rays_per_second\": {:.0}, \
+                                  \"pass\": {}, \"tiles_done\": {}, \"tiles\": {}, \"done\": {}}}",
+                         report.fraction, report.elapsed.as_secs_f64(), eta, report.samples_per_second, report.rays_per_second,
+                         report.pass_total, report.tiles_done, report.tiles, report.done);
+        let _ = stdout.flush();
+    })
+}
 
-    //Generate world
-    let mut world: Vec<Box<dyn Hitable + Sync>> = Vec::new();
+//Reports what went wrong and exits with a status saying what kind of error it was
+fn fail(action: &str, error: &Error) -> ! {
+    eprintln!("{}: {}", action, error);
+    std::process::exit(error.exit_code());
+}
 
-    let obj_file = Obj::load(Path::new(filename)).unwrap();
-    for object in obj_file.data.objects.iter() {
-        for group in object.groups.iter() {
-            for polygon in group.polys.iter() {
-                let mut vertices: Vec<Vec3> = Vec::new();
-                for vertex in polygon.0.iter() {
-                    let index = vertex.0;
-                    let position = obj_file.data.position[index];
-                    vertices.push(Vec3::new(position[0], position[1], position[2]));
-                }
-                world.append(&mut triangulate(vertices));
-            }
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let matches = job::app().get_matches_from(&args);
+
+    if let Some(matches) = matches.subcommand_matches("merge") {
+        let inputs: Vec<PathBuf> = matches.values_of("INPUTS").unwrap().map(PathBuf::from).collect();
+        let output_path = Path::new(matches.value_of("output").unwrap());
+        if let Err(e) = merge::merge(&inputs, output_path) {
+            fail(&format!("Failed to merge into {}", output_path.display()), &e);
         }
+        return;
     }
 
-    world.push(Box::new(Sphere::new(Vec3::new(0.0, 0.0, 2.0), 0.5, Box::new(DiffuseLight::new(Box::new(ConstantTexture::new(Vec3::new(2.0, 2.0, 2.0))))))));
-
-    let bvh_tree: Box<dyn Hitable + Sync> = Box::new(BvhNode::new(world));
-    //Setup camera
-    let lookfrom = 3.0 * Vec3::new(-2.26788425, 0.320256859, 1.83503199);
-    let lookat = Vec3::new(-1.33643341, 0.320256859, 1.47116470);
-    let focus_dist = (lookfrom - lookat).length();
-    let aperture = 0.0;
-    let camera = Camera::new(lookfrom, lookat, Vec3::new(0.0, 1.0, 0.0), 20.0, image_width as f32 / image_height as f32, aperture, focus_dist);
-
-    //Generate image
-    let mut data = Vec::new();
-
-    //Save start time
-    let start_time = std::time::Instant::now();
-
-    for y in (0..image_height).rev() {
-        for x in 0..image_width {
-            let mut samples = vec![Vec3::zero_vector(); samples_per_pixel];
-
-            samples.par_iter_mut().for_each( |sample| {
-                let u = (x as f32 + rand::random::<f32>()) / image_width as f32;
-                let v = (y as f32 + rand::random::<f32>()) / image_height as f32;
-
-                let r = camera.get_ray(u, v);
-                *sample = color(&r, &bvh_tree, 0);
-            });
-
-            let mut avg_color = Vec3::zero_vector();
-
-            samples.iter().for_each( |sample| {
-                avg_color = avg_color + *sample;
-            });
-
-            avg_color = avg_color / samples_per_pixel as f32;
-
-            //Do gamma correction
-            avg_color = avg_color.clamp(Vec3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 1.0, 1.0));
-            avg_color = Vec3::new(avg_color.x().sqrt(), avg_color.y().sqrt(), avg_color.z().sqrt());
-
-            let ir = (255.99*avg_color.x()) as u8;
-            let ig = (255.99*avg_color.y()) as u8;
-            let ib = (255.99*avg_color.z()) as u8;
-
-            data.push(ir);
-            data.push(ig);
-            data.push(ib);
-            data.push(255);
+    if let Some(matches) = matches.subcommand_matches("serve") {
+        let address = matches.value_of("listen").unwrap_or("127.0.0.1:7878");
+        if let Err(e) = distributed::serve(address) {
+            fail(&format!("Failed to listen on {}", address), &Error::from(e));
         }
-        print!("{} / {} scanlines rendered \r", (image_height - y), image_height)
+        return;
     }
 
-    //Save end time
-    let end_time = std::time::Instant::now();
-
-    let render_duration = end_time.duration_since(start_time);
-    let render_time_sec = render_duration.as_secs();
-    let render_time_ms = render_duration.subsec_millis();
-
-    println!("Render took {}.{} seconds", render_time_sec, render_time_ms);
-
-    //Store image to file
-    let path = Path::new(output_filename);
-    let file = File::create(path).unwrap();
-    let ref mut w = BufWriter::new(file);
-
-    let mut encoder = png::Encoder::new(w, image_width, image_height);
-    encoder.set_color(png::ColorType::RGBA);
-    encoder.set_depth(png::BitDepth::Eight);
-    let mut writer = encoder.write_header().unwrap();
-
-
-    writer.write_image_data(&data).unwrap();
+    if let Some(matches) = matches.subcommand_matches("server") {
+        let address = matches.value_of("listen").unwrap_or("127.0.0.1:8080");
+        let directory = Path::new(matches.value_of("directory").unwrap_or("jobs"));
+        if let Err(e) = server::serve(address, directory) {
+            fail(&format!("Failed to serve on {}", address), &Error::from(e));
+        }
+        return;
+    }
 
-    println!("Done");
+    let observer = if matches.is_present("json_progress") {
+        Some(json_progress())
+    } else if !matches.is_present("quiet") && std::io::stderr().is_terminal() {
+        Some(progress_bar())
+    } else {
+        None
+    };
+    let control = RenderControl {
+        observer,
+        ..RenderControl::default()
+    };
+    let cancel = control.cancel.clone();
+    if let Err(e) = ctrlc::set_handler(move || cancel.cancel()) {
+        eprintln!("Ctrl-C will not stop the render cleanly: {}", e);
+    }
+    if let Err(e) = job::run(&args, &control) {
+        fail("Failed to render", &e);
+    }
 }
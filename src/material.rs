@@ -1,8 +1,8 @@
 use ray::Ray;
 use vec3::Vec3;
 use texture::Texture;
+use rng;
 
-extern crate rand;
 
 pub struct ScatterRecord {
     pub attenuation: Vec3,
@@ -12,6 +12,32 @@ pub struct ScatterRecord {
 pub trait Material {
     fn scatter(&self, r: &Ray, t: f32, point: Vec3, normal: Vec3) -> Option<ScatterRecord>;
     fn emitted(&self, u: f32, v: f32, p: &Vec3) -> Vec3;
+    /// Albedo of a perfectly diffuse surface, used for explicit light sampling
+    fn diffuse_albedo(&self, _p: &Vec3) -> Option<Vec3> {
+        None
+    }
+    /// Mirror-like, so the albedo and normal AOVs look through it to the next surface
+    fn is_specular(&self) -> bool {
+        false
+    }
+    fn labels(&self) -> Labels {
+        Labels::default()
+    }
+}
+
+/// What the scene says a material belongs to: the ids written to the object and material ID
+/// AOVs, 0 meaning none, and the light group its emission counts towards
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Labels {
+    pub object_id: u32,
+    pub material_id: u32,
+    pub light_group: usize
+}
+
+/// Attaches labels to another material
+pub struct Labelled {
+    material: Box<dyn Material + Sync>,
+    labels: Labels
 }
 
 pub struct Lambertian {
@@ -68,6 +94,15 @@ impl Isotropic {
     }
 }
 
+impl Labelled {
+    pub fn new(material: Box<dyn Material + Sync>, labels: Labels) -> Labelled {
+        Labelled {
+            material,
+            labels
+        }
+    }
+}
+
 impl DiffuseLight {
     pub fn new(emit: Box<dyn Texture + Sync>) -> DiffuseLight {
         DiffuseLight {
@@ -76,13 +111,14 @@ impl DiffuseLight {
     }
 }
 
+/// Maps exactly three sample dimensions to the ball rather than rejecting, so stratified
+/// and low discrepancy samples keep their structure
 fn random_in_unit_sphere() -> Vec3 {
-    loop {
-        let p = 2.0 * Vec3::new(rand::random::<f32>(), rand::random::<f32>(), rand::random::<f32>()) - Vec3::new(1.0, 1.0, 1.0);
-        if p.squared_length() < 1.0 {
-            return p;
-        }
-    }
+    let z = 1.0 - 2.0 * rng::random();
+    let phi = 2.0 * std::f32::consts::PI * rng::random();
+    let r = rng::random().cbrt();
+    let ring = (1.0 - z * z).max(0.0).sqrt();
+    r * Vec3::new(ring * phi.cos(), ring * phi.sin(), z)
 }
 
 fn reflect(v: Vec3, n: Vec3) -> Vec3 {
@@ -118,23 +154,26 @@ fn refract(v: Vec3, n: Vec3, ni_over_nt: f32) -> RefractRecord {
 }
 
 impl Material for Lambertian {
-    fn scatter(&self, _r: &Ray, _t: f32, point: Vec3, normal: Vec3) -> Option<ScatterRecord> {
+    fn scatter(&self, r: &Ray, _t: f32, point: Vec3, normal: Vec3) -> Option<ScatterRecord> {
         let target = point + normal + random_in_unit_sphere();
         Some(ScatterRecord {
             attenuation: self.albedo.value(0.0, 0.0, &point),
-            scattered: Ray::new(point, target - point)
+            scattered: Ray::new(point, target - point, r.time())
         })
     }
     fn emitted(&self, _u: f32, _v: f32, _p: &Vec3) -> Vec3 {
         Vec3::new(0.0, 0.0, 0.0)
     }
+    fn diffuse_albedo(&self, p: &Vec3) -> Option<Vec3> {
+        Some(self.albedo.value(0.0, 0.0, p))
+    }
 }
 
 impl Material for Metal {
     fn scatter(&self, r: &Ray, _t: f32, point: Vec3, normal: Vec3) -> Option<ScatterRecord> {
         let reflected = reflect(Vec3::unit_vector(r.direction()), normal);
 
-        let scattered = Ray::new(point, reflected + self.fuzz*random_in_unit_sphere());
+        let scattered = Ray::new(point, reflected + self.fuzz*random_in_unit_sphere(), r.time());
         if scattered.direction().dot(normal) > 0.0 {
             Some(ScatterRecord {
                 attenuation: self.albedo.value(0.0, 0.0, &point),
@@ -147,6 +186,9 @@ impl Material for Metal {
     fn emitted(&self, _u: f32, _v: f32, _p: &Vec3) -> Vec3 {
         Vec3::new(0.0, 0.0, 0.0)
     }
+    fn is_specular(&self) -> bool {
+        true
+    }
 }
 
 impl Material for Dielectric {
@@ -155,8 +197,6 @@ impl Material for Dielectric {
         let reflected = reflect(r.direction(), normal);
         let ni_over_nt: f32;
         let cosine: f32;
-        let reflect_prob: f32;
-        let scattered: Ray;
 
         if r.direction().dot(normal) > 0.0 {
             outward_normal = -1.0 * normal;
@@ -165,20 +205,20 @@ impl Material for Dielectric {
         } else {
             outward_normal = normal;
             ni_over_nt = 1.0 / self.ref_idx;
-            cosine = -1.0 * r.direction().dot(normal) / r.direction().length();
+            cosine = -r.direction().dot(normal) / r.direction().length();
         }
         let refract_rec = refract(r.direction(), outward_normal, ni_over_nt);
-        if refract_rec.should_refract {
-            reflect_prob = schlick(cosine, self.ref_idx);
+        let reflect_prob = if refract_rec.should_refract {
+            schlick(cosine, self.ref_idx)
         } else {
-            reflect_prob = 1.0;
-        }
+            1.0
+        };
 
-        if rand::random::<f32>() < reflect_prob {
-            scattered = Ray::new(point, reflected);
+        let scattered = if rng::random() < reflect_prob {
+            Ray::new(point, reflected, r.time())
         } else {
-            scattered = Ray::new(point, refract_rec.refracted);
-        }
+            Ray::new(point, refract_rec.refracted, r.time())
+        };
 
         Some(ScatterRecord {
             attenuation: Vec3::new(1.0, 1.0, 1.0),
@@ -188,13 +228,16 @@ impl Material for Dielectric {
     fn emitted(&self, _u: f32, _v: f32, _p: &Vec3) -> Vec3 {
         Vec3::new(0.0, 0.0, 0.0)
     }
+    fn is_specular(&self) -> bool {
+        true
+    }
 }
 
 impl Material for Isotropic {
-    fn scatter(&self, _r: &Ray, _t: f32, point: Vec3, _normal: Vec3) -> Option<ScatterRecord> {
+    fn scatter(&self, r: &Ray, _t: f32, point: Vec3, _normal: Vec3) -> Option<ScatterRecord> {
         Some(ScatterRecord {
             attenuation: self.albedo.value(0.0, 0.0, &point),
-            scattered: Ray::new(point, random_in_unit_sphere())
+            scattered: Ray::new(point, random_in_unit_sphere(), r.time())
         })
     }
     fn emitted(&self, _u: f32, _v: f32, _p: &Vec3) -> Vec3 {
@@ -210,3 +253,21 @@ impl Material for DiffuseLight {
         self.emit.value(u, v, p)
     }
 }
+
+impl Material for Labelled {
+    fn scatter(&self, r: &Ray, t: f32, point: Vec3, normal: Vec3) -> Option<ScatterRecord> {
+        self.material.scatter(r, t, point, normal)
+    }
+    fn emitted(&self, u: f32, v: f32, p: &Vec3) -> Vec3 {
+        self.material.emitted(u, v, p)
+    }
+    fn diffuse_albedo(&self, p: &Vec3) -> Option<Vec3> {
+        self.material.diffuse_albedo(p)
+    }
+    fn is_specular(&self) -> bool {
+        self.material.is_specular()
+    }
+    fn labels(&self) -> Labels {
+        self.labels
+    }
+}
@@ -0,0 +1,114 @@
+use output::{self, Channel, ExrPrecision, ImageFormat};
+use tile::Tile;
+use error::{Error, Result};
+use std::path::{Path, PathBuf};
+
+/// Stitches images rendered with --crop back into one. EXR crops carry their region as the data
+/// window; PNG crops are transparent outside it. Later inputs win where crops overlap
+pub fn merge(inputs: &[PathBuf], output_path: &Path) -> Result<()> {
+    let format = ImageFormat::from_path(output_path)?;
+    if let Some(input) = inputs.iter().find(|i| ImageFormat::from_path(i).ok() != Some(format)) {
+        return Err(Error::Usage(format!("{} is not in the format of {}", input.display(), output_path.display())));
+    }
+    match format {
+        ImageFormat::Exr => merge_exr(inputs, output_path),
+        ImageFormat::Png => merge_png(inputs, output_path),
+        _ => Err(Error::Usage("only EXR and PNG images can be merged".to_string()))
+    }
+}
+
+fn merge_exr(inputs: &[PathBuf], output_path: &Path) -> Result<()> {
+    let (mut width, mut height, mut float) = (0, 0, false);
+    let mut channels: Vec<Channel> = Vec::new();
+    for (i, input) in inputs.iter().enumerate() {
+        let image = output::read_exr(input)?;
+        if i == 0 {
+            width = image.width;
+            height = image.height;
+        } else if (image.width, image.height) != (width, height) {
+            return Err(Error::Usage(format!("{} is {}x{}, not {}x{}", input.display(), image.width, image.height, width, height)));
+        }
+        float |= image.float;
+        for channel in image.channels {
+            let index = match channels.iter().position(|c| c.name == channel.name) {
+                Some(index) => index,
+                None => {
+                    channels.push(Channel { name: channel.name.clone(), values: vec![0.0; (width * height) as usize] });
+                    channels.len() - 1
+                }
+            };
+            let window = &image.window;
+            for row in window.y0..window.y1 {
+                let range = (row * width + window.x0) as usize..(row * width + window.x1) as usize;
+                channels[index].values[range.clone()].copy_from_slice(&channel.values[range]);
+            }
+        }
+    }
+    let precision = if float { ExrPrecision::Float } else { ExrPrecision::Half };
+    let window = Tile { x0: 0, y0: 0, x1: width, y1: height };
+    output::write_exr(output_path, width, height, &window, &channels, precision)
+}
+
+fn merge_png(inputs: &[PathBuf], output_path: &Path) -> Result<()> {
+    let (mut width, mut height, mut bit_depth) = (0, 0, 8);
+    let mut data = Vec::new();
+    for (i, input) in inputs.iter().enumerate() {
+        let (w, h, depth, pixels) = output::read_png(input)?;
+        if i == 0 {
+            width = w;
+            height = h;
+            bit_depth = depth;
+            data = vec![0; pixels.len()];
+        } else if (w, h, depth) != (width, height, bit_depth) {
+            return Err(Error::Usage(format!("{} is {}x{} at {} bits, not {}x{} at {} bits",
+                                       input.display(), w, h, depth, width, height, bit_depth)));
+        }
+        //Anything rendered is opaque, so any alpha at all marks a pixel of the crop
+        let bytes = if bit_depth == 16 { 8 } else { 4 };
+        for (target, source) in data.chunks_mut(bytes).zip(pixels.chunks(bytes)) {
+            if source[bytes / 4 * 3..].iter().any(|b| *b != 0) {
+                target.copy_from_slice(source);
+            }
+        }
+    }
+    output::write_png(output_path, width, height, &data, bit_depth)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use job;
+    use render::RenderControl;
+    use std::env;
+    use std::fs;
+
+    fn render(output: &Path, extra: &[&str]) {
+        let scene = format!("{}/scenes/ies_demo.scene", env!("CARGO_MANIFEST_DIR"));
+        let mut args: Vec<String> = ["pathtracer", scene.as_str(), "-w", "24", "-h", "14", "-s", "4", "-q", "--filter", "mitchell",
+                                     "--exr-precision", "float", "-o"].iter().map(|s| s.to_string()).collect();
+        args.push(output.display().to_string());
+        args.extend(extra.iter().map(|s| s.to_string()));
+        job::run(&args, &RenderControl::default()).unwrap();
+    }
+
+    #[test]
+    fn halves_merge_into_the_full_render() {
+        for extension in &["exr", "png"] {
+            let path = |name: &str| env::temp_dir().join(format!("pathtracer-merge-{}-{}.{}", std::process::id(), name, extension));
+            let (full, left, right, merged) = (path("full"), path("left"), path("right"), path("merged"));
+            render(&full, &[]);
+            render(&left, &["--crop", "0,0,0.5,1"]);
+            render(&right, &["--crop", "0.5,0,1,1"]);
+            merge(&[left.clone(), right.clone()], &merged).unwrap();
+            let (expected, stitched) = (fs::read(&full).unwrap(), fs::read(&merged).unwrap());
+            let halves = output::read_exr(&left).map(|image| (image.window.x0, image.window.x1));
+            for file in [&full, &left, &right, &merged] {
+                fs::remove_file(file).ok();
+            }
+            if *extension == "exr" {
+                assert_eq!(halves.unwrap(), (0, 12));
+            }
+            assert!(expected == stitched, "merged {} differs from the full render", extension);
+        }
+    }
+}
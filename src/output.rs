@@ -0,0 +1,507 @@
+use vec3::Vec3;
+use tile::Tile;
+use error::{Error, Result};
+use framebuffer;
+use png;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ImageFormat {
+    Png,
+    Exr,
+    Pfm,
+    Hdr
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExrPrecision {
+    Half,
+    Float
+}
+
+/// One channel of a multi-layer image, named `layer.channel` or just `channel` for the main layer
+pub struct Channel {
+    pub name: String,
+    pub values: Vec<f32>
+}
+
+impl ImageFormat {
+    /// Picks the format from the file extension, which must be one of png, exr, pfm or hdr
+    pub fn from_path(path: &Path) -> Result<ImageFormat> {
+        let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("").to_lowercase();
+        match extension.as_str() {
+            "png" => Ok(ImageFormat::Png),
+            "exr" => Ok(ImageFormat::Exr),
+            "pfm" => Ok(ImageFormat::Pfm),
+            "hdr" => Ok(ImageFormat::Hdr),
+            _ => Err(Error::Usage(format!("can't tell the image format of {}; use a .png, .exr, .pfm or .hdr extension", path.display())))
+        }
+    }
+
+    /// Whether the format keeps linear radiance, rather than display referred 8-bit values
+    pub fn is_hdr(self) -> bool {
+        self != ImageFormat::Png
+    }
+}
+
+impl ExrPrecision {
+    pub fn from_name(name: &str) -> Option<ExrPrecision> {
+        match name {
+            "half" => Some(ExrPrecision::Half),
+            "float" => Some(ExrPrecision::Float),
+            _ => None
+        }
+    }
+}
+
+/// Creates `path` and has `encode` write its contents, naming the file in any error
+fn write_file<F: FnOnce(&mut dyn Write) -> Result<()>>(path: &Path, encode: F) -> Result<()> {
+    let file = File::create(path).map_err(|e| Error::io(path, e))?;
+    let mut w = BufWriter::new(file);
+    encode(&mut w).and_then(|_| Ok(w.flush()?)).map_err(|e| e.in_file(path))
+}
+
+/// RGBA data of the given bit depth, 16-bit samples big endian
+pub fn write_png(path: &Path, width: u32, height: u32, data: &[u8], bit_depth: u32) -> Result<()> {
+    write_file(path, |w| encode_png(w, width, height, data, bit_depth))
+}
+
+pub fn encode_png(w: &mut dyn Write, width: u32, height: u32, data: &[u8], bit_depth: u32) -> Result<()> {
+    let mut encoder = png::Encoder::new(w, width, height);
+    encoder.set_color(png::ColorType::RGBA);
+    encoder.set_depth(if bit_depth == 16 { png::BitDepth::Sixteen } else { png::BitDepth::Eight });
+    let mut writer = encoder.write_header()?;
+
+    Ok(writer.write_image_data(data)?)
+}
+
+/// Portable float map: a small text header then little endian floats, bottom row first
+pub fn write_pfm(path: &Path, width: u32, height: u32, pixels: &[Vec3]) -> Result<()> {
+    write_file(path, |w| {
+        write!(w, "PF\n{} {}\n-1.0\n", width, height)?;
+        for row in pixels.chunks(width as usize).rev() {
+            for p in row {
+                for c in [p.x(), p.y(), p.z()] {
+                    w.write_all(&c.to_le_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Shared exponent encoding used by Radiance: three 8-bit mantissas and one exponent
+fn rgbe(p: Vec3) -> [u8; 4] {
+    let (r, g, b) = (p.x().max(0.0), p.y().max(0.0), p.z().max(0.0));
+    let v = r.max(g).max(b);
+    if v < 1e-32 || !v.is_finite() {
+        return [0, 0, 0, 0];
+    }
+    //v = m * 2^e with m in [0.5, 1)
+    let e = ((v.to_bits() >> 23) & 0xff) as i32 - 126;
+    let scale = 256.0 / 2f32.powi(e);
+    [(r * scale) as u8, (g * scale) as u8, (b * scale) as u8, (e + 128) as u8]
+}
+
+/// Radiance RGBE picture with flat (not run length encoded) scanlines, top row first
+pub fn write_hdr(path: &Path, width: u32, height: u32, pixels: &[Vec3]) -> Result<()> {
+    write_file(path, |w| {
+        write!(w, "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n", height, width)?;
+        for p in pixels {
+            w.write_all(&rgbe(*p))?;
+        }
+        Ok(())
+    })
+}
+
+/// Rounds to the nearest half float, ties to even, overflowing to infinity and keeping subnormals
+fn to_half(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32;
+    let mantissa = bits & 0x7f_ffff;
+    if exponent == 0xff {
+        return sign | 0x7c00 | if mantissa != 0 { 0x200 } else { 0 };
+    }
+    let e = exponent - 127 + 15;
+    if e >= 0x1f {
+        return sign | 0x7c00;
+    }
+    //Keep the implicit leading bit for subnormals, which shift it into the mantissa
+    let (m, shift, base) = if e <= 0 {
+        if e < -10 {
+            return sign;
+        }
+        (mantissa | 0x80_0000, (14 - e) as u32, 0)
+    } else {
+        (mantissa, 13, (e as u32) << 10)
+    };
+    let half = base | (m >> shift);
+    let halfway = 1 << (shift - 1);
+    let rest = m & ((1 << shift) - 1);
+    //A carry out of the mantissa correctly moves up an exponent, or on to infinity
+    let rounded = if rest > halfway || (rest == halfway && half & 1 == 1) { half + 1 } else { half };
+    sign | rounded as u16
+}
+
+fn from_half(half: u16) -> f32 {
+    let sign = if half & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exponent = ((half >> 10) & 0x1f) as i32;
+    let mantissa = (half & 0x3ff) as f32;
+    match exponent {
+        0 => sign * mantissa * 2f32.powi(-24),
+        0x1f if mantissa == 0.0 => sign * f32::INFINITY,
+        0x1f => f32::NAN,
+        e => sign * (1.0 + mantissa / 1024.0) * 2f32.powi(e - 15)
+    }
+}
+
+fn attribute(header: &mut Vec<u8>, name: &str, kind: &str, value: &[u8]) {
+    header.extend_from_slice(name.as_bytes());
+    header.push(0);
+    header.extend_from_slice(kind.as_bytes());
+    header.push(0);
+    header.extend_from_slice(&(value.len() as i32).to_le_bytes());
+    header.extend_from_slice(value);
+}
+
+/// Single part, uncompressed scanline OpenEXR. Channels are written in name order as the format
+/// requires; names with a `layer.` prefix show up as separate layers in compositors. Channels
+/// cover the whole width x height image, of which only `window` is stored, as the data window
+pub fn write_exr(path: &Path, width: u32, height: u32, window: &Tile, channels: &[Channel], precision: ExrPrecision) -> Result<()> {
+    let mut channels: Vec<&Channel> = channels.iter().collect();
+    channels.sort_by(|a, b| a.name.cmp(&b.name));
+    let (pixel_type, bytes_per_value) = match precision {
+        ExrPrecision::Half => (1i32, 2),
+        ExrPrecision::Float => (2i32, 4)
+    };
+
+    let mut list = Vec::new();
+    for channel in channels.iter() {
+        list.extend_from_slice(channel.name.as_bytes());
+        list.push(0);
+        list.extend_from_slice(&pixel_type.to_le_bytes());
+        //pLinear and three reserved bytes, then x and y sampling
+        list.extend_from_slice(&[0, 0, 0, 0]);
+        list.extend_from_slice(&1i32.to_le_bytes());
+        list.extend_from_slice(&1i32.to_le_bytes());
+    }
+    list.push(0);
+
+    let box2i = |x0: u32, y0: u32, x1: u32, y1: u32| {
+        let mut value = Vec::new();
+        for v in [x0 as i32, y0 as i32, x1 as i32 - 1, y1 as i32 - 1] {
+            value.extend_from_slice(&v.to_le_bytes());
+        }
+        value
+    };
+
+    let mut header = vec![0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0];
+    attribute(&mut header, "channels", "chlist", &list);
+    attribute(&mut header, "compression", "compression", &[0]);
+    attribute(&mut header, "dataWindow", "box2i", &box2i(window.x0, window.y0, window.x1, window.y1));
+    attribute(&mut header, "displayWindow", "box2i", &box2i(0, 0, width, height));
+    attribute(&mut header, "lineOrder", "lineOrder", &[0]);
+    attribute(&mut header, "pixelAspectRatio", "float", &1f32.to_le_bytes());
+    attribute(&mut header, "screenWindowCenter", "v2f", &[0; 8]);
+    attribute(&mut header, "screenWindowWidth", "float", &1f32.to_le_bytes());
+    header.push(0);
+
+    //One chunk per scanline, each a y coordinate, a byte count and the channels one after another
+    let line_bytes = window.width() as usize * channels.len() * bytes_per_value;
+    let chunk_bytes = 8 + line_bytes;
+    let table_end = header.len() + 8 * window.height() as usize;
+
+    write_file(path, |w| {
+        w.write_all(&header)?;
+        for line in 0..window.height() as usize {
+            w.write_all(&((table_end + line * chunk_bytes) as u64).to_le_bytes())?;
+        }
+        for y in window.y0 as usize..window.y1 as usize {
+            w.write_all(&(y as i32).to_le_bytes())?;
+            w.write_all(&(line_bytes as i32).to_le_bytes())?;
+            for channel in channels.iter() {
+                let start = y * width as usize;
+                let row = &channel.values[start + window.x0 as usize..start + window.x1 as usize];
+                for &v in row {
+                    match precision {
+                        ExrPrecision::Half => w.write_all(&to_half(v).to_le_bytes())?,
+                        ExrPrecision::Float => w.write_all(&v.to_le_bytes())?
+                    }
+                }
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Splits colours into the R, G and B channels of a layer, the main layer when `layer` is empty
+pub fn rgb_channels(layer: &str, pixels: &[Vec3]) -> Vec<Channel> {
+    let prefix = if layer.is_empty() { String::new() } else { format!("{}.", layer) };
+    vec![
+        Channel { name: format!("{}R", prefix), values: pixels.iter().map(|p| p.x()).collect() },
+        Channel { name: format!("{}G", prefix), values: pixels.iter().map(|p| p.y()).collect() },
+        Channel { name: format!("{}B", prefix), values: pixels.iter().map(|p| p.z()).collect() }
+    ]
+}
+
+/// An image read back by `read_exr`, its channels covering the whole display window and zero
+/// outside the data window
+pub struct ExrImage {
+    pub width: u32,
+    pub height: u32,
+    pub window: Tile,
+    pub channels: Vec<Channel>,
+    /// Whether any channel was stored as 32-bit float rather than half
+    pub float: bool
+}
+
+fn read_i32(r: &mut dyn Read) -> io::Result<i32> {
+    let mut b = [0; 4];
+    r.read_exact(&mut b)?;
+    Ok(i32::from_le_bytes(b))
+}
+
+fn read_box2i(value: &[u8]) -> Result<(i32, i32, i32, i32)> {
+    if value.len() != 16 {
+        return Err(Error::parse("malformed box2i attribute"));
+    }
+    let v = |i: usize| i32::from_le_bytes([value[4 * i], value[4 * i + 1], value[4 * i + 2], value[4 * i + 3]]);
+    Ok((v(0), v(1), v(2), v(3)))
+}
+
+/// Reads the uncompressed scanline files `write_exr` produces, with half or float channels
+pub fn read_exr(path: &Path) -> Result<ExrImage> {
+    let file = File::open(path).map_err(|e| Error::io(path, e))?;
+    read_exr_from(&mut BufReader::new(file)).map_err(|e| e.in_file(path))
+}
+
+fn read_exr_from(r: &mut dyn Read) -> Result<ExrImage> {
+    let mut magic = [0; 8];
+    r.read_exact(&mut magic)?;
+    if magic[..4] != [0x76, 0x2f, 0x31, 0x01] || magic[4] != 2 || magic[5] & 0x16 != 0 {
+        return Err(Error::parse("not a single part scanline OpenEXR file"));
+    }
+
+    let read_string = |r: &mut dyn Read| -> Result<String> {
+        let mut bytes = Vec::new();
+        let mut b = [0; 1];
+        loop {
+            r.read_exact(&mut b)?;
+            if b[0] == 0 {
+                return String::from_utf8(bytes).map_err(|_| Error::parse("attribute name is not UTF-8"));
+            }
+            bytes.push(b[0]);
+        }
+    };
+    let (mut channel_types, mut data_window, mut display_window) = (Vec::new(), None, None);
+    loop {
+        let name = read_string(r)?;
+        if name.is_empty() {
+            break;
+        }
+        let _kind = read_string(r)?;
+        let size = read_i32(r)?;
+        let mut value = vec![0; size.max(0) as usize];
+        r.read_exact(&mut value)?;
+        match name.as_str() {
+            "channels" => {
+                let mut list = &value[..];
+                while list.first().is_some_and(|b| *b != 0) {
+                    let end = list.iter().position(|b| *b == 0).ok_or_else(|| Error::parse("malformed channel list"))?;
+                    let channel = String::from_utf8(list[..end].to_vec()).map_err(|_| Error::parse("channel name is not UTF-8"))?;
+                    if list.len() < end + 17 {
+                        return Err(Error::parse("malformed channel list"));
+                    }
+                    let pixel_type = i32::from_le_bytes([list[end + 1], list[end + 2], list[end + 3], list[end + 4]]);
+                    channel_types.push((channel, pixel_type));
+                    list = &list[end + 17..];
+                }
+            },
+            "compression" if value != [0] => return Err(Error::parse("compressed OpenEXR files are not supported")),
+            "dataWindow" => data_window = Some(read_box2i(&value)?),
+            "displayWindow" => display_window = Some(read_box2i(&value)?),
+            _ => {}
+        }
+    }
+    let (dx0, dy0, dx1, dy1) = data_window.ok_or_else(|| Error::parse("missing data window"))?;
+    let (wx0, wy0, wx1, wy1) = display_window.ok_or_else(|| Error::parse("missing display window"))?;
+    if wx0 != 0 || wy0 != 0 || dx0 < 0 || dy0 < 0 || dx1 > wx1 || dy1 > wy1 || dx0 > dx1 || dy0 > dy1 {
+        return Err(Error::parse("data window must lie inside a display window starting at 0, 0"));
+    }
+    if channel_types.iter().any(|(_, t)| *t != 1 && *t != 2) {
+        return Err(Error::parse("only half and float channels are supported"));
+    }
+    let (width, height) = (wx1 as u32 + 1, wy1 as u32 + 1);
+    let size = framebuffer::pixel_count(width, height).ok_or_else(|| Error::parse(format!("a {}x{} image is too large", width, height)))?;
+    let window = Tile { x0: dx0 as u32, y0: dy0 as u32, x1: (dx1 + 1) as u32, y1: (dy1 + 1) as u32 };
+
+    let mut channels: Vec<Channel> = channel_types.iter().map(|(name, _)| Channel {
+        name: name.clone(),
+        values: vec![0.0; size]
+    }).collect();
+    //Skip the offset table; chunks follow in increasing y for this line order
+    let mut table = vec![0; 8 * window.height() as usize];
+    r.read_exact(&mut table)?;
+    for _ in 0..window.height() {
+        let y = read_i32(r)?;
+        let _size = read_i32(r)?;
+        if y < dy0 || y > dy1 {
+            return Err(Error::parse("scanline outside the data window"));
+        }
+        for (channel, (_, pixel_type)) in channels.iter_mut().zip(channel_types.iter()) {
+            let start = (y as u32 * width) as usize;
+            for value in channel.values[start + window.x0 as usize..start + window.x1 as usize].iter_mut() {
+                *value = if *pixel_type == 1 {
+                    let mut b = [0; 2];
+                    r.read_exact(&mut b)?;
+                    from_half(u16::from_le_bytes(b))
+                } else {
+                    let mut b = [0; 4];
+                    r.read_exact(&mut b)?;
+                    f32::from_le_bytes(b)
+                };
+            }
+        }
+    }
+    Ok(ExrImage {
+        width,
+        height,
+        window,
+        channels,
+        float: channel_types.iter().any(|(_, t)| *t == 2)
+    })
+}
+
+/// Reads an RGBA PNG as `write_png` writes it, returning the width, height, bit depth and data
+pub fn read_png(path: &Path) -> Result<(u32, u32, u32, Vec<u8>)> {
+    let decoder = png::Decoder::new(File::open(path).map_err(|e| Error::io(path, e))?);
+    let (info, mut reader) = decoder.read_info().map_err(|e| Error::from(e).in_file(path))?;
+    if info.color_type != png::ColorType::RGBA {
+        return Err(Error::parse("only RGBA PNG files are supported").in_file(path));
+    }
+    let mut data = vec![0; info.buffer_size()];
+    reader.next_frame(&mut data).map_err(|e| Error::from(e).in_file(path))?;
+    let bit_depth = if info.bit_depth == png::BitDepth::Sixteen { 16 } else { 8 };
+    Ok((info.width, info.height, bit_depth, data))
+}
+
+/// Makes the pixels of RGBA data outside `window` fully transparent
+pub fn clear_outside(data: &mut [u8], width: u32, bit_depth: u32, window: &Tile) {
+    let bytes = if bit_depth == 16 { 8 } else { 4 };
+    for (index, pixel) in data.chunks_mut(bytes).enumerate() {
+        let (x, row) = (index as u32 % width, index as u32 / width);
+        if !window.contains(x, row) {
+            for b in pixel.iter_mut() {
+                *b = 0;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn temp(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("pathtracer-output-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn only_knows_four_extensions() {
+        assert_eq!(ImageFormat::from_path(Path::new("a.png")).unwrap(), ImageFormat::Png);
+        assert_eq!(ImageFormat::from_path(Path::new("a.EXR")).unwrap(), ImageFormat::Exr);
+        assert_eq!(ImageFormat::from_path(Path::new("dir.v2/a.pfm")).unwrap(), ImageFormat::Pfm);
+        assert_eq!(ImageFormat::from_path(Path::new("a.hdr")).unwrap(), ImageFormat::Hdr);
+        for name in &["a.jpg", "a.tiff", "a", "exr"] {
+            assert!(matches!(ImageFormat::from_path(Path::new(name)), Err(Error::Usage(_))), "{}", name);
+        }
+    }
+
+    #[test]
+    fn rounds_to_half() {
+        assert_eq!(to_half(65504.0), 0x7bff);
+        assert_eq!(to_half(-65504.0), 0xfbff);
+        //65520 is halfway to the next power of two and rounds to even, which is infinity
+        assert_eq!(to_half(65519.0), 0x7bff);
+        assert_eq!(to_half(65520.0), 0x7c00);
+        assert_eq!(to_half(1e9), 0x7c00);
+        //Ties go to the even mantissa
+        assert_eq!(to_half(1.0 + 2f32.powi(-11)), 0x3c00);
+        assert_eq!(to_half(1.0 + 3.0 * 2f32.powi(-11)), 0x3c02);
+
+        //Subnormals, down to 2^-24 and rounding below it
+        assert_eq!(to_half(2f32.powi(-14)), 0x0400);
+        assert_eq!(to_half(2f32.powi(-15)), 0x0200);
+        assert_eq!(to_half(2f32.powi(-24)), 0x0001);
+        assert_eq!(to_half(-2f32.powi(-24)), 0x8001);
+        assert_eq!(to_half(1.5 * 2f32.powi(-24)), 0x0002);
+        assert_eq!(to_half(2f32.powi(-25)), 0x0000);
+        assert_eq!(to_half(1.01 * 2f32.powi(-25)), 0x0001);
+        assert_eq!(to_half(2f32.powi(-30)), 0x0000);
+        assert_eq!(to_half(1023.5 * 2f32.powi(-24)), 0x0400);
+        assert_eq!(from_half(0x0001), 2f32.powi(-24));
+
+        assert_eq!(to_half(f32::INFINITY), 0x7c00);
+        assert_eq!(to_half(f32::NEG_INFINITY), 0xfc00);
+        assert_eq!(from_half(0xfc00), f32::NEG_INFINITY);
+        assert!(from_half(to_half(f32::NAN)).is_nan());
+
+        //Every finite half survives the trip through f32
+        for half in (0..=0xffffu16).filter(|h| h & 0x7c00 != 0x7c00) {
+            assert_eq!(to_half(from_half(half)), half, "{:#06x}", half);
+        }
+    }
+
+    #[test]
+    fn reads_back_exr_pixels() {
+        let (width, height) = (3, 2);
+        let pixels = vec![
+            Vec3::new(0.0, 0.5, 1.0), Vec3::new(2.0, 65504.0, 0.25), Vec3::new(-1.0, 3.0, 1e-3),
+            Vec3::new(0.125, 8.0, 100.0), Vec3::new(7.0, 6.0, 5.0), Vec3::new(1.5, 2.5, 3.5)
+        ];
+        let window = Tile { x0: 1, y0: 0, x1: 3, y1: 2 };
+        let mut channels = rgb_channels("", &pixels);
+        channels.push(Channel { name: "samples.Y".to_string(), values: (0..6).map(|i| i as f32).collect() });
+        for &precision in &[ExrPrecision::Half, ExrPrecision::Float] {
+            let path = temp("image.exr");
+            write_exr(&path, width, height, &window, &channels, precision).unwrap();
+            let image = read_exr(&path);
+            fs::remove_file(&path).ok();
+            let image = image.unwrap();
+            assert_eq!((image.width, image.height, image.float), (width, height, precision == ExrPrecision::Float));
+            assert_eq!((image.window.x0, image.window.y0, image.window.x1, image.window.y1), (1, 0, 3, 2));
+            let mut names: Vec<&str> = image.channels.iter().map(|c| c.name.as_str()).collect();
+            names.sort_unstable();
+            assert_eq!(names, ["B", "G", "R", "samples.Y"]);
+            for written in channels.iter() {
+                let read = image.channels.iter().find(|c| c.name == written.name).unwrap();
+                for (i, (&r, &w)) in read.values.iter().zip(written.values.iter()).enumerate() {
+                    let expected = if i % 3 == 0 { 0.0 } else if precision == ExrPrecision::Half { from_half(to_half(w)) } else { w };
+                    assert_eq!(r, expected, "{} pixel {}", written.name, i);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn writes_pfm_pixels() {
+        let pixels = vec![Vec3::new(1.0, 2.0, 3.0), Vec3::new(-4.5, 1e-8, 1e8), Vec3::new(0.0, 0.5, 0.25), Vec3::new(9.0, 8.0, 7.0)];
+        let path = temp("image.pfm");
+        write_pfm(&path, 2, 2, &pixels).unwrap();
+        let bytes = fs::read(&path);
+        fs::remove_file(&path).ok();
+        let bytes = bytes.unwrap();
+        let header = b"PF\n2 2\n-1.0\n";
+        assert_eq!(&bytes[..header.len()], &header[..]);
+        let values: Vec<f32> = bytes[header.len()..].chunks(4).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect();
+        //Bottom row first
+        let expected: Vec<f32> = pixels[2..].iter().chain(pixels[..2].iter()).flat_map(|p| vec![p.x(), p.y(), p.z()]).collect();
+        assert_eq!(values, expected);
+    }
+}
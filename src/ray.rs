@@ -3,14 +3,16 @@ use vec3::Vec3;
 #[derive(Debug)]
 pub struct Ray {
     origin: Vec3,
-    direction: Vec3
+    direction: Vec3,
+    time: f32
 }
 
 impl Ray {
-    pub fn new(origin: Vec3, direction: Vec3) -> Ray {
+    pub fn new(origin: Vec3, direction: Vec3, time: f32) -> Ray {
         Ray {
             origin,
-            direction
+            direction,
+            time
         }
     }
 
@@ -22,6 +24,10 @@ impl Ray {
         self.direction
     }
 
+    pub fn time(&self) -> f32 {
+        self.time
+    }
+
     pub fn point_at_parameter(&self, t: f32) -> Vec3 {
         self.origin + t * self.direction
     }
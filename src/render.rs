@@ -0,0 +1,620 @@
+use vec3::Vec3;
+use ray::Ray;
+use hitable::{BvhNode, Hitable};
+use light::{Light, LightDistribution};
+use camera::{Camera, CameraSettings};
+use tile::{generate_tiles, Tile, TileOrder};
+use sampler::{self, Sampler, SamplerKind};
+use framebuffer::{self, Framebuffer, PixelStats, TileBuffer};
+use filter::{Filter, FilterKind};
+use aov::SampleAovs;
+use distributed::RemoteWorker;
+use error::{Error, Result};
+
+use std::f32::consts::PI;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use rng;
+use stats;
+
+extern crate rayon;
+
+pub struct RenderSettings {
+    pub width: u32,
+    pub height: u32,
+    pub samples_per_pixel: usize,
+    /// Adaptive sampling: pixels whose estimated error is above the threshold keep sampling
+    /// up to the maximum
+    pub max_samples_per_pixel: usize,
+    pub noise_threshold: Option<f32>,
+    /// Wall clock budget, after which the render stops with the samples taken so far
+    pub time_limit: Option<Duration>,
+    /// How often the partial image is handed out while rendering
+    pub write_interval: Option<Duration>,
+    /// Cancelled from another thread to stop the render early with the samples taken so far
+    pub cancel: CancelToken,
+    /// Updated as tiles finish, for reporting on another thread
+    pub progress: Arc<Progress>,
+    /// Called as tiles finish and once more when the render is over
+    pub observer: Option<ProgressFn>,
+    /// Pixels to render, the whole image unless cropping
+    pub region: Tile,
+    pub tile_size: u32,
+    pub tile_order: TileOrder,
+    pub seed: u64,
+    pub sampler: Arc<dyn Sampler + Send + Sync>,
+    /// Reconstruction filter each sample is splatted through
+    pub filter: Filter,
+    pub clamp: Clamp
+}
+
+impl RenderSettings {
+    /// A fixed number of samples in every pixel of a width x height image, with the command
+    /// line's defaults: Sobol samples from seed 0, a box filter and 32 pixel tiles in a spiral
+    pub fn new(width: u32, height: u32, samples_per_pixel: usize) -> RenderSettings {
+        RenderSettings {
+            width,
+            height,
+            samples_per_pixel,
+            max_samples_per_pixel: samples_per_pixel,
+            noise_threshold: None,
+            time_limit: None,
+            write_interval: None,
+            cancel: CancelToken::new(),
+            progress: Arc::new(Progress::default()),
+            observer: None,
+            region: Tile { x0: 0, y0: 0, x1: width, y1: height },
+            tile_size: 32,
+            tile_order: TileOrder::Spiral,
+            seed: 0,
+            sampler: Arc::from(SamplerKind::Sobol.build(0, samples_per_pixel)),
+            filter: Filter::new(FilterKind::Box, None),
+            clamp: Clamp::default()
+        }
+    }
+}
+
+/// How far a render has got: the fraction of the sample budget spent, or of the time limit when
+/// that runs out first. With adaptive sampling the budget assumes every pixel keeps sampling to
+/// the maximum, so the fraction runs behind and jumps to 1 at the end
+#[derive(Debug, Default)]
+pub struct Progress {
+    /// In millionths, to fit an atomic
+    done: AtomicU32
+}
+
+impl Progress {
+    pub fn fraction(&self) -> f32 {
+        self.done.load(Ordering::Relaxed) as f32 / 1e6
+    }
+
+    fn set(&self, fraction: f32) {
+        self.done.store((fraction.clamp(0.0, 1.0) * 1e6) as u32, Ordering::Relaxed);
+    }
+}
+
+/// How a render is going, handed to the progress observer
+#[derive(Clone, Copy, Debug)]
+pub struct ProgressReport {
+    /// As `Progress::fraction`
+    pub fraction: f32,
+    pub elapsed: Duration,
+    /// Time left at the rate so far, once anything has been rendered
+    pub eta: Option<Duration>,
+    pub samples_per_second: f64,
+    pub rays_per_second: f64,
+    /// Samples per pixel the current pass takes pixels up to, and its tiles done out of all
+    pub pass_total: u32,
+    pub tiles_done: usize,
+    pub tiles: usize,
+    /// Set on the last report, once the render has finished, been cancelled or run out of time
+    pub done: bool
+}
+
+/// Called from whichever thread finished a tile, so it should return quickly
+pub type ProgressFn = Arc<dyn Fn(&ProgressReport) + Send + Sync>;
+
+/// Stops a render from another thread, keeping the samples taken so far. Clones share the flag;
+/// the renderer checks it before every tile and pixel
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Takes the width, height and tone mapped 8-bit RGBA data of an image
+pub type PreviewFn = Box<dyn Fn(u32, u32, Vec<u8>) + Send + Sync>;
+
+/// Ways for whoever started a render to follow it and stop it from another thread
+#[derive(Default)]
+pub struct RenderControl {
+    pub cancel: CancelToken,
+    pub progress: Arc<Progress>,
+    pub observer: Option<ProgressFn>,
+    /// Handed the tone mapped 8-bit RGBA image with every snapshot and at the end
+    pub preview: Option<PreviewFn>
+}
+
+/// Per sample limits on radiance, trading a little energy for fewer fireflies. Direct light is
+/// what reaches the camera straight from an emitter or off the first surface, indirect the rest.
+/// Each limit caps the largest colour component, scaling the others to keep the hue
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Clamp {
+    pub direct: Option<f32>,
+    pub indirect: Option<f32>
+}
+
+fn clamp_radiance(radiance: Vec3, limit: Option<f32>) -> Vec3 {
+    let peak = radiance.x().max(radiance.y()).max(radiance.z());
+    match limit {
+        Some(limit) if peak > limit => (limit / peak) * radiance,
+        _ => radiance
+    }
+}
+
+/// Everything a worker needs to trace paths
+pub struct SceneContext<'a> {
+    pub world: &'a (dyn Hitable + Sync),
+    pub lights: &'a LightDistribution,
+    pub camera: &'a (dyn Camera + Sync)
+}
+
+/// A scene ready to trace: its objects in a BVH, its lights sampled by power and a camera for
+/// the image size
+pub struct PreparedScene {
+    pub world: Box<dyn Hitable + Sync>,
+    pub lights: LightDistribution,
+    pub camera: Box<dyn Camera + Sync>
+}
+
+impl PreparedScene {
+    /// Emissive objects light the scene alongside `lights`. Building the BVH and autofocusing
+    /// draw random numbers, so set the build stream first for a repeatable result
+    pub fn new(objects: Vec<Box<dyn Hitable + Sync>>, mut lights: Vec<Light>, camera: &CameraSettings, width: u32, height: u32) -> Result<PreparedScene> {
+        if objects.is_empty() {
+            return Err(Error::scene("there is nothing to render"));
+        }
+        if width == 0 || height == 0 {
+            return Err(Error::Usage(format!("a {}x{} image has no pixels", width, height)));
+        }
+        if framebuffer::pixel_count(width, height).is_none() {
+            return Err(Error::Usage(format!("a {}x{} image is larger than the {} pixel limit", width, height, framebuffer::MAX_PIXELS)));
+        }
+        let mut all: Vec<_> = objects.iter().filter_map(|h| h.as_light()).collect();
+        all.append(&mut lights);
+        let world: Box<dyn Hitable + Sync> = Box::new(BvhNode::new(objects));
+        let camera = camera.build(width, height, &*world)?;
+        Ok(PreparedScene {
+            world,
+            lights: LightDistribution::new(all),
+            camera
+        })
+    }
+
+    pub fn context(&self) -> SceneContext<'_> {
+        SceneContext {
+            world: &*self.world,
+            lights: &self.lights,
+            camera: &*self.camera
+        }
+    }
+}
+
+/// Next event estimation: samples one light, picked proportionally to its power. Returns the
+/// reflected light and the light group of the light sampled
+fn direct_lighting(point: Vec3, normal: Vec3, albedo: Vec3, time: f32, world: &(dyn Hitable + Sync), lights: &LightDistribution) -> (Vec3, usize) {
+    let (light, pmf) = match lights.sample(rng::random()) {
+        Some(x) => x,
+        None => return (Vec3::zero_vector(), 0)
+    };
+    let none = (Vec3::zero_vector(), light.group());
+    let sample = match light.sample(point, rng::random(), rng::random()) {
+        Some(x) => x,
+        None => return none
+    };
+    let to_light = sample.point - point;
+    let cos_surface = Vec3::unit_vector(to_light).dot(normal);
+    if cos_surface <= 0.0 {
+        return none;
+    }
+
+    let shadow_ray = Ray::new(point, to_light, time);
+    stats::count(|s| s.shadow_rays += 1);
+    if world.hit(0.001, 0.999, &shadow_ray).is_some() {
+        return none;
+    }
+
+    ((cos_surface / (pmf * PI)) * albedo * sample.weight, light.group())
+}
+
+/// `aovs`, when given, is filled in along the path. `clamp` applies to the camera ray's radiance
+pub fn color(r : &Ray, world: &(dyn Hitable + Sync), lights: &LightDistribution, depth: u32, count_emitted: bool, clamp: &Clamp, mut aovs: Option<&mut SampleAovs>) -> Vec3 {
+    let dimension = sampler::bounce_dimension(depth);
+    rng::start_dimensions(dimension + sampler::MEDIUM_OFFSET, 1);
+    stats::count(|s| if depth == 0 { s.camera_rays += 1 } else { s.bounce_rays += 1 });
+    if let Some(hit_rec) = world.hit(0.001, 50.0, r) {
+        let material = hit_rec.material;
+        let normal = hit_rec.normal;
+        let point = hit_rec.p;
+        let t = hit_rec.t;
+        rng::start_dimensions(dimension + sampler::SCATTER_OFFSET, sampler::SCATTER_DIMENSIONS);
+        let scatter_rec = material.scatter(r, t, point, normal);
+        let emitted = if count_emitted || !hit_rec.explicit_light {
+            material.emitted(0.0, 0.0, &point)
+        } else {
+            Vec3::zero_vector()
+        };
+
+        //Diffuse surfaces gather light explicitly, so emitters found by the next bounce must not be counted twice
+        rng::start_dimensions(dimension + sampler::LIGHT_OFFSET, sampler::LIGHT_DIMENSIONS);
+        let direct = match material.diffuse_albedo(&point) {
+            Some(albedo) if !lights.is_empty() => Some(direct_lighting(point, normal, albedo, r.time(), world, lights)),
+            _ => None
+        };
+        let continues = depth < 50 && scatter_rec.is_some();
+        match scatter_rec {
+            Some(_) if !continues => stats::count(|s| s.depth_limited += 1),
+            None => stats::count(|s| s.absorbed += 1),
+            _ => ()
+        }
+        if let Some(aovs) = aovs.as_deref_mut() {
+            let attenuation = scatter_rec.as_ref().filter(|_| continues).map(|s| s.attenuation);
+            aovs.record(depth, r, &hit_rec, attenuation, emitted, direct);
+        }
+        let sampled_lights = direct.is_some();
+        let direct = direct.map_or_else(Vec3::zero_vector, |(radiance, _)| radiance);
+        match scatter_rec {
+            Some(scatter_rec) if continues => {
+                let indirect = scatter_rec.attenuation * color(&scatter_rec.scattered, world, lights, depth + 1, !sampled_lights, clamp, aovs);
+                if depth == 0 {
+                    return clamp_radiance(emitted + direct, clamp.direct) + clamp_radiance(indirect, clamp.indirect);
+                }
+                return emitted + direct + indirect;
+            },
+            _ if depth == 0 => {
+                return clamp_radiance(emitted + direct, clamp.direct);
+            },
+            _ => {
+                return emitted + direct;
+            }
+        }
+    }
+    stats::count(|s| s.escaped += 1);
+    Vec3::new(0.0, 0.0, 0.0)
+    //let unit_direction = Vec3::unit_vector(r.direction());
+    //let t = 0.5 * (unit_direction.y() + 1.0);
+    //(1.0 - t) * Vec3::new(1.0, 1.0, 1.0) + t * Vec3::new(0.5, 0.7, 1.0)
+}
+
+/// Traces one camera sample through pixel (x, row), row 0 being the top of the image
+/// Returns the radiance and where in the pixel the sample lies, from its top left corner
+fn trace_sample(scene: &SceneContext, settings: &RenderSettings, x: u32, row: u32, sample: u32, aovs: Option<&mut SampleAovs>) -> (Vec3, f32, f32) {
+    //Image rows run top to bottom, camera space t runs bottom to top
+    let y = settings.height - 1 - row;
+    rng::set(rng::sample_rng(settings.seed, x, row, sample));
+    rng::start_sample(x, row, sample);
+    rng::start_dimensions(sampler::PIXEL_DIMENSION, sampler::PIXEL_DIMENSIONS);
+    let (jitter_x, jitter_y) = (rng::random(), rng::random());
+    let u = (x as f32 + jitter_x) / settings.width as f32;
+    let v = (y as f32 + jitter_y) / settings.height as f32;
+    rng::start_dimensions(sampler::CAMERA_DIMENSION, sampler::CAMERA_DIMENSIONS);
+    let radiance = match scene.camera.get_weighted_ray(u, v) {
+        Some((r, weight)) => weight * color(&r, scene.world, scene.lights, 0, true, &settings.clamp, aovs),
+        None => Vec3::zero_vector()
+    };
+    (radiance, jitter_x, 1.0 - jitter_y)
+}
+
+/// Number of samples a pixel should have at the end of a pass. Passes double the sample count
+/// up to `samples_per_pixel`; after that only pixels above the noise threshold continue
+fn pixel_target(pixel: &PixelStats, settings: &RenderSettings, pass_total: u32) -> u32 {
+    let base = settings.samples_per_pixel.max(1) as u32;
+    if pixel.samples < base.min(pass_total) {
+        return base.min(pass_total);
+    }
+    match settings.noise_threshold {
+        Some(threshold) if pass_total > pixel.samples && pixel.error() > threshold => pass_total,
+        _ => pixel.samples
+    }
+}
+
+/// True once the render has been cancelled or has run out of time
+fn should_stop(settings: &RenderSettings, deadline: Option<Instant>) -> bool {
+    settings.cancel.is_cancelled() || deadline.is_some_and(|d| Instant::now() >= d)
+}
+
+/// Adds one pass worth of samples to a tile's pixels, splats them through the reconstruction
+/// filter and adds to the tile's AOV sums if it has them. Stops early, leaving the remaining
+/// pixels as they were, once the render is cancelled or the deadline passes
+pub fn render_tile(scene: &SceneContext, settings: &RenderSettings, tile: &Tile, buffer: &mut TileBuffer, pass_total: u32, deadline: Option<Instant>) {
+    rng::set_sampler(Some(settings.sampler.clone()));
+    stats::take();
+    let mut sample_aovs = buffer.aovs.as_ref().map(|a| SampleAovs::new(a.groups));
+    let reach = settings.filter.pixel_reach();
+    let (mut weights_x, mut weights_y) = (Vec::new(), Vec::new());
+    let mut index = 0;
+    'rows: for row in tile.y0..tile.y1 {
+        for x in tile.x0..tile.x1 {
+            if should_stop(settings, deadline) {
+                break 'rows;
+            }
+            let pixel = &mut buffer.pixels[index];
+            for sample in pixel.samples..pixel_target(pixel, settings, pass_total) {
+                if let Some(s) = sample_aovs.as_mut() {
+                    s.reset();
+                }
+                let (radiance, offset_x, offset_y) = trace_sample(scene, settings, x, row, sample, sample_aovs.as_mut());
+                pixel.add(radiance);
+                buffer.samples += 1;
+                if let Some(batches) = buffer.batches.as_mut() {
+                    batches.add(index, sample, radiance);
+                }
+                if let (Some(aovs), Some(s)) = (buffer.aovs.as_mut(), sample_aovs.as_ref()) {
+                    aovs.add(index, s, sample == 0);
+                }
+                settings.filter.weights(offset_x, &mut weights_x);
+                settings.filter.weights(offset_y, &mut weights_y);
+                for (j, wy) in weights_y.iter().enumerate() {
+                    for (i, wx) in weights_x.iter().enumerate() {
+                        let weight = wx * wy;
+                        if weight != 0.0 {
+                            buffer.splats.add(x as i32 + i as i32 - reach, row as i32 + j as i32 - reach, radiance, weight);
+                        }
+                    }
+                }
+            }
+            index += 1;
+        }
+    }
+    buffer.stats.add(&stats::take());
+    rng::set_sampler(None);
+}
+
+/// Renders the image progressively: each pass covers the whole image in tiles spread over the
+/// rayon pool, doubling the samples per pixel until the sample budget is spent, the time limit
+/// runs out or the render is cancelled. Threads pull tiles from a shared counter so they are
+/// started in `tile_order`. Samples, and the statistics gathered taking them, are added to
+/// `framebuffer`, so a render can carry on from a checkpoint. The observer in `settings`, if
+/// any, hears about every finished tile
+pub fn render(scene: &SceneContext, settings: &RenderSettings, framebuffer: Framebuffer) -> Framebuffer {
+    render_distributed(scene, settings, framebuffer, &mut [], &|_| {})
+}
+
+/// As `render`, with remote workers each taking batches of tiles alongside the local threads
+/// from their own thread, until their connection fails. With a write interval, `snapshot` is
+/// handed a copy of the framebuffer that often
+pub fn render_distributed(scene: &SceneContext, settings: &RenderSettings, framebuffer: Framebuffer, workers: &mut [RemoteWorker], snapshot: &(dyn Fn(&Framebuffer) + Sync)) -> Framebuffer {
+    let start = Instant::now();
+    let deadline = settings.time_limit.map(|limit| start + limit);
+    let tiles = generate_tiles(&settings.region, settings.tile_size, settings.tile_order);
+    let framebuffer = Mutex::new(framebuffer);
+    let last_snapshot = Mutex::new(start);
+    let reach = settings.filter.pixel_reach();
+
+    let base = settings.samples_per_pixel.max(1);
+    let max_total = match settings.noise_threshold {
+        Some(_) => settings.max_samples_per_pixel.max(base),
+        None => base
+    } as u32;
+    let (samples, rays) = (AtomicU64::new(0), AtomicU64::new(0));
+    let report = |fraction: f32, pass_total: u32, tiles_done: usize, done: bool| {
+        let fraction = fraction.clamp(0.0, 1.0);
+        let elapsed = start.elapsed();
+        let seconds = elapsed.as_secs_f64().max(1e-6);
+        let progress = ProgressReport {
+            fraction,
+            elapsed,
+            eta: if fraction > 0.0 { Some(elapsed.mul_f64((1.0 - fraction as f64) / fraction as f64)) } else { None },
+            samples_per_second: samples.load(Ordering::Relaxed) as f64 / seconds,
+            rays_per_second: rays.load(Ordering::Relaxed) as f64 / seconds,
+            pass_total,
+            tiles_done,
+            tiles: tiles.len(),
+            done
+        };
+        if let Some(observer) = settings.observer.as_ref() {
+            observer(&progress);
+        }
+    };
+    let mut pass_total = 1;
+    let mut previous_total = 0;
+    let mut tiles_done;
+    loop {
+        let next_tile = AtomicUsize::new(0);
+        let finished = AtomicUsize::new(0);
+        //Claims up to `count` of the tiles left in this pass
+        let claim = |count: usize| -> &[Tile] {
+            if should_stop(settings, deadline) {
+                return &[];
+            }
+            let first = next_tile.fetch_add(count, Ordering::Relaxed).min(tiles.len());
+            &tiles[first..(first + count).min(tiles.len())]
+        };
+        let finish = |claimed: &[Tile], buffers: &[TileBuffer]| {
+            for (tile, buffer) in claimed.iter().zip(buffers.iter()) {
+                framebuffer.lock().unwrap().finish_tile(tile, buffer);
+                samples.fetch_add(buffer.samples, Ordering::Relaxed);
+                rays.fetch_add(buffer.stats.rays(), Ordering::Relaxed);
+            }
+            let done = finished.fetch_add(claimed.len(), Ordering::Relaxed) + claimed.len();
+            let pass_fraction = done as f32 / tiles.len() as f32;
+            let mut fraction = (previous_total as f32 + pass_fraction * (pass_total - previous_total) as f32) / max_total as f32;
+            if let Some(limit) = settings.time_limit {
+                fraction = fraction.max(start.elapsed().as_secs_f32() / limit.as_secs_f32());
+            }
+            settings.progress.set(fraction);
+            report(fraction, pass_total, done, false);
+
+            //Whoever finds the interval expired writes the snapshot, the others move on
+            if let (Some(interval), Ok(mut last)) = (settings.write_interval, last_snapshot.try_lock()) {
+                if last.elapsed() >= interval {
+                    let copy = framebuffer.lock().unwrap().clone();
+                    snapshot(&copy);
+                    *last = Instant::now();
+                }
+            }
+        };
+        let start_tiles = |claimed: &[Tile]| -> Vec<TileBuffer> {
+            claimed.iter().map(|tile| framebuffer.lock().unwrap().start_tile(tile, reach)).collect()
+        };
+        let render_locally = |claimed: &[Tile]| {
+            let mut buffers = start_tiles(claimed);
+            for (tile, buffer) in claimed.iter().zip(buffers.iter_mut()) {
+                render_tile(scene, settings, tile, buffer, pass_total, deadline);
+            }
+            finish(claimed, &buffers);
+        };
+        let (claim, start_tiles, finish, render_locally) = (&claim, &start_tiles, &finish, &render_locally);
+        thread::scope(|threads| {
+            for worker in workers.iter_mut().filter(|w| !w.failed) {
+                threads.spawn(move || loop {
+                    let claimed = claim(worker.threads);
+                    if claimed.is_empty() {
+                        break;
+                    }
+                    let mut buffers = start_tiles(claimed);
+                    match worker.render_tiles(claimed, &mut buffers, pass_total, deadline) {
+                        Ok(()) => finish(claimed, &buffers),
+                        Err(e) => {
+                            eprintln!("Worker {} failed, rendering its tiles here: {}", worker.address, e);
+                            worker.failed = true;
+                            render_locally(claimed);
+                            break;
+                        }
+                    }
+                });
+            }
+            rayon::scope(|s| {
+                for _ in 0..rayon::current_num_threads() {
+                    s.spawn(|_| loop {
+                        let claimed = claim(1);
+                        if claimed.is_empty() {
+                            break;
+                        }
+                        render_locally(claimed);
+                    });
+                }
+            });
+        });
+        tiles_done = finished.into_inner();
+
+        if should_stop(settings, deadline) {
+            break;
+        }
+        if pass_total >= max_total {
+            settings.progress.set(1.0);
+            break;
+        }
+        previous_total = pass_total;
+        pass_total = (2 * pass_total).min(max_total);
+    }
+    report(settings.progress.fraction(), pass_total, tiles_done, true);
+
+    let mut framebuffer = framebuffer.into_inner().unwrap();
+    framebuffer.stats.render_time += start.elapsed();
+    framebuffer
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scene::Scene;
+    use sphere::Sphere;
+    use material::{DiffuseLight, Metal};
+    use texture::ConstantTexture;
+    use std::path::PathBuf;
+
+    /// Renders the IES demo with adaptive sampling, a Gaussian filter reaching into neighbouring
+    /// tiles and small tiles, on a pool of `threads` threads. Returns every pixel's filtered
+    /// radiance as bits, and its sample count
+    fn render_with_threads(threads: usize) -> (Vec<[u32; 3]>, Vec<u32>) {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("scenes/ies_demo.scene");
+        let scene = Scene::load(&path).unwrap();
+        let (width, height) = (48, 27);
+        let prepared = PreparedScene::new(scene.objects, scene.lights, &scene.camera, width, height).unwrap();
+        let mut settings = RenderSettings::new(width, height, 8);
+        settings.max_samples_per_pixel = 32;
+        settings.noise_threshold = Some(0.05);
+        settings.tile_size = 8;
+        settings.seed = 5;
+        settings.sampler = Arc::from(SamplerKind::Sobol.build(5, 8));
+        settings.filter = Filter::new(FilterKind::Gaussian, None);
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+        let framebuffer = pool.install(|| render(&prepared.context(), &settings, Framebuffer::new(width, height)));
+        let radiance = framebuffer.radiance().iter().map(|c| [c.x().to_bits(), c.y().to_bits(), c.z().to_bits()]).collect();
+        (radiance, framebuffer.pixels.iter().map(|p| p.samples).collect())
+    }
+
+    #[test]
+    fn thread_count_does_not_change_the_image() {
+        let (one, one_samples) = render_with_threads(1);
+        let (seven, seven_samples) = render_with_threads(7);
+        assert_eq!(one_samples, seven_samples);
+        assert!(one == seven, "1 and 7 threads rendered different images");
+        assert!(one_samples.iter().any(|&s| s > 8), "adaptive sampling never ran");
+    }
+
+    #[test]
+    fn clamps_limit_only_their_own_light() {
+        let light = Box::new(Sphere::new(Vec3::new(0.0, 0.0, 3.0), 1.0, Box::new(DiffuseLight::new(Box::new(ConstantTexture::new(Vec3::new(100.0, 50.0, 10.0)))))));
+        let mirror = Box::new(Sphere::new(Vec3::new(0.0, 0.0, -3.0), 1.0, Box::new(Metal::new(Box::new(ConstantTexture::new(Vec3::new(0.5, 0.5, 0.5))), 0.0))));
+        let world = BvhNode::new(vec![light, mirror]);
+        let lights = LightDistribution::new(Vec::new());
+        let trace = |direction: Vec3, clamp: Clamp| {
+            let ray = Ray::new(Vec3::zero_vector(), direction, 0.0);
+            color(&ray, &world, &lights, 0, true, &clamp, None)
+        };
+        let close = |a: Vec3, b: Vec3| (a - b).length() < 1e-4;
+        let direct = Clamp { direct: Some(10.0), indirect: None };
+        let indirect = Clamp { direct: None, indirect: Some(10.0) };
+
+        //Looking straight at the light is direct
+        let towards_light = Vec3::new(0.0, 0.0, 1.0);
+        assert!(close(trace(towards_light, Clamp::default()), Vec3::new(100.0, 50.0, 10.0)));
+        assert!(close(trace(towards_light, direct), Vec3::new(10.0, 5.0, 1.0)), "{:?}", trace(towards_light, direct));
+        assert!(close(trace(towards_light, indirect), Vec3::new(100.0, 50.0, 10.0)), "{:?}", trace(towards_light, indirect));
+
+        //Its reflection in the mirror is indirect
+        let towards_mirror = Vec3::new(0.0, 0.0, -1.0);
+        assert!(close(trace(towards_mirror, Clamp::default()), Vec3::new(50.0, 25.0, 5.0)));
+        assert!(close(trace(towards_mirror, direct), Vec3::new(50.0, 25.0, 5.0)), "{:?}", trace(towards_mirror, direct));
+        assert!(close(trace(towards_mirror, indirect), Vec3::new(10.0, 5.0, 1.0)), "{:?}", trace(towards_mirror, indirect));
+    }
+
+    #[test]
+    fn counts_how_each_path_ends() {
+        let mirror = |z: f32| -> Box<dyn Hitable + Sync> {
+            Box::new(Sphere::new(Vec3::new(0.0, 0.0, z), 5.0, Box::new(Metal::new(Box::new(ConstantTexture::new(Vec3::new(0.9, 0.9, 0.9))), 0.0))))
+        };
+        let light = Box::new(Sphere::new(Vec3::new(3.0, 0.0, 0.0), 1.0, Box::new(DiffuseLight::new(Box::new(ConstantTexture::new(Vec3::new(1.0, 1.0, 1.0)))))));
+        let world = BvhNode::new(vec![light, mirror(-10.0), mirror(10.0)]);
+        let lights = LightDistribution::new(Vec::new());
+        let trace = |direction: Vec3| {
+            stats::take();
+            color(&Ray::new(Vec3::zero_vector(), direction, 0.0), &world, &lights, 0, true, &Clamp::default(), None);
+            let s = stats::take();
+            assert_eq!(s.camera_rays, 1);
+            assert_eq!(s.escaped + s.absorbed + s.depth_limited, 1, "{:?}", s);
+            s
+        };
+        assert_eq!(trace(Vec3::new(0.0, 1.0, 0.0)).escaped, 1);
+        assert_eq!(trace(Vec3::new(1.0, 0.0, 0.0)).absorbed, 1);
+        //Between two facing mirrors the path bounces until the depth limit
+        let trapped = trace(Vec3::new(0.0, 0.0, -1.0));
+        assert_eq!((trapped.depth_limited, trapped.bounce_rays), (1, 50));
+    }
+}
+
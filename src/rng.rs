@@ -0,0 +1,145 @@
+use std::cell::RefCell;
+use std::sync::Arc;
+use sampler::Sampler;
+
+/// PCG32 (XSH RR): small, fast, and identical on every platform and thread count
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Pcg32 {
+    state: u64,
+    inc: u64
+}
+
+/// Stream used for scene construction, such as BVH split axes
+pub const BUILD_STREAM: u64 = u64::MAX;
+
+/// The sample currently being traced on a thread. While the current block of dimensions
+/// lasts, `random` draws from the sampler; everything else comes from the PCG stream
+struct SampleState {
+    sampler: Option<Arc<dyn Sampler + Send + Sync>>,
+    x: u32,
+    y: u32,
+    index: u32,
+    dimension: u32,
+    end: u32
+}
+
+thread_local! {
+    static RNG: RefCell<Pcg32> = RefCell::new(Pcg32::new(0, BUILD_STREAM));
+    static SAMPLE: RefCell<SampleState> = RefCell::new(SampleState {
+        sampler: None,
+        x: 0,
+        y: 0,
+        index: 0,
+        dimension: 0,
+        end: 0
+    });
+}
+
+impl Pcg32 {
+    pub fn new(seed: u64, stream: u64) -> Pcg32 {
+        let mut rng = Pcg32 {
+            state: 0,
+            inc: (stream << 1) | 1
+        };
+        rng.next_u32();
+        rng.state = rng.state.wrapping_add(seed);
+        rng.next_u32();
+        rng
+    }
+
+    pub fn from_state(state: u64, inc: u64) -> Pcg32 {
+        Pcg32 {
+            state,
+            inc: inc | 1
+        }
+    }
+
+    pub fn state(&self) -> (u64, u64) {
+        (self.state, self.inc)
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let old = self.state;
+        self.state = old.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(self.inc);
+        let xorshifted = (((old >> 18) ^ old) >> 27) as u32;
+        let rot = (old >> 59) as u32;
+        xorshifted.rotate_right(rot)
+    }
+
+    /// Uniform in [0, 1)
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u32() >> 8) as f32 * (1.0 / 16_777_216.0)
+    }
+}
+
+pub fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// Independent generator for one sample of one pixel, so results don't depend on which
+/// thread renders it or in what order
+pub fn sample_rng(seed: u64, x: u32, y: u32, sample: u32) -> Pcg32 {
+    let pixel = ((y as u64) << 32) | x as u64;
+    Pcg32::new(splitmix64(seed), splitmix64(pixel ^ splitmix64(sample as u64)))
+}
+
+/// Replaces the calling thread's generator
+pub fn set(rng: Pcg32) {
+    RNG.with(|r| *r.borrow_mut() = rng);
+}
+
+pub fn get() -> Pcg32 {
+    RNG.with(|r| *r.borrow())
+}
+
+/// Installs the sampler used by `start_sample` on the calling thread, None to go back to
+/// plain random numbers
+pub fn set_sampler(sampler: Option<Arc<dyn Sampler + Send + Sync>>) {
+    SAMPLE.with(|s| {
+        let mut s = s.borrow_mut();
+        s.sampler = sampler;
+        s.end = 0;
+    });
+}
+
+/// Points the calling thread at one sample of one pixel. No dimensions are available until
+/// `start_dimensions` is called
+pub fn start_sample(x: u32, y: u32, index: u32) {
+    SAMPLE.with(|s| {
+        let mut s = s.borrow_mut();
+        s.x = x;
+        s.y = y;
+        s.index = index;
+        s.end = 0;
+    });
+}
+
+/// The next `count` calls to `random` return dimensions `first..first + count` of the sample
+pub fn start_dimensions(first: u32, count: u32) {
+    SAMPLE.with(|s| {
+        let mut s = s.borrow_mut();
+        s.dimension = first;
+        s.end = first + count;
+    });
+}
+
+/// Next uniform number in [0, 1), from the current sample dimension if there is one left and
+/// otherwise from the calling thread's generator
+pub fn random() -> f32 {
+    let sampled = SAMPLE.with(|s| {
+        let mut s = s.borrow_mut();
+        if s.dimension >= s.end {
+            return None;
+        }
+        let dimension = s.dimension;
+        s.dimension += 1;
+        s.sampler.as_ref().map(|sampler| sampler.get(s.x, s.y, s.index, dimension))
+    });
+    match sampled {
+        Some(value) => value,
+        None => RNG.with(|r| r.borrow_mut().next_f32())
+    }
+}
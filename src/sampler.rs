@@ -0,0 +1,378 @@
+use rng::splitmix64;
+
+/// Sample dimensions are laid out the same way for every path, so each decision draws from
+/// its own dimension of the sequence: the film position, then the camera (lens and shutter),
+/// then a fixed block per bounce. Draws past the end of a block come from the pixel's PCG stream
+pub const PIXEL_DIMENSION: u32 = 0;
+pub const PIXEL_DIMENSIONS: u32 = 2;
+pub const CAMERA_DIMENSION: u32 = PIXEL_DIMENSION + PIXEL_DIMENSIONS;
+pub const CAMERA_DIMENSIONS: u32 = 3;
+/// Offsets inside the camera block: a point on the lens, then the time within the shutter interval
+pub const LENS_OFFSET: u32 = 0;
+pub const LENS_DIMENSIONS: u32 = 2;
+pub const TIME_OFFSET: u32 = 2;
+pub const BOUNCE_DIMENSION: u32 = CAMERA_DIMENSION + CAMERA_DIMENSIONS;
+pub const BOUNCE_DIMENSIONS: u32 = 8;
+/// Offsets inside a bounce's block: the distance into a participating medium, the
+/// scattered direction, then light selection and the position on the light
+pub const MEDIUM_OFFSET: u32 = 0;
+pub const SCATTER_OFFSET: u32 = 1;
+pub const SCATTER_DIMENSIONS: u32 = 3;
+pub const LIGHT_OFFSET: u32 = 4;
+pub const LIGHT_DIMENSIONS: u32 = 3;
+
+pub fn bounce_dimension(depth: u32) -> u32 {
+    BOUNCE_DIMENSION + depth * BOUNCE_DIMENSIONS
+}
+
+/// Source of sample values in [0, 1), addressed by pixel, sample index and dimension so any
+/// sample can be regenerated without replaying the ones before it
+pub trait Sampler {
+    fn get(&self, x: u32, y: u32, index: u32, dimension: u32) -> f32;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SamplerKind {
+    Independent,
+    Stratified,
+    Halton,
+    Sobol
+}
+
+impl SamplerKind {
+    pub fn from_name(name: &str) -> Option<SamplerKind> {
+        match name {
+            "independent" => Some(SamplerKind::Independent),
+            "stratified" => Some(SamplerKind::Stratified),
+            "halton" => Some(SamplerKind::Halton),
+            "sobol" => Some(SamplerKind::Sobol),
+            _ => None
+        }
+    }
+
+    pub fn build(self, seed: u64, samples_per_pixel: usize) -> Box<dyn Sampler + Send + Sync> {
+        match self {
+            SamplerKind::Independent => Box::new(IndependentSampler::new(seed)),
+            SamplerKind::Stratified => Box::new(StratifiedSampler::new(seed, samples_per_pixel)),
+            SamplerKind::Halton => Box::new(HaltonSampler::new(seed)),
+            SamplerKind::Sobol => Box::new(SobolSampler::new(seed))
+        }
+    }
+}
+
+fn hash(seed: u64, x: u32, y: u32, dimension: u32) -> u64 {
+    let pixel = ((y as u64) << 32) | x as u64;
+    splitmix64(seed ^ splitmix64(pixel ^ splitmix64(dimension as u64)))
+}
+
+fn to_unit(bits: u32) -> f32 {
+    (bits >> 8) as f32 * (1.0 / 16_777_216.0)
+}
+
+/// Uniform random numbers with no correlation between samples
+pub struct IndependentSampler {
+    seed: u64
+}
+
+impl IndependentSampler {
+    pub fn new(seed: u64) -> IndependentSampler {
+        IndependentSampler {
+            seed
+        }
+    }
+}
+
+impl Sampler for IndependentSampler {
+    fn get(&self, x: u32, y: u32, index: u32, dimension: u32) -> f32 {
+        let h = hash(self.seed, x, y, dimension);
+        to_unit((splitmix64(h ^ index as u64) >> 32) as u32)
+    }
+}
+
+/// Jittered strata. Pairs of dimensions share a 2D grid with one cell per sample; the cells are
+/// shuffled independently for every pair and pixel so the dimensions don't correlate.
+/// Sample indices past the sample count start a new, differently shuffled round
+pub struct StratifiedSampler {
+    seed: u64,
+    columns: u32,
+    rows: u32
+}
+
+/// Random permutation of 0..n applied to `index`, using Kensler's hash based permutation
+fn permute(mut index: u32, n: u32, seed: u32) -> u32 {
+    let mut w = n - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    loop {
+        index ^= seed;
+        index = index.wrapping_mul(0xe170_893d);
+        index ^= seed >> 16;
+        index ^= (index & w) >> 4;
+        index ^= seed >> 8;
+        index = index.wrapping_mul(0x0929_eb3f);
+        index ^= seed >> 23;
+        index ^= (index & w) >> 1;
+        index = index.wrapping_mul(1 | seed >> 27);
+        index = index.wrapping_mul(0x6935_fa69);
+        index ^= (index & w) >> 11;
+        index = index.wrapping_mul(0x74dc_b303);
+        index ^= (index & w) >> 2;
+        index = index.wrapping_mul(0x9e50_1cc3);
+        index ^= (index & w) >> 2;
+        index = index.wrapping_mul(0xc860_a3df);
+        index &= w;
+        index ^= index >> 5;
+        if index < n {
+            break;
+        }
+    }
+    (index.wrapping_add(seed)) % n
+}
+
+impl StratifiedSampler {
+    pub fn new(seed: u64, samples_per_pixel: usize) -> StratifiedSampler {
+        let samples = samples_per_pixel.max(1) as u32;
+        let columns = (samples as f32).sqrt().ceil() as u32;
+        let rows = samples.div_ceil(columns);
+        StratifiedSampler {
+            seed,
+            columns,
+            rows
+        }
+    }
+}
+
+impl Sampler for StratifiedSampler {
+    fn get(&self, x: u32, y: u32, index: u32, dimension: u32) -> f32 {
+        let cells = self.columns * self.rows;
+        let round = index / cells;
+        let pair = dimension / 2;
+        let h = hash(self.seed, x, y, pair) ^ splitmix64(round as u64);
+        let cell = permute(index % cells, cells, h as u32);
+        let jitter = to_unit((splitmix64(h ^ ((index as u64) << 1 | (dimension & 1) as u64)) >> 32) as u32);
+        let value = if dimension & 1 == 0 {
+            ((cell % self.columns) as f32 + jitter) / self.columns as f32
+        } else {
+            ((cell / self.columns) as f32 + jitter) / self.rows as f32
+        };
+        value.min(1.0 - f32::EPSILON)
+    }
+}
+
+const PRIMES: [u32; 64] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53,
+    59, 61, 67, 71, 73, 79, 83, 89, 97, 101, 103, 107, 109, 113, 127, 131,
+    137, 139, 149, 151, 157, 163, 167, 173, 179, 181, 191, 193, 197, 199, 211, 223,
+    227, 229, 233, 239, 241, 251, 257, 263, 269, 271, 277, 281, 283, 293, 307, 311
+];
+
+/// Halton sequence per pixel, one prime base per dimension. The digits are Owen scrambled
+/// with a different hash per pixel and dimension, which decorrelates the pixels and breaks up
+/// the linear patterns between large bases. Dimensions past the prime table fall back to
+/// independent samples
+pub struct HaltonSampler {
+    fallback: IndependentSampler,
+    seed: u64
+}
+
+/// Radical inverse of `index` with every digit permuted depending on the digits before it,
+/// carried on until the digits are below f32 precision
+fn scrambled_radical_inverse(base: u32, mut index: u32, seed: u64) -> f32 {
+    let inverse_base = 1.0 / base as f64;
+    let mut reversed: u64 = 0;
+    let mut inverse_power = 1.0;
+    while inverse_power > 1e-8 {
+        let next = index / base;
+        let digit = index - next * base;
+        let digit_seed = (splitmix64(seed ^ reversed) >> 32) as u32;
+        reversed = reversed * base as u64 + permute(digit, base, digit_seed) as u64;
+        inverse_power *= inverse_base;
+        index = next;
+    }
+    ((reversed as f64 * inverse_power) as f32).min(1.0 - f32::EPSILON)
+}
+
+impl HaltonSampler {
+    pub fn new(seed: u64) -> HaltonSampler {
+        HaltonSampler {
+            fallback: IndependentSampler::new(seed),
+            seed
+        }
+    }
+}
+
+impl Sampler for HaltonSampler {
+    fn get(&self, x: u32, y: u32, index: u32, dimension: u32) -> f32 {
+        match PRIMES.get(dimension as usize) {
+            Some(&base) => scrambled_radical_inverse(base, index, hash(self.seed, x, y, dimension)),
+            None => self.fallback.get(x, y, index, dimension)
+        }
+    }
+}
+
+/// Owen scrambled Sobol points using hash based nested uniform scrambling (Burley 2020).
+/// Only the first four Sobol dimensions are used: every group of four sample dimensions
+/// gets its own scramble and index shuffle, which keeps the groups independent of each other
+pub struct SobolSampler {
+    seed: u64,
+    directions: [[u32; 32]; 4]
+}
+
+/// Primitive polynomial degree, coefficients and initial direction numbers for Sobol
+/// dimensions 2 to 4, from Joe and Kuo
+const SOBOL_PARAMETERS: [(u32, u32, [u32; 3]); 3] = [
+    (1, 0, [1, 0, 0]),
+    (2, 1, [1, 3, 0]),
+    (3, 1, [1, 3, 1])
+];
+
+fn sobol_directions() -> [[u32; 32]; 4] {
+    let mut directions = [[0u32; 32]; 4];
+    for (bit, v) in directions[0].iter_mut().enumerate() {
+        *v = 1 << (31 - bit);
+    }
+    for (dimension, &(s, a, m)) in SOBOL_PARAMETERS.iter().enumerate() {
+        let s = s as usize;
+        let v = &mut directions[dimension + 1];
+        for k in 0..32 {
+            if k < s {
+                v[k] = m[k] << (31 - k);
+            } else {
+                let mut value = v[k - s] ^ (v[k - s] >> s);
+                for l in 1..s {
+                    if (a >> (s - 1 - l)) & 1 == 1 {
+                        value ^= v[k - l];
+                    }
+                }
+                v[k] = value;
+            }
+        }
+    }
+    directions
+}
+
+fn laine_karras_permutation(mut x: u32, seed: u32) -> u32 {
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50_b47c);
+    x ^= x.wrapping_mul(0xb82f_1e52);
+    x ^= x.wrapping_mul(0xc7af_e638);
+    x ^= x.wrapping_mul(0x8d22_f6e6);
+    x
+}
+
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+}
+
+impl SobolSampler {
+    pub fn new(seed: u64) -> SobolSampler {
+        SobolSampler {
+            seed,
+            directions: sobol_directions()
+        }
+    }
+
+    fn sobol(&self, index: u32, dimension: usize) -> u32 {
+        let mut value = 0;
+        let mut bits = index;
+        let mut bit = 0;
+        while bits > 0 {
+            if bits & 1 == 1 {
+                value ^= self.directions[dimension][bit];
+            }
+            bits >>= 1;
+            bit += 1;
+        }
+        value
+    }
+}
+
+impl Sampler for SobolSampler {
+    fn get(&self, x: u32, y: u32, index: u32, dimension: u32) -> f32 {
+        let group_seed = hash(self.seed, x, y, dimension / 4);
+        let component = (dimension % 4) as usize;
+        let shuffled = nested_uniform_scramble(index, group_seed as u32);
+        let value = self.sobol(shuffled, component);
+        let scramble_seed = (splitmix64(group_seed ^ component as u64) >> 32) as u32;
+        to_unit(nested_uniform_scramble(value, scramble_seed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sobol_points_fill_every_elementary_interval() {
+        let sampler = SobolSampler::new(7);
+        for &(x, y, first) in &[(0, 0, 0), (3, 9, 0), (3, 9, 4), (120, 45, 8)] {
+            for k in 0..=8 {
+                let n = 1u32 << k;
+                let points: Vec<(f32, f32)> = (0..n).map(|i| (sampler.get(x, y, i, first), sampler.get(x, y, i, first + 1))).collect();
+                //Intervals 2^-a wide and 2^-(k - a) tall each hold exactly one point
+                for a in 0..=k {
+                    let (columns, rows) = (1u32 << a, 1u32 << (k - a));
+                    let mut seen = vec![false; n as usize];
+                    for &(u, v) in points.iter() {
+                        let cell = (v * rows as f32) as u32 * columns + (u * columns as f32) as u32;
+                        assert!(!seen[cell as usize], "pixel {} {} dimension {}: two of {} points in a {}x{} interval", x, y, first, n, columns, rows);
+                        seen[cell as usize] = true;
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn halton_dimension_zero_is_the_base_2_radical_inverse() {
+        let sampler = HaltonSampler::new(7);
+        let values: Vec<f32> = (0..256).map(|i| sampler.get(5, 2, i, 0)).collect();
+        //Scrambling permutes digits, so the first digit is the index's lowest bit or its inverse
+        let flip = (values[0] >= 0.5) as u32;
+        for (i, v) in values.iter().enumerate() {
+            assert_eq!((*v >= 0.5) as u32, (i as u32 & 1) ^ flip, "{} {}", i, v);
+        }
+        //and, like the radical inverse, every run of 2^k starting at 0 lands once in each 2^-k interval
+        for k in 0..=8 {
+            let n = 1usize << k;
+            let mut cells: Vec<u32> = values[..n].iter().map(|v| (v * n as f32) as u32).collect();
+            cells.sort_unstable();
+            assert_eq!(cells, (0..n as u32).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn stratified_samples_take_one_stratum_each() {
+        for &spp in &[16, 12, 10, 1] {
+            let sampler = StratifiedSampler::new(3, spp);
+            let (columns, rows) = (sampler.columns, sampler.rows);
+            for &dimension in &[0, 2, 6] {
+                let mut seen = vec![false; (columns * rows) as usize];
+                for i in 0..spp as u32 {
+                    let (u, v) = (sampler.get(4, 1, i, dimension), sampler.get(4, 1, i, dimension + 1));
+                    let cell = (v * rows as f32) as u32 * columns + (u * columns as f32) as u32;
+                    assert!(!seen[cell as usize], "{} spp: stratum {} sampled twice", spp, cell);
+                    seen[cell as usize] = true;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn every_sampler_stays_in_the_unit_interval() {
+        for &kind in &[SamplerKind::Independent, SamplerKind::Stratified, SamplerKind::Halton, SamplerKind::Sobol] {
+            let sampler = kind.build(11, 9);
+            for index in 0..64 {
+                for dimension in 0..70 {
+                    for &(x, y) in &[(0, 0), (17, 3), (u32::MAX, u32::MAX)] {
+                        let v = sampler.get(x, y, index, dimension);
+                        assert!((0.0..1.0).contains(&v), "{:?} {} {} gave {}", kind, index, dimension, v);
+                    }
+                }
+            }
+        }
+    }
+}
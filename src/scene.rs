@@ -0,0 +1,516 @@
+//! Line based scene description. Each non-empty line that does not start with '#' is a
+//! directive followed by whitespace separated arguments; paths are relative to the scene file.
+//!
+//! ```text
+//!   obj <path> [key ...]
+//!   sphere <x> <y> <z> <radius> [emit <r> <g> <b>] [group <name>] [key ...]
+//!   moving_sphere <x0> <y0> <z0> <time0> <x1> <y1> <z1> <time1> <radius> [emit <r> <g> <b>] [group <name>]
+//!   point_light <x> <y> <z> <r> <g> <b> [aim <x> <y> <z>] [c0 <x> <y> <z>] [ies <path>] [group <name>]
+//!   spot_light <x> <y> <z> <tx> <ty> <tz> <r> <g> <b> <cone deg> <falloff deg> [c0 <x> <y> <z>] [ies <path>] [group <name>]
+//!   camera [projection <name>] [from <x> <y> <z>] [at <x> <y> <z>] [up <x> <y> <z>] [fov <deg>] [aperture <diameter>]
+//!          [fstop <n>] [focus <distance>] [focus_pixel <x> <y>] [blades <n>] [blade_rotation <deg>]
+//!          [shutter <open> <close>] [lens <path>]
+//!   tonemap [operator <clamp|reinhard|aces|agx|hable>] [exposure <ev>] [white_balance <kelvin>]
+//!           [white_point <luminance>] [dither <on|off>] [bits <8|16>]
+//! ```
+//!
+//! `lens` loads a pbrt style lens prescription and switches to the realistic projection.
+//!
+//! Objects may be animated with one or more transform keyframes, interpolated linearly in time:
+//!
+//! ```text
+//!   key <time> [translate <x> <y> <z>] [rotate <ax> <ay> <az> <deg>] [scale <s>]
+//! ```
+//!
+//! Point and spot light colours are radiant intensities. `ies` modulates the intensity by an
+//! IES LM-63 candela distribution whose vertical angle 0 points along `aim` (straight down
+//! for point lights, towards the target for spot lights) and whose horizontal angle 0 lies
+//! towards `c0`.
+//!
+//! `group` puts a light or emitter in a named light group, whose contribution can be written out
+//! as an AOV; everything else is in the group `default`. Each object directive gets the next
+//! object ID, and objects with the same material share a material ID.
+
+use vec3::Vec3;
+use hitable::{BvhNode, Hitable, Instance};
+use sphere::{MovingSphere, Sphere};
+use triangle::Triangle;
+use material::{DiffuseLight, Labelled, Labels, Lambertian, Material};
+use texture::ConstantTexture;
+use light::Light;
+use ies::{IesProfile, OrientedProfile};
+use camera::{CameraSettings, Projection};
+use transform::{AnimatedTransform, Quaternion, Transform};
+use lens::LensSystem;
+use rng;
+use tonemap::{Operator, ToneMapSettings};
+use error::{Error, Result};
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use obj::Obj;
+
+pub struct Scene {
+    pub objects: Vec<Box<dyn Hitable + Sync>>,
+    pub lights: Vec<Light>,
+    pub camera: CameraSettings,
+    pub tonemap: ToneMapSettings,
+    /// Names of the light groups, indexed by `Labels::light_group`
+    pub light_groups: Vec<String>,
+    /// Every file read to build the scene: the scene file itself, meshes, IES profiles and
+    /// lenses, in the order they were first read
+    pub files: Vec<PathBuf>
+}
+
+fn white_lambertian() -> Box<dyn Material + Sync> {
+    Box::new(Lambertian::new(Box::new(ConstantTexture::new(Vec3::new(1.0, 1.0, 1.0)))))
+}
+
+fn labelled_white(labels: Labels) -> Box<dyn Material + Sync> {
+    Box::new(Labelled::new(white_lambertian(), labels))
+}
+
+pub fn triangulate(vertices: Vec<Vec3>, labels: Labels) -> Result<Vec<Box<dyn Hitable + Sync>>> {
+    if vertices.len() < 3 {
+        return Err(Error::scene(format!("a face needs at least 3 vertices, found {}", vertices.len())));
+    }
+    let mut output: Vec<Box<dyn Hitable + Sync>> = Vec::new();
+
+    //Trivial case: exactly 3 vertices are passed in
+    if vertices.len() == 3 {
+        let edge1 = vertices[1] - vertices[0];
+        let edge2 = vertices[2] - vertices[0];
+        let normal = Vec3::unit_vector(edge1.cross(edge2));
+        output.push(Box::new(Triangle::new(vertices[0], vertices[1], vertices[2], normal, labelled_white(labels))));
+    } else { //Non trivial case - parse vertices as triangle fan
+        let common_idx = 0;
+        let mut first_idx = 1;
+        let mut second_idx = 2;
+
+        let common_v = vertices[common_idx];
+
+        while second_idx < vertices.len() {
+            let v1 = vertices[first_idx];
+            let v2 = vertices[second_idx];
+            let edge1 = v1 - common_v;
+            let edge2 = v2 - common_v;
+            let normal = Vec3::unit_vector(edge1.cross(edge2));
+            output.push(Box::new(Triangle::new(common_v, v1, v2, normal, labelled_white(labels))));
+            first_idx += 1;
+            second_idx += 1;
+        }
+    }
+    Ok(output)
+}
+
+pub fn load_obj(path: &Path, labels: Labels) -> Result<Vec<Box<dyn Hitable + Sync>>> {
+    let mut objects: Vec<Box<dyn Hitable + Sync>> = Vec::new();
+
+    let obj_file = Obj::load(path).map_err(|e| Error::from(e).in_file(path))?;
+    let positions = &obj_file.data.position;
+    for object in obj_file.data.objects.iter() {
+        for group in object.groups.iter() {
+            for polygon in group.polys.iter() {
+                let mut vertices: Vec<Vec3> = Vec::new();
+                for vertex in polygon.0.iter() {
+                    let index = vertex.0;
+                    let position = positions.get(index).ok_or_else(|| {
+                        Error::parse(format!("face refers to vertex {} of {}", index + 1, positions.len())).in_file(path)
+                    })?;
+                    vertices.push(Vec3::new(position[0], position[1], position[2]));
+                }
+                objects.append(&mut triangulate(vertices, labels).map_err(|e| e.in_file(path))?);
+            }
+        }
+    }
+    Ok(objects)
+}
+
+struct Args<'a> {
+    tokens: Vec<&'a str>,
+    pos: usize,
+    line: usize
+}
+
+impl<'a> Args<'a> {
+    fn has_next(&self) -> bool {
+        self.pos < self.tokens.len()
+    }
+
+    fn word(&mut self) -> Result<&'a str> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token.ok_or_else(|| Error::parse_line(self.line, "missing argument"))
+    }
+
+    fn float(&mut self) -> Result<f32> {
+        let token = self.word()?;
+        token.parse::<f32>().map_err(|_| Error::parse_line(self.line, format!("expected a number, found '{}'", token)))
+    }
+
+    /// A keyframe, motion or shutter time, which must be finite to be ordered
+    fn time(&mut self) -> Result<f32> {
+        let time = self.float()?;
+        if !time.is_finite() {
+            return Err(Error::parse_line(self.line, format!("expected a finite time, found '{}'", time)));
+        }
+        Ok(time)
+    }
+
+    fn uint(&mut self) -> Result<u32> {
+        let token = self.word()?;
+        token.parse::<u32>().map_err(|_| Error::parse_line(self.line, format!("expected a non-negative integer, found '{}'", token)))
+    }
+
+    fn vec3(&mut self) -> Result<Vec3> {
+        Ok(Vec3::new(self.float()?, self.float()?, self.float()?))
+    }
+
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.get(self.pos).cloned()
+    }
+
+    /// Parses one `key` keyframe, the `key` keyword itself already consumed
+    fn keyframe(&mut self) -> Result<(f32, Transform)> {
+        let time = self.time()?;
+        let mut translation = Vec3::zero_vector();
+        let mut rotation = Quaternion::identity();
+        let mut scale = 1.0;
+        loop {
+            match self.peek() {
+                Some("translate") => {
+                    self.pos += 1;
+                    translation = self.vec3()?;
+                },
+                Some("rotate") => {
+                    self.pos += 1;
+                    let axis = self.vec3()?;
+                    rotation = Quaternion::from_axis_angle(axis, self.float()?);
+                },
+                Some("scale") => {
+                    self.pos += 1;
+                    scale = self.float()?;
+                },
+                _ => break
+            }
+        }
+        Ok((time, Transform::new(translation, rotation, scale)))
+    }
+
+}
+
+struct Loader {
+    base: PathBuf,
+    /// Directory every file the scene names must be inside, for scenes from untrusted sources
+    root: Option<PathBuf>,
+    profiles: HashMap<PathBuf, Arc<IesProfile>>,
+    files: Vec<PathBuf>,
+    objects: u32,
+    /// Material IDs by a description of the material
+    materials: HashMap<String, u32>
+}
+
+impl Loader {
+    fn path(&self, relative: &str, line: usize) -> Result<PathBuf> {
+        let path = self.base.join(relative);
+        if let Some(root) = self.root.as_ref() {
+            let plain = Path::new(relative).components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+            if !plain || !path.starts_with(root) {
+                return Err(Error::parse_line(line, format!("'{}' is outside the directory scenes may read from", relative)));
+            }
+        }
+        Ok(path)
+    }
+
+    fn profile(&mut self, relative: &str, line: usize) -> Result<Arc<IesProfile>> {
+        let path = self.path(relative, line)?;
+        if let Some(profile) = self.profiles.get(&path) {
+            return Ok(profile.clone());
+        }
+        let profile = Arc::new(IesProfile::load(&path)?);
+        self.files.push(path.clone());
+        self.profiles.insert(path, profile.clone());
+        Ok(profile)
+    }
+
+    /// Labels for the next object, made of white diffuse or emitting `emission`
+    fn labels(&mut self, emission: Option<Vec3>, light_group: usize) -> Labels {
+        let description = match emission {
+            Some(c) => format!("emit {} {} {}", c.x(), c.y(), c.z()),
+            None => "white".to_string()
+        };
+        let next = self.materials.len() as u32 + 1;
+        let material_id = *self.materials.entry(description).or_insert(next);
+        self.objects += 1;
+        Labels {
+            object_id: self.objects,
+            material_id,
+            light_group
+        }
+    }
+
+    fn material(&mut self, emission: Option<Vec3>, light_group: usize) -> Box<dyn Material + Sync> {
+        let labels = self.labels(emission, light_group);
+        match emission {
+            Some(c) => Box::new(Labelled::new(Box::new(DiffuseLight::new(Box::new(ConstantTexture::new(c)))), labels)),
+            None => labelled_white(labels)
+        }
+    }
+
+    /// Parses the trailing keyword options shared by point and spot lights
+    fn light_options(&mut self, args: &mut Args, scene: &mut Scene, mut light: Light, mut aim: Vec3) -> Result<Light> {
+        let mut c0 = Vec3::new(1.0, 0.0, 0.0);
+        let mut profile = None;
+        while args.has_next() {
+            match args.word()? {
+                "aim" => aim = args.vec3()?,
+                "c0" => c0 = args.vec3()?,
+                "ies" => {
+                    let path = args.word()?;
+                    profile = Some(self.profile(path, args.line)?);
+                },
+                "group" => light = light.with_group(scene.light_group(args.word()?)),
+                other => return Err(Error::parse_line(args.line, format!("unknown light option '{}'", other)))
+            }
+        }
+        if let Some(profile) = profile {
+            light = light.with_profile(OrientedProfile::new(profile, aim, c0));
+        }
+        Ok(light)
+    }
+}
+
+impl Default for Scene {
+    fn default() -> Scene {
+        Scene::new()
+    }
+}
+
+impl Scene {
+    pub fn new() -> Scene {
+        Scene {
+            objects: Vec::new(),
+            lights: Vec::new(),
+            camera: CameraSettings::default(),
+            tonemap: ToneMapSettings::default(),
+            light_groups: vec!["default".to_string()],
+            files: Vec::new()
+        }
+    }
+
+    /// Index of the named light group, adding it if it is new
+    pub fn light_group(&mut self, name: &str) -> usize {
+        match self.light_groups.iter().position(|g| g == name) {
+            Some(index) => index,
+            None => {
+                self.light_groups.push(name.to_string());
+                self.light_groups.len() - 1
+            }
+        }
+    }
+
+    /// An OBJ model on its own, lit by a small spherical light in front of it
+    pub fn from_obj(path: &Path) -> Result<Scene> {
+        rng::set(rng::Pcg32::new(0, rng::BUILD_STREAM));
+        let mut scene = Scene::new();
+        scene.objects = load_obj(path, Labels { object_id: 1, material_id: 1, light_group: 0 })?;
+        scene.files.push(path.to_path_buf());
+        let light = Box::new(DiffuseLight::new(Box::new(ConstantTexture::new(Vec3::new(2.0, 2.0, 2.0)))));
+        let labels = Labels { object_id: 2, material_id: 2, light_group: 0 };
+        scene.objects.push(Box::new(Sphere::new(Vec3::new(0.0, 0.0, 2.0), 0.5, Box::new(Labelled::new(light, labels)))));
+        Ok(scene)
+    }
+
+    pub fn load(path: &Path) -> Result<Scene> {
+        Scene::load_from(path, None)
+    }
+
+    /// As `load`, refusing meshes, profiles and lenses the scene names by an absolute path or
+    /// one leading out of `root`, for scenes from someone who may not read other files
+    pub fn load_inside(path: &Path, root: &Path) -> Result<Scene> {
+        Scene::load_from(path, Some(root))
+    }
+
+    fn load_from(path: &Path, root: Option<&Path>) -> Result<Scene> {
+        let text = fs::read_to_string(path).map_err(|e| Error::io(path, e))?;
+        let base = path.parent().map(|p| p.to_path_buf()).unwrap_or_default();
+        let mut scene = Scene::parse_from(&text, &base, root).map_err(|e| e.in_file(path))?;
+        scene.files.insert(0, path.to_path_buf());
+        Ok(scene)
+    }
+
+    /// Restarts this thread's build stream first, so BVH splits here and in the `PreparedScene`
+    /// made from the scene are the same every time it is loaded
+    pub fn parse(text: &str, base: &Path) -> Result<Scene> {
+        Scene::parse_from(text, base, None)
+    }
+
+    fn parse_from(text: &str, base: &Path, root: Option<&Path>) -> Result<Scene> {
+        rng::set(rng::Pcg32::new(0, rng::BUILD_STREAM));
+        let mut scene = Scene::new();
+        let mut loader = Loader {
+            base: base.to_path_buf(),
+            root: root.map(|r| r.to_path_buf()),
+            profiles: HashMap::new(),
+            files: Vec::new(),
+            objects: 0,
+            materials: HashMap::new()
+        };
+
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut args = Args {
+                tokens: line.split_whitespace().collect(),
+                pos: 0,
+                line: index + 1
+            };
+            match args.word()? {
+                "obj" => {
+                    let path = loader.path(args.word()?, args.line)?;
+                    let mut keys = Vec::new();
+                    while args.has_next() {
+                        match args.word()? {
+                            "key" => keys.push(args.keyframe()?),
+                            other => return Err(Error::parse_line(args.line, format!("unknown obj option '{}'", other)))
+                        }
+                    }
+                    let mut objects = load_obj(&path, loader.labels(None, 0))?;
+                    loader.files.push(path);
+                    if keys.is_empty() {
+                        scene.objects.append(&mut objects);
+                    } else if !objects.is_empty() {
+                        let mesh = Box::new(BvhNode::new(objects));
+                        scene.objects.push(Box::new(Instance::new(mesh, AnimatedTransform::keyframed(keys))));
+                    }
+                },
+                "sphere" => {
+                    let center = args.vec3()?;
+                    let radius = args.float()?;
+                    let mut emission = None;
+                    let mut group = 0;
+                    let mut keys = Vec::new();
+                    while args.has_next() {
+                        match args.word()? {
+                            "emit" => emission = Some(args.vec3()?),
+                            "group" => group = scene.light_group(args.word()?),
+                            "key" => keys.push(args.keyframe()?),
+                            other => return Err(Error::parse_line(args.line, format!("unknown sphere option '{}'", other)))
+                        }
+                    }
+                    let sphere = Box::new(Sphere::new(center, radius, loader.material(emission, group)));
+                    if keys.is_empty() {
+                        scene.objects.push(sphere);
+                    } else {
+                        scene.objects.push(Box::new(Instance::new(sphere, AnimatedTransform::keyframed(keys))));
+                    }
+                },
+                "moving_sphere" => {
+                    let center0 = args.vec3()?;
+                    let time0 = args.time()?;
+                    let center1 = args.vec3()?;
+                    let time1 = args.time()?;
+                    let radius = args.float()?;
+                    let mut emission = None;
+                    let mut group = 0;
+                    while args.has_next() {
+                        match args.word()? {
+                            "emit" => emission = Some(args.vec3()?),
+                            "group" => group = scene.light_group(args.word()?),
+                            other => return Err(Error::parse_line(args.line, format!("unknown moving_sphere option '{}'", other)))
+                        }
+                    }
+                    let material = loader.material(emission, group);
+                    scene.objects.push(Box::new(MovingSphere::new(center0, center1, time0, time1, radius, material)));
+                },
+                "point_light" => {
+                    let position = args.vec3()?;
+                    let intensity = args.vec3()?;
+                    let light = loader.light_options(&mut args, &mut scene, Light::point(position, intensity), Vec3::new(0.0, -1.0, 0.0))?;
+                    scene.lights.push(light);
+                },
+                "spot_light" => {
+                    let position = args.vec3()?;
+                    let target = args.vec3()?;
+                    let intensity = args.vec3()?;
+                    let cone = args.float()?;
+                    let falloff = args.float()?;
+                    let light = Light::spot(position, target - position, intensity, cone, falloff);
+                    let light = loader.light_options(&mut args, &mut scene, light, target - position)?;
+                    scene.lights.push(light);
+                },
+                "camera" => {
+                    let camera = &mut scene.camera;
+                    while args.has_next() {
+                        match args.word()? {
+                            "projection" => {
+                                let name = args.word()?;
+                                camera.projection = Projection::from_name(name)
+                                    .ok_or_else(|| Error::parse_line(args.line, format!("unknown projection '{}'", name)))?;
+                            },
+                            "from" => camera.lookfrom = args.vec3()?,
+                            "at" => camera.lookat = args.vec3()?,
+                            "up" => camera.vup = args.vec3()?,
+                            "fov" => camera.vfov = args.float()?,
+                            "aperture" => camera.aperture = args.float()?,
+                            "fstop" => camera.fstop = Some(args.float()?),
+                            "focus" => camera.focus_dist = Some(args.float()?),
+                            "focus_pixel" => camera.focus_pixel = Some((args.uint()?, args.uint()?)),
+                            "blades" => camera.blades = args.uint()?,
+                            "blade_rotation" => camera.blade_rotation = args.float()?,
+                            "lens" => {
+                                let path = loader.path(args.word()?, args.line)?;
+                                let lens = LensSystem::load(&path)?;
+                                loader.files.push(path);
+                                camera.lens = Some(Arc::new(lens));
+                                camera.projection = Projection::Realistic;
+                            },
+                            "shutter" => {
+                                camera.shutter_open = args.time()?;
+                                camera.shutter_close = args.time()?;
+                            },
+                            other => return Err(Error::parse_line(args.line, format!("unknown camera option '{}'", other)))
+                        }
+                    }
+                },
+                "tonemap" => {
+                    let tonemap = &mut scene.tonemap;
+                    while args.has_next() {
+                        match args.word()? {
+                            "operator" => {
+                                let name = args.word()?;
+                                tonemap.operator = Operator::from_name(name)
+                                    .ok_or_else(|| Error::parse_line(args.line, format!("unknown tone mapping operator '{}'", name)))?;
+                            },
+                            "exposure" => tonemap.exposure = args.float()?,
+                            "white_balance" => tonemap.white_balance = Some(args.float()?),
+                            "white_point" => tonemap.white_point = args.float()?,
+                            "dither" => tonemap.dither = match args.word()? {
+                                "on" => true,
+                                "off" => false,
+                                other => return Err(Error::parse_line(args.line, format!("dither expects on or off, found '{}'", other)))
+                            },
+                            "bits" => tonemap.bit_depth = match args.uint()? {
+                                8 => 8,
+                                16 => 16,
+                                other => return Err(Error::parse_line(args.line, format!("bits must be 8 or 16, found {}", other)))
+                            },
+                            other => return Err(Error::parse_line(args.line, format!("unknown tonemap option '{}'", other)))
+                        }
+                    }
+                },
+                other => return Err(Error::parse_line(args.line, format!("unknown directive '{}'", other)))
+            }
+            if args.has_next() {
+                return Err(Error::parse_line(args.line, format!("unexpected argument '{}'", args.tokens[args.pos])));
+            }
+        }
+        scene.files = loader.files;
+        Ok(scene)
+    }
+}
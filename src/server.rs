@@ -0,0 +1,578 @@
+//! A small HTTP/1.1 API for queueing renders and following them, one request per connection:
+//!
+//! ```text
+//!   POST   /jobs              queues a render. Query parameters are command line options by their
+//!                             long names, like `spp=64&width=320&denoise`; the body is the scene
+//!                             file, or `scene=path` names one in the jobs directory. An uploaded
+//!                             scene is saved there, and files it names are relative to it. The
+//!                             job is loaded before it is queued, so bad options or scenes are
+//!                             refused here. Replies {"id": n}
+//!   GET    /jobs              the status of every job
+//!   GET    /jobs/<id>         {"id", "state", "scene", "output", "progress", "elapsed", "eta", "error"},
+//!                             times in seconds; state is queued, running, done, cancelled or failed
+//!   GET    /jobs/<id>/preview the image so far as an 8-bit PNG, without denoising
+//!   DELETE /jobs/<id>         cancels a queued or running job, keeping what has been rendered
+//! ```
+//!
+//! Jobs render one at a time, each using every core. Only the options in `OPTIONS` and
+//! `FILE_OPTIONS` can be set. Files are named by relative paths inside the jobs directory, in
+//! the query and in the meshes, IES profiles and lenses a scene names, so a request can't read
+//! or write anywhere else on the server
+
+use render::{CancelToken, Progress, RenderControl};
+use output;
+use job;
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often running jobs update their preview unless they set --write-interval themselves
+const PREVIEW_INTERVAL: &str = "1";
+
+/// Largest scene file accepted in a request body
+const MAX_BODY: usize = 64 << 20;
+
+/// How long a client may take to send its request or read the response
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Options a request may set, by their long names
+const OPTIONS: &[&str] = &[
+    "spp", "max-spp", "noise-threshold", "time-limit", "write-interval", "tonemap", "exposure",
+    "white-balance", "white-point", "no-dither", "bit-depth", "exr-precision", "exr-layers", "aovs",
+    "denoise", "clamp-direct", "clamp-indirect", "median-of-means", "crop", "width", "height",
+    "projection", "lookfrom", "lookat", "up", "vfov", "aperture", "fstop", "focus-dist",
+    "focus-pixel", "blades", "blade-rotation", "shutter", "tile-size", "tile-order", "seed",
+    "sampler", "filter", "filter-radius"
+];
+
+/// Options naming a file, which must be a relative path inside the jobs directory
+const FILE_OPTIONS: &[&str] = &["output", "checkpoint", "resume", "sample-heatmap", "lens"];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    Queued,
+    Running,
+    Done,
+    Cancelled,
+    Failed
+}
+
+impl State {
+    fn name(self) -> &'static str {
+        match self {
+            State::Queued => "queued",
+            State::Running => "running",
+            State::Done => "done",
+            State::Cancelled => "cancelled",
+            State::Failed => "failed"
+        }
+    }
+}
+
+struct Status {
+    state: State,
+    started: Option<Instant>,
+    /// Set once the job stops
+    elapsed: Option<Duration>,
+    error: Option<String>
+}
+
+struct Job {
+    id: usize,
+    args: Vec<String>,
+    scene: String,
+    output: String,
+    cancel: CancelToken,
+    progress: Arc<Progress>,
+    status: Mutex<Status>,
+    /// Width, height and 8-bit RGBA data
+    preview: Mutex<Option<(u32, u32, Vec<u8>)>>
+}
+
+struct Server {
+    directory: PathBuf,
+    /// Starts after the highest numbered file already in the directory, so a restarted server
+    /// doesn't overwrite earlier jobs' scenes and images
+    next_id: AtomicUsize,
+    jobs: Mutex<Vec<Arc<Job>>>,
+    queue: Mutex<VecDeque<Arc<Job>>>,
+    queued: Condvar
+}
+
+struct Request {
+    method: String,
+    path: String,
+    query: String,
+    body: Vec<u8>
+}
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: Vec<u8>
+}
+
+impl Response {
+    fn json(status: &'static str, body: String) -> Response {
+        Response {
+            status,
+            content_type: "application/json",
+            body: body.into_bytes()
+        }
+    }
+
+    fn error(status: &'static str, message: &str) -> Response {
+        Response::json(status, format!("{{\"error\": {}}}", json_string(message)))
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c)
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn json_number(v: Option<f64>) -> String {
+    match v {
+        Some(v) if v.is_finite() => format!("{:.3}", v),
+        _ => "null".to_string()
+    }
+}
+
+/// Decodes %XX escapes and + as a space
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                match std::str::from_utf8(&bytes[i + 1..i + 3]).ok().and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                    Some(b) => {
+                        decoded.push(b);
+                        i += 2;
+                    },
+                    None => decoded.push(b'%')
+                }
+            },
+            b => decoded.push(b)
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn read_request(stream: &TcpStream) -> io::Result<Request> {
+    let mut r = BufReader::new(stream);
+    let mut line = String::new();
+    r.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method.to_string(), target.to_string()),
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "malformed request line"))
+    };
+    let mut length = 0;
+    loop {
+        line.clear();
+        if r.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse::<usize>().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            }
+        }
+    }
+    if length > MAX_BODY {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "request body too large"));
+    }
+    let mut body = vec![0; length];
+    r.read_exact(&mut body)?;
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path.to_string(), query.to_string()),
+        None => (target, String::new())
+    };
+    Ok(Request {
+        method,
+        path,
+        query,
+        body
+    })
+}
+
+fn write_response(mut stream: &TcpStream, response: &Response) -> io::Result<()> {
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+           response.status, response.content_type, response.body.len())?;
+    stream.write_all(&response.body)?;
+    stream.flush()
+}
+
+impl Job {
+    fn status_json(&self) -> String {
+        let status = self.status.lock().unwrap();
+        let elapsed = status.elapsed.or_else(|| status.started.map(|s| s.elapsed())).map(|e| e.as_secs_f64());
+        let progress = self.progress.fraction() as f64;
+        let eta = match (status.state, elapsed) {
+            (State::Running, Some(elapsed)) if progress > 0.0 => Some(elapsed * (1.0 - progress) / progress),
+            (State::Done, _) => Some(0.0),
+            _ => None
+        };
+        format!("{{\"id\": {}, \"state\": \"{}\", \"scene\": {}, \"output\": {}, \"progress\": {:.4}, \"elapsed\": {}, \"eta\": {}, \"error\": {}}}",
+                self.id, status.state.name(), json_string(&self.scene), json_string(&self.output), progress,
+                json_number(elapsed), json_number(eta), status.error.as_ref().map_or("null".to_string(), |e| json_string(e)))
+    }
+}
+
+impl Server {
+    fn job(&self, id: &str) -> Option<Arc<Job>> {
+        let id = id.parse::<usize>().ok()?;
+        self.jobs.lock().unwrap().iter().find(|j| j.id == id).cloned()
+    }
+
+    /// `name` as a path in the jobs directory, or None if it is absolute or leads out of it
+    fn inside(&self, name: &str) -> Option<PathBuf> {
+        let path = Path::new(name);
+        if name.is_empty() || !path.components().all(|c| matches!(c, Component::Normal(_))) {
+            return None;
+        }
+        Some(self.directory.join(path))
+    }
+
+    fn submit(&self, request: &Request) -> Response {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut options = Vec::new();
+        let (mut scene, mut output, mut write_interval) = (None, None, false);
+        for pair in request.query.split('&').filter(|p| !p.is_empty()) {
+            let (name, value) = match pair.split_once('=') {
+                Some((name, value)) => (percent_decode(name), percent_decode(value)),
+                None => (percent_decode(pair), String::new())
+            };
+            let path = if name == "scene" || FILE_OPTIONS.contains(&name.as_str()) {
+                match self.inside(&value) {
+                    Some(path) => Some(path.display().to_string()),
+                    None => return Response::error("400 Bad Request", &format!("{} must be a relative path inside the jobs directory", name))
+                }
+            } else if OPTIONS.contains(&name.as_str()) {
+                None
+            } else {
+                return Response::error("400 Bad Request", &format!("Unknown or disallowed option '{}'", name));
+            };
+            match name.as_str() {
+                "scene" => scene = path,
+                "output" => output = path,
+                _ => {
+                    write_interval |= name == "write-interval";
+                    options.push(format!("--{}", name));
+                    match path {
+                        Some(path) => options.push(path),
+                        None if !value.is_empty() => options.push(value),
+                        None => {}
+                    }
+                }
+            }
+        }
+        let uploaded = !request.body.is_empty();
+        let scene = match scene {
+            Some(path) if !uploaded => path,
+            _ if uploaded => {
+                let path = self.directory.join(format!("{}.scene", id));
+                if let Err(e) = fs::write(&path, &request.body) {
+                    return Response::error("500 Internal Server Error", &format!("Failed to save the scene: {}", e));
+                }
+                path.display().to_string()
+            },
+            _ => return Response::error("400 Bad Request", "Send the scene file as the body or name one with scene=path")
+        };
+        let output = output.unwrap_or_else(|| self.directory.join(format!("{}.png", id)).display().to_string());
+
+        let mut args = vec!["rust-pathtracer-demo".to_string(), scene.clone(), "--output".to_string(), output.clone(),
+                            "--root".to_string(), self.directory.display().to_string()];
+        if !write_interval {
+            args.push("--write-interval".to_string());
+            args.push(PREVIEW_INTERVAL.to_string());
+        }
+        args.append(&mut options);
+        //Loading the job here means a client hears about bad options or files straight away
+        if let Err(e) = job::RenderJob::from_args(&args, 0) {
+            if uploaded {
+                let _ = fs::remove_file(&scene);
+            }
+            return Response::error("400 Bad Request", &e.to_string());
+        }
+        let job = Arc::new(Job {
+            id,
+            args,
+            scene,
+            output,
+            cancel: CancelToken::new(),
+            progress: Arc::new(Progress::default()),
+            status: Mutex::new(Status { state: State::Queued, started: None, elapsed: None, error: None }),
+            preview: Mutex::new(None)
+        });
+        self.jobs.lock().unwrap().push(job.clone());
+        self.queue.lock().unwrap().push_back(job);
+        self.queued.notify_one();
+        Response::json("201 Created", format!("{{\"id\": {}}}", id))
+    }
+
+    fn handle(&self, request: &Request) -> Response {
+        let segments: Vec<&str> = request.path.split('/').filter(|s| !s.is_empty()).collect();
+        match (request.method.as_str(), segments.as_slice()) {
+            ("POST", ["jobs"]) => self.submit(request),
+            ("GET", ["jobs"]) => {
+                let statuses: Vec<String> = self.jobs.lock().unwrap().iter().map(|j| j.status_json()).collect();
+                Response::json("200 OK", format!("[{}]", statuses.join(", ")))
+            },
+            (method, ["jobs", id, rest @ ..]) => {
+                let job = match self.job(id) {
+                    Some(job) => job,
+                    None => return Response::error("404 Not Found", "No such job")
+                };
+                match (method, rest) {
+                    ("GET", []) => Response::json("200 OK", job.status_json()),
+                    ("DELETE", []) => {
+                        job.cancel.cancel();
+                        Response::json("200 OK", job.status_json())
+                    },
+                    ("GET", ["preview"]) => match job.preview.lock().unwrap().as_ref() {
+                        Some((width, height, data)) => {
+                            let mut png = Vec::new();
+                            match output::encode_png(&mut png, *width, *height, data, 8) {
+                                Ok(()) => Response { status: "200 OK", content_type: "image/png", body: png },
+                                Err(e) => Response::error("500 Internal Server Error", &e.to_string())
+                            }
+                        },
+                        None => Response::error("404 Not Found", "No preview yet")
+                    },
+                    _ => Response::error("405 Method Not Allowed", "Unsupported method for this path")
+                }
+            },
+            _ => Response::error("404 Not Found", "Unknown path")
+        }
+    }
+
+    /// Renders queued jobs one at a time, forever
+    fn run_jobs(&self) {
+        loop {
+            let job = {
+                let mut queue = self.queue.lock().unwrap();
+                loop {
+                    match queue.pop_front() {
+                        Some(job) => break job,
+                        None => queue = self.queued.wait(queue).unwrap()
+                    }
+                }
+            };
+            if job.cancel.is_cancelled() {
+                job.status.lock().unwrap().state = State::Cancelled;
+                continue;
+            }
+            let start = Instant::now();
+            {
+                let mut status = job.status.lock().unwrap();
+                status.state = State::Running;
+                status.started = Some(start);
+            }
+            let previewed = job.clone();
+            let control = RenderControl {
+                cancel: job.cancel.clone(),
+                progress: job.progress.clone(),
+                observer: None,
+                preview: Some(Box::new(move |width, height, data| {
+                    *previewed.preview.lock().unwrap() = Some((width, height, data));
+                }))
+            };
+            //A panic in the renderer fails the job, not the server
+            let result = panic::catch_unwind(AssertUnwindSafe(|| job::run(&job.args, &control).map_err(|e| e.to_string())))
+                .unwrap_or_else(|_| Err("The render panicked".to_string()));
+            let mut status = job.status.lock().unwrap();
+            status.elapsed = Some(start.elapsed());
+            status.state = match result {
+                Ok(()) if job.cancel.is_cancelled() => State::Cancelled,
+                Ok(()) => State::Done,
+                Err(e) => {
+                    status.error = Some(e);
+                    State::Failed
+                }
+            };
+        }
+    }
+}
+
+/// One more than the highest job ID among the files in `directory`, named like `12.png`
+fn first_free_id(directory: &Path) -> io::Result<usize> {
+    let mut highest = 0;
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        if let Some(id) = path.file_stem().and_then(|s| s.to_str()).and_then(|s| s.parse::<usize>().ok()) {
+            highest = highest.max(id);
+        }
+    }
+    Ok(highest + 1)
+}
+
+/// Serves the API on `address`, keeping uploaded scenes and rendered images in `directory`
+pub fn serve(address: &str, directory: &Path) -> io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+    println!("Serving render jobs on http://{}", listener.local_addr()?);
+    serve_on(listener, directory)
+}
+
+fn serve_on(listener: TcpListener, directory: &Path) -> io::Result<()> {
+    fs::create_dir_all(directory)?;
+    let server = Arc::new(Server {
+        directory: directory.to_path_buf(),
+        next_id: AtomicUsize::new(first_free_id(directory)?),
+        jobs: Mutex::new(Vec::new()),
+        queue: Mutex::new(VecDeque::new()),
+        queued: Condvar::new()
+    });
+
+    let worker = server.clone();
+    thread::spawn(move || worker.run_jobs());
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Failed to accept a connection: {}", e);
+                continue;
+            }
+        };
+        let server = server.clone();
+        thread::spawn(move || {
+            if stream.set_read_timeout(Some(REQUEST_TIMEOUT)).and_then(|_| stream.set_write_timeout(Some(REQUEST_TIMEOUT))).is_err() {
+                return;
+            }
+            let response = match read_request(&stream) {
+                Ok(request) => server.handle(&request),
+                Err(e) => Response::error("400 Bad Request", &e.to_string())
+            };
+            let _ = write_response(&stream, &response);
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    const SCENE: &str = "sphere 0 0 -1 0.5\npoint_light 0 2 0 4 4 4\ncamera from 0 0 1 at 0 0 -1 fov 60\n";
+
+    /// Sends one request and returns the response's status code and body
+    fn send(address: SocketAddr, method: &str, target: &str, body: &str) -> (u32, String) {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(stream, "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}", method, target, body.len(), body).unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        let response = String::from_utf8_lossy(&response);
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        (head.split_whitespace().nth(1).unwrap().parse().unwrap(), body.to_string())
+    }
+
+    fn start(directory: &Path) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let directory = directory.to_path_buf();
+        thread::spawn(move || serve_on(listener, &directory));
+        address
+    }
+
+    /// The status of job `id` once it has stopped
+    fn wait_for(address: SocketAddr, id: usize) -> String {
+        let deadline = Instant::now() + Duration::from_secs(60);
+        loop {
+            let (status, body) = send(address, "GET", &format!("/jobs/{}", id), "");
+            assert_eq!(status, 200);
+            if !body.contains("\"state\": \"queued\"") && !body.contains("\"state\": \"running\"") {
+                return body;
+            }
+            assert!(Instant::now() < deadline, "job did not finish");
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    fn temporary_directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("pathtracer-server-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    #[test]
+    fn renders_a_submitted_scene() {
+        let directory = temporary_directory("render");
+        let address = start(&directory);
+        let (status, body) = send(address, "POST", "/jobs?spp=4&width=16&height=9", SCENE);
+        assert_eq!((status, body.as_str()), (201, "{\"id\": 1}"));
+        assert!(wait_for(address, 1).contains("\"state\": \"done\""));
+        assert!(directory.join("1.png").exists());
+        let (status, _) = send(address, "GET", "/jobs/1/preview", "");
+        assert_eq!(status, 200);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn refuses_bad_jobs_before_queueing() {
+        let directory = temporary_directory("refuse");
+        let address = start(&directory);
+        for target in ["/jobs?spp=many", "/jobs?workers=127.0.0.1:7878", "/jobs?output=/tmp/stolen.png",
+                       "/jobs?output=../stolen.png", "/jobs?checkpoint=a/../../b.ckpt"].iter() {
+            let (status, body) = send(address, "POST", target, SCENE);
+            assert_eq!(status, 400, "{}: {}", target, body);
+        }
+        let (status, _) = send(address, "POST", "/jobs?scene=missing.scene", "");
+        assert_eq!(status, 400);
+        let (status, body) = send(address, "GET", "/jobs", "");
+        assert_eq!((status, body.as_str()), (200, "[]"));
+        assert_eq!(fs::read_dir(&directory).unwrap().count(), 0);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn refuses_scenes_naming_files_outside_the_directory() {
+        let directory = temporary_directory("outside");
+        let address = start(&directory);
+        for line in ["obj /etc/passwd", "obj ../x.obj", "point_light 0 2 0 1 1 1 ies ../x.ies",
+                     "camera lens /etc/passwd", "obj ./sub/../../x.obj"].iter() {
+            let (status, body) = send(address, "POST", "/jobs?spp=1&width=4&height=4", &format!("{}{}\n", SCENE, line));
+            assert_eq!(status, 400, "{}: {}", line, body);
+            assert!(body.contains("outside the directory"), "{}: {}", line, body);
+        }
+        let (_, body) = send(address, "GET", "/jobs", "");
+        assert_eq!(body, "[]");
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn numbers_jobs_after_earlier_files() {
+        let directory = temporary_directory("numbering");
+        fs::write(directory.join("7.png"), b"").unwrap();
+        fs::write(directory.join("notes.txt"), b"").unwrap();
+        let address = start(&directory);
+        let (status, body) = send(address, "POST", "/jobs?spp=1&width=4&height=4", SCENE);
+        assert_eq!((status, body.as_str()), (201, "{\"id\": 8}"));
+        let (_, body) = send(address, "DELETE", "/jobs/8", "");
+        assert!(body.contains("\"id\": 8"));
+        wait_for(address, 8);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
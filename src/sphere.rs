@@ -1,9 +1,12 @@
 use aabb::AABB;
+use aabb::surrounding_bbox;
 use vec3::Vec3;
 use ray::Ray;
 use material::Material;
 use hitable::Hit;
 use hitable::Hitable;
+use light::{Light, LightShape};
+use stats;
 
 pub struct Sphere {
     center: Vec3,
@@ -11,6 +14,16 @@ pub struct Sphere {
     material: Box<dyn Material + Sync>
 }
 
+/// Sphere whose centre moves linearly from `center0` at `time0` to `center1` at `time1`
+pub struct MovingSphere {
+    center0: Vec3,
+    center1: Vec3,
+    time0: f32,
+    time1: f32,
+    radius: f32,
+    material: Box<dyn Material + Sync>
+}
+
 impl Sphere {
     pub fn new(center: Vec3, radius: f32, material: Box<dyn Material + Sync>) -> Sphere {
         Sphere {
@@ -22,7 +35,8 @@ impl Sphere {
 }
 
 impl Hitable for Sphere {
-    fn hit(&self, t_min: f32, t_max: f32, r: &Ray) -> Option<Hit> {
+    fn hit(&self, t_min: f32, t_max: f32, r: &Ray) -> Option<Hit<'_>> {
+        stats::count(|s| s.primitive_tests += 1);
         let oc = r.origin() - self.center;
         let a = r.direction().dot(r.direction());
         let b = oc.dot(r.direction());
@@ -35,7 +49,8 @@ impl Hitable for Sphere {
                     t: temp,
                     p: r.point_at_parameter(temp),
                     normal: (r.point_at_parameter(temp) - self.center) / self.radius,
-                    material: &self.material
+                    material: &*self.material,
+                    explicit_light: true
                 });
             }
             let temp = (-b + discriminant.sqrt()) / a;
@@ -44,7 +59,8 @@ impl Hitable for Sphere {
                     t: temp,
                     p: r.point_at_parameter(temp),
                     normal: (r.point_at_parameter(temp) - self.center) / self.radius,
-                    material: &self.material
+                    material: &*self.material,
+                    explicit_light: true
                 });
             }
         }
@@ -54,4 +70,94 @@ impl Hitable for Sphere {
     fn bounding_box(&self) -> AABB {
         AABB::new(self.center - Vec3::new(self.radius, self.radius, self.radius), self.center + Vec3::new(self.radius, self.radius, self.radius))
     }
+
+    fn as_light(&self) -> Option<Light> {
+        let radiance = self.material.emitted(0.0, 0.0, &self.center);
+        if radiance.luminance() > 0.0 {
+            Some(Light::new(LightShape::Sphere { center: self.center, radius: self.radius }, radiance).with_group(self.material.labels().light_group))
+        } else {
+            None
+        }
+    }
+}
+
+impl MovingSphere {
+    pub fn new(center0: Vec3, center1: Vec3, time0: f32, time1: f32, radius: f32, material: Box<dyn Material + Sync>) -> MovingSphere {
+        MovingSphere {
+            center0,
+            center1,
+            time0,
+            time1,
+            radius,
+            material
+        }
+    }
+
+    pub fn center(&self, time: f32) -> Vec3 {
+        let span = self.time1 - self.time0;
+        let t = if span > 0.0 {
+            ((time - self.time0) / span).clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        self.center0 + t * (self.center1 - self.center0)
+    }
+}
+
+impl Hitable for MovingSphere {
+    fn hit(&self, t_min: f32, t_max: f32, r: &Ray) -> Option<Hit<'_>> {
+        stats::count(|s| s.primitive_tests += 1);
+        let center = self.center(r.time());
+        let oc = r.origin() - center;
+        let a = r.direction().dot(r.direction());
+        let b = oc.dot(r.direction());
+        let c = oc.dot(oc) - self.radius * self.radius;
+        let discriminant = b*b - a*c;
+        if discriminant > 0.0 {
+            for temp in [(-b - discriminant.sqrt()) / a, (-b + discriminant.sqrt()) / a].iter() {
+                if *temp < t_max && *temp > t_min {
+                    return Some(Hit {
+                        t: *temp,
+                        p: r.point_at_parameter(*temp),
+                        normal: (r.point_at_parameter(*temp) - center) / self.radius,
+                        material: &*self.material,
+                        explicit_light: false
+                    });
+                }
+            }
+        }
+        None
+    }
+
+    fn bounding_box(&self) -> AABB {
+        let extent = Vec3::new(self.radius, self.radius, self.radius);
+        let box0 = AABB::new(self.center0 - extent, self.center0 + extent);
+        let box1 = AABB::new(self.center1 - extent, self.center1 + extent);
+        surrounding_bbox(box0, box1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use material::Lambertian;
+    use texture::ConstantTexture;
+
+    #[test]
+    fn moving_spheres_are_hit_where_they_are_at_the_ray_time() {
+        let material = Box::new(Lambertian::new(Box::new(ConstantTexture::new(Vec3::new(0.5, 0.5, 0.5)))));
+        let sphere = MovingSphere::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(2.0, 0.0, 0.0), 0.0, 1.0, 0.5, material);
+        for &(time, x) in &[(0.0, 0.0), (0.5, 1.0), (1.0, 2.0)] {
+            let ray = Ray::new(Vec3::new(x, 0.0, -5.0), Vec3::new(0.0, 0.0, 1.0), time);
+            let hit = sphere.hit(0.001, f32::MAX, &ray).expect("ray through the centre misses");
+            assert!((hit.t - 4.5).abs() < 1e-5, "{} {}", time, hit.t);
+            assert!((hit.p - Vec3::new(x, 0.0, -0.5)).length() < 1e-5);
+            assert!((hit.normal - Vec3::new(0.0, 0.0, -1.0)).length() < 1e-5);
+            //Where the sphere was at another time is empty
+            let elsewhere = Ray::new(Vec3::new(x, 0.0, -5.0), Vec3::new(0.0, 0.0, 1.0), 1.0 - time);
+            if time != 0.5 {
+                assert!(sphere.hit(0.001, f32::MAX, &elsewhere).is_none());
+            }
+        }
+    }
 }
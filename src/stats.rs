@@ -0,0 +1,110 @@
+use std::cell::RefCell;
+use std::time::Duration;
+
+/// Counters for finding out where a render spends its time. Each thread counts into its own
+/// copy, which `take` hands over to the tile it was rendering, so counting never waits on
+/// another thread; tiles are added up as they are merged into the framebuffer
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RenderStats {
+    pub camera_rays: u64,
+    pub bounce_rays: u64,
+    pub shadow_rays: u64,
+    pub bvh_nodes: u64,
+    /// Ray tests against spheres and triangles
+    pub primitive_tests: u64,
+    /// Why each path ended: it left the scene, was absorbed (lights absorb too, they don't
+    /// scatter) or reached the depth limit. Paths are never cut short at random, so each one
+    /// lands in exactly one of these and together they add up to `camera_rays`
+    pub escaped: u64,
+    pub absorbed: u64,
+    pub depth_limited: u64,
+    /// Loading the scene and building its BVHs, for whoever loaded it to fill in, and time
+    /// spent in `render::render`
+    pub build_time: Duration,
+    pub render_time: Duration
+}
+
+thread_local! {
+    static STATS: RefCell<RenderStats> = const { RefCell::new(RenderStats::ZERO) };
+}
+
+/// Updates this thread's counters
+pub fn count<F: FnOnce(&mut RenderStats)>(f: F) {
+    STATS.with(|s| f(&mut s.borrow_mut()));
+}
+
+/// This thread's counters since the last call, resetting them
+pub fn take() -> RenderStats {
+    STATS.with(|s| s.replace(RenderStats::ZERO))
+}
+
+impl RenderStats {
+    pub const ZERO: RenderStats = RenderStats {
+        camera_rays: 0,
+        bounce_rays: 0,
+        shadow_rays: 0,
+        bvh_nodes: 0,
+        primitive_tests: 0,
+        escaped: 0,
+        absorbed: 0,
+        depth_limited: 0,
+        build_time: Duration::ZERO,
+        render_time: Duration::ZERO
+    };
+
+    pub fn rays(&self) -> u64 {
+        self.camera_rays + self.bounce_rays + self.shadow_rays
+    }
+
+    /// Segments per path, counting the camera ray
+    pub fn mean_path_length(&self) -> f64 {
+        if self.camera_rays == 0 {
+            return 0.0;
+        }
+        (self.camera_rays + self.bounce_rays) as f64 / self.camera_rays as f64
+    }
+
+    /// The counters in a fixed order, for sending over the wire
+    pub fn counters(&self) -> [u64; 8] {
+        [self.camera_rays, self.bounce_rays, self.shadow_rays, self.bvh_nodes,
+         self.primitive_tests, self.escaped, self.absorbed, self.depth_limited]
+    }
+
+    pub fn counters_mut(&mut self) -> [&mut u64; 8] {
+        [&mut self.camera_rays, &mut self.bounce_rays, &mut self.shadow_rays, &mut self.bvh_nodes,
+         &mut self.primitive_tests, &mut self.escaped, &mut self.absorbed, &mut self.depth_limited]
+    }
+
+    pub fn add(&mut self, other: &RenderStats) {
+        for (a, b) in self.counters_mut().iter_mut().zip(other.counters().iter()) {
+            **a += b;
+        }
+        self.build_time += other.build_time;
+        self.render_time += other.render_time;
+    }
+
+    pub fn print(&self) {
+        let rays = self.rays().max(1) as f64;
+        let paths = self.camera_rays.max(1) as f64;
+        println!("Render statistics");
+        println!("  Build time:             {:.3} s", self.build_time.as_secs_f64());
+        println!("  Render time:            {:.3} s", self.render_time.as_secs_f64());
+        println!("  Camera rays:            {}", self.camera_rays);
+        println!("  Bounce rays:            {}", self.bounce_rays);
+        println!("  Shadow rays:            {}", self.shadow_rays);
+        println!("  BVH nodes visited:      {} ({:.1} per ray)", self.bvh_nodes, self.bvh_nodes as f64 / rays);
+        println!("  Primitive tests:        {} ({:.1} per ray)", self.primitive_tests, self.primitive_tests as f64 / rays);
+        println!("  Mean path length:       {:.2}", self.mean_path_length());
+        println!("  Paths escaped:          {} ({:.1}%)", self.escaped, 100.0 * self.escaped as f64 / paths);
+        println!("  Paths absorbed:         {} ({:.1}%)", self.absorbed, 100.0 * self.absorbed as f64 / paths);
+        println!("  Paths at depth limit:   {} ({:.1}%)", self.depth_limited, 100.0 * self.depth_limited as f64 / paths);
+    }
+
+    pub fn to_json(&self) -> String {
+        format!("{{\"build_time\": {:.3}, \"render_time\": {:.3}, \"camera_rays\": {}, \"bounce_rays\": {}, \"shadow_rays\": {}, \
+                 \"bvh_nodes\": {}, \"primitive_tests\": {}, \"mean_path_length\": {:.4}, \"escaped\": {}, \"absorbed\": {}, \
+                 \"depth_limited\": {}}}",
+                self.build_time.as_secs_f64(), self.render_time.as_secs_f64(), self.camera_rays, self.bounce_rays, self.shadow_rays,
+                self.bvh_nodes, self.primitive_tests, self.mean_path_length(), self.escaped, self.absorbed, self.depth_limited)
+    }
+}
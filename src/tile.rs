@@ -0,0 +1,123 @@
+use std::f32::consts::PI;
+
+/// Rectangle of pixels [x0, x1) x [y0, y1), with row 0 at the top of the image
+#[derive(Clone, Copy, Debug)]
+pub struct Tile {
+    pub x0: u32,
+    pub y0: u32,
+    pub x1: u32,
+    pub y1: u32
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TileOrder {
+    Scanline,
+    /// Outwards from the centre of the image, where the subject usually is
+    Spiral,
+    /// Along a Hilbert curve, keeping consecutive tiles close for cache locality
+    Hilbert
+}
+
+impl Tile {
+    pub fn width(&self) -> u32 {
+        self.x1 - self.x0
+    }
+
+    pub fn height(&self) -> u32 {
+        self.y1 - self.y0
+    }
+
+    pub fn pixel_count(&self) -> usize {
+        (self.width() * self.height()) as usize
+    }
+
+    pub fn contains(&self, x: u32, row: u32) -> bool {
+        x >= self.x0 && x < self.x1 && row >= self.y0 && row < self.y1
+    }
+
+    /// Grown by `margin` pixels on every side, staying within a width x height image
+    pub fn expand(&self, margin: u32, width: u32, height: u32) -> Tile {
+        Tile {
+            x0: self.x0.saturating_sub(margin),
+            y0: self.y0.saturating_sub(margin),
+            x1: (self.x1 + margin).min(width),
+            y1: (self.y1 + margin).min(height)
+        }
+    }
+}
+
+impl TileOrder {
+    pub fn from_name(name: &str) -> Option<TileOrder> {
+        match name {
+            "scanline" => Some(TileOrder::Scanline),
+            "spiral" => Some(TileOrder::Spiral),
+            "hilbert" => Some(TileOrder::Hilbert),
+            _ => None
+        }
+    }
+}
+
+/// Distance of (x, y) along the Hilbert curve filling an n x n grid, n a power of two
+fn hilbert_index(n: u32, mut x: u32, mut y: u32) -> u64 {
+    let mut d: u64 = 0;
+    let mut s = n / 2;
+    while s > 0 {
+        let rx = (x & s > 0) as u32;
+        let ry = (y & s > 0) as u32;
+        d += s as u64 * s as u64 * ((3 * rx) ^ ry) as u64;
+        //Rotate the quadrant
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - (x & (s - 1));
+                y = s - 1 - (y & (s - 1));
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        x &= s - 1;
+        y &= s - 1;
+        s /= 2;
+    }
+    d
+}
+
+/// Splits `region` of the image into tiles of at most tile_size x tile_size, in the given order
+pub fn generate_tiles(region: &Tile, tile_size: u32, order: TileOrder) -> Vec<Tile> {
+    let (width, height) = (region.width(), region.height());
+    let tile_size = tile_size.max(1);
+    let columns = width.div_ceil(tile_size);
+    let rows = height.div_ceil(tile_size);
+
+    let mut grid: Vec<(u32, u32)> = Vec::with_capacity((columns * rows) as usize);
+    for row in 0..rows {
+        for column in 0..columns {
+            grid.push((column, row));
+        }
+    }
+
+    match order {
+        TileOrder::Scanline => {},
+        TileOrder::Spiral => {
+            let cx = (columns as f32 - 1.0) / 2.0;
+            let cy = (rows as f32 - 1.0) / 2.0;
+            let key = |&(c, r): &(u32, u32)| {
+                let dx = c as f32 - cx;
+                let dy = r as f32 - cy;
+                let ring = dx.abs().max(dy.abs());
+                let angle = dy.atan2(dx) + PI;
+                (ring, angle)
+            };
+            grid.sort_by(|a, b| key(a).partial_cmp(&key(b)).unwrap());
+        },
+        TileOrder::Hilbert => {
+            let n = columns.max(rows).next_power_of_two();
+            grid.sort_by_key(|&(c, r)| hilbert_index(n, c, r));
+        }
+    }
+
+    grid.into_iter().map(|(column, row)| Tile {
+        x0: region.x0 + column * tile_size,
+        y0: region.y0 + row * tile_size,
+        x1: region.x0 + ((column + 1) * tile_size).min(width),
+        y1: region.y0 + ((row + 1) * tile_size).min(height)
+    }).collect()
+}
@@ -0,0 +1,347 @@
+use vec3::Vec3;
+use rng::splitmix64;
+
+/// Display transform applied when writing 8 or 16-bit images:
+/// exposure, white balance, tone curve, sRGB encoding, dithering and quantisation
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operator {
+    /// Clip to [0, 1]
+    Clamp,
+    /// Reinhard with a white point, applied to luminance so hues are kept
+    Reinhard,
+    /// Stephen Hill's fit of the ACES reference and output transforms
+    Aces,
+    /// Troy Sobotka's AgX with the default contrast look
+    Agx,
+    /// John Hable's filmic curve from Uncharted 2
+    Hable
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ToneMapSettings {
+    pub operator: Operator,
+    /// Exposure adjustment in stops
+    pub exposure: f32,
+    /// Colour temperature in Kelvin that should come out white, None to leave colours alone
+    pub white_balance: Option<f32>,
+    /// Luminance mapped to white by the Reinhard operator
+    pub white_point: f32,
+    pub dither: bool,
+    /// 8 or 16
+    pub bit_depth: u32
+}
+
+type Matrix = [[f32; 3]; 3];
+
+fn transform(m: &Matrix, v: Vec3) -> Vec3 {
+    Vec3::new(
+        m[0][0] * v.x() + m[0][1] * v.y() + m[0][2] * v.z(),
+        m[1][0] * v.x() + m[1][1] * v.y() + m[1][2] * v.z(),
+        m[2][0] * v.x() + m[2][1] * v.y() + m[2][2] * v.z()
+    )
+}
+
+fn multiply(a: &Matrix, b: &Matrix) -> Matrix {
+    let mut m = [[0.0; 3]; 3];
+    for (i, row) in m.iter_mut().enumerate() {
+        for (j, value) in row.iter_mut().enumerate() {
+            *value = (0..3).map(|k| a[i][k] * b[k][j]).sum();
+        }
+    }
+    m
+}
+
+const XYZ_FROM_SRGB: Matrix = [
+    [0.412_456_4, 0.357_576_1, 0.180_437_5],
+    [0.212_672_9, 0.715_152_2, 0.072_175_0],
+    [0.019_333_9, 0.119_192, 0.950_304_1]
+];
+
+const SRGB_FROM_XYZ: Matrix = [
+    [3.240_454_2, -1.537_138_5, -0.498_531_4],
+    [-0.969_266, 1.876_010_8, 0.041_556_0],
+    [0.055_643_4, -0.204_025_9, 1.057_225_2]
+];
+
+const BRADFORD: Matrix = [
+    [0.8951, 0.2664, -0.1614],
+    [-0.7502, 1.7135, 0.0367],
+    [0.0389, -0.0685, 1.0296]
+];
+
+const BRADFORD_INVERSE: Matrix = [
+    [0.986_992_9, -0.147_054_3, 0.159_962_7],
+    [0.432_305_3, 0.518_360_3, 0.049_291_2],
+    [-0.008_528_7, 0.040_042_8, 0.968_486_7]
+];
+
+const D65: (f32, f32) = (0.312_71, 0.329_02);
+
+/// Chromaticity of a light source at the given temperature: the Planckian locus below 4000K
+/// (Kim et al.) and the CIE daylight locus above it, so 6504K lands on D65
+fn white_point_xy(kelvin: f32) -> (f32, f32) {
+    let t = kelvin.clamp(1667.0, 25000.0) as f64;
+    let (t2, t3) = (t * t, t * t * t);
+    if t < 4000.0 {
+        let x = -0.266_123_9e9 / t3 - 0.234_358_9e6 / t2 + 0.877_695_6e3 / t + 0.179_910;
+        let y = if t < 2222.0 {
+            -1.106_381_4 * x * x * x - 1.348_110_20 * x * x + 2.185_558_32 * x - 0.202_196_83
+        } else {
+            -0.954_947_6 * x * x * x - 1.374_185_93 * x * x + 2.091_370_15 * x - 0.167_488_67
+        };
+        (x as f32, y as f32)
+    } else {
+        let x = if t <= 7000.0 {
+            -4.6070e9 / t3 + 2.9678e6 / t2 + 0.09911e3 / t + 0.244_063
+        } else {
+            -2.0064e9 / t3 + 1.9018e6 / t2 + 0.24748e3 / t + 0.237_040
+        };
+        let y = -3.0 * x * x + 2.870 * x - 0.275;
+        (x as f32, y as f32)
+    }
+}
+
+/// Bradford adaptation, in linear sRGB, from a light of the given temperature to D65
+fn white_balance_matrix(kelvin: f32) -> Matrix {
+    let xyz = |(x, y): (f32, f32)| Vec3::new(x / y, 1.0, (1.0 - x - y) / y);
+    let source = transform(&BRADFORD, xyz(white_point_xy(kelvin)));
+    let target = transform(&BRADFORD, xyz(D65));
+    let scale = [
+        [target.x() / source.x(), 0.0, 0.0],
+        [0.0, target.y() / source.y(), 0.0],
+        [0.0, 0.0, target.z() / source.z()]
+    ];
+    let adapt = multiply(&BRADFORD_INVERSE, &multiply(&scale, &BRADFORD));
+    multiply(&SRGB_FROM_XYZ, &multiply(&adapt, &XYZ_FROM_SRGB))
+}
+
+fn reinhard(c: Vec3, white: f32) -> Vec3 {
+    let l = c.luminance();
+    if l <= 0.0 {
+        return Vec3::zero_vector();
+    }
+    let mapped = l * (1.0 + l / (white * white)) / (1.0 + l);
+    (mapped / l) * c
+}
+
+const ACES_INPUT: Matrix = [
+    [0.597_19, 0.354_58, 0.048_23],
+    [0.076_00, 0.908_34, 0.015_66],
+    [0.028_40, 0.133_83, 0.837_77]
+];
+
+const ACES_OUTPUT: Matrix = [
+    [1.604_75, -0.531_08, -0.073_67],
+    [-0.102_08, 1.108_13, -0.006_05],
+    [-0.003_27, -0.072_76, 1.076_02]
+];
+
+fn aces(c: Vec3) -> Vec3 {
+    let fit = |v: f32| (v * (v + 0.024_578_6) - 0.000_090_537) / (v * (0.983_729 * v + 0.432_951) + 0.238_081);
+    let v = transform(&ACES_INPUT, c);
+    transform(&ACES_OUTPUT, Vec3::new(fit(v.x()), fit(v.y()), fit(v.z())))
+}
+
+const AGX_INSET: Matrix = [
+    [0.842_479_06, 0.078_433_6, 0.079_223_745],
+    [0.042_328_24, 0.878_468_6, 0.079_166_13],
+    [0.042_375_655, 0.078_433_6, 0.879_143]
+];
+
+const AGX_OUTSET: Matrix = [
+    [1.196_879, -0.098_020_88, -0.099_029_74],
+    [-0.052_896_85, 1.151_903_1, -0.098_961_18],
+    [-0.052_971_635, -0.098_043_45, 1.151_073_7]
+];
+
+fn agx(c: Vec3) -> Vec3 {
+    const MIN_EV: f32 = -12.473_93;
+    const MAX_EV: f32 = 4.026_069;
+    let curve = |v: f32| {
+        let x = ((v.max(1e-10).log2().clamp(MIN_EV, MAX_EV)) - MIN_EV) / (MAX_EV - MIN_EV);
+        let x2 = x * x;
+        let x4 = x2 * x2;
+        15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x - 0.002_32
+    };
+    let v = transform(&AGX_INSET, c);
+    let v = transform(&AGX_OUTSET, Vec3::new(curve(v.x()), curve(v.y()), curve(v.z())));
+    //The curve produces display encoded values for a 2.2 gamma display; back to linear
+    let linear = |v: f32| v.max(0.0).powf(2.2);
+    Vec3::new(linear(v.x()), linear(v.y()), linear(v.z()))
+}
+
+fn hable(c: Vec3) -> Vec3 {
+    let curve = |x: f32| {
+        let (a, b, c, d, e, f) = (0.15, 0.50, 0.10, 0.20, 0.02, 0.30);
+        ((x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f)) - e / f
+    };
+    const EXPOSURE_BIAS: f32 = 2.0;
+    const WHITE: f32 = 11.2;
+    //The curve is 0 at 0 only up to rounding, so subtract what it gives there to keep black black
+    let black = curve(0.0);
+    let scale = 1.0 / (curve(WHITE) - black);
+    let mapped = |v: f32| (curve(EXPOSURE_BIAS * v) - black) * scale;
+    Vec3::new(mapped(c.x()), mapped(c.y()), mapped(c.z()))
+}
+
+/// sRGB OETF, linear [0, 1] to encoded [0, 1]
+fn srgb_encode(v: f32) -> f32 {
+    let v = v.clamp(0.0, 1.0);
+    if v <= 0.003_130_8 {
+        12.92 * v
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Triangular noise in (-1, 1), a function of the pixel and channel so images are repeatable
+fn dither_noise(pixel: usize, channel: usize) -> f32 {
+    let h = splitmix64(((pixel as u64) << 2) | channel as u64);
+    let a = (h >> 40) as f32 / 16_777_216.0;
+    let b = ((h >> 16) & 0xff_ffff) as f32 / 16_777_216.0;
+    a + b - 1.0
+}
+
+impl Operator {
+    pub fn from_name(name: &str) -> Option<Operator> {
+        match name {
+            "clamp" => Some(Operator::Clamp),
+            "reinhard" => Some(Operator::Reinhard),
+            "aces" => Some(Operator::Aces),
+            "agx" => Some(Operator::Agx),
+            "hable" => Some(Operator::Hable),
+            _ => None
+        }
+    }
+}
+
+impl Default for ToneMapSettings {
+    fn default() -> ToneMapSettings {
+        ToneMapSettings {
+            operator: Operator::Clamp,
+            exposure: 0.0,
+            white_balance: None,
+            white_point: 4.0,
+            dither: true,
+            bit_depth: 8
+        }
+    }
+}
+
+impl ToneMapSettings {
+    /// Maps linear radiance to linear display values in [0, 1]
+    pub fn map(&self, pixels: &[Vec3]) -> Vec<Vec3> {
+        let scale = 2f32.powf(self.exposure);
+        let balance = self.white_balance.map(white_balance_matrix);
+        pixels.iter().map(|&p| {
+            let mut c = scale * p;
+            if let Some(m) = balance.as_ref() {
+                c = transform(m, c);
+            }
+            let c = c.clamp(Vec3::zero_vector(), Vec3::new(f32::MAX, f32::MAX, f32::MAX));
+            let c = match self.operator {
+                Operator::Clamp => c,
+                Operator::Reinhard => reinhard(c, self.white_point),
+                Operator::Aces => aces(c),
+                Operator::Agx => agx(c),
+                Operator::Hable => hable(c)
+            };
+            c.clamp(Vec3::zero_vector(), Vec3::new(1.0, 1.0, 1.0))
+        }).collect()
+    }
+
+    /// Tone maps and quantises to opaque RGBA, 8-bit or big endian 16-bit as PNG stores it
+    pub fn encode(&self, pixels: &[Vec3]) -> Vec<u8> {
+        let levels = if self.bit_depth == 16 { 65535.0 } else { 255.0 };
+        let bytes = if self.bit_depth == 16 { 2 } else { 1 };
+        let mut data = Vec::with_capacity(pixels.len() * 4 * bytes);
+        for (index, c) in self.map(pixels).iter().enumerate() {
+            for (channel, v) in [c.x(), c.y(), c.z(), 1.0].iter().enumerate() {
+                let mut v = if channel < 3 { srgb_encode(*v) * levels } else { levels };
+                if self.dither && channel < 3 {
+                    v += dither_noise(index, channel);
+                }
+                let q = (v + 0.5).floor().clamp(0.0, levels) as u32;
+                if bytes == 2 {
+                    data.extend_from_slice(&(q as u16).to_be_bytes());
+                } else {
+                    data.push(q as u8);
+                }
+            }
+        }
+        data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPERATORS: [Operator; 5] = [Operator::Clamp, Operator::Reinhard, Operator::Aces, Operator::Agx, Operator::Hable];
+
+    fn settings(operator: Operator) -> ToneMapSettings {
+        ToneMapSettings {
+            operator,
+            ..ToneMapSettings::default()
+        }
+    }
+
+    #[test]
+    fn curves_start_at_zero_rise_and_stay_in_range() {
+        //Greys and a few colours, each scaled from black to far past white
+        let colours = [Vec3::new(1.0, 1.0, 1.0), Vec3::new(1.0, 0.2, 0.05), Vec3::new(0.1, 0.6, 0.9)];
+        for &operator in OPERATORS.iter() {
+            let tonemap = settings(operator);
+            assert_eq!(tonemap.map(&[Vec3::zero_vector()])[0].length(), 0.0, "{:?}", operator);
+            for colour in colours.iter() {
+                let inputs: Vec<Vec3> = (0..2000).map(|i| (i as f32 * 0.05).powi(2) * *colour).collect();
+                let mapped = tonemap.map(&inputs);
+                //AgX's outset matrix takes a little from a channel that has reached the top of
+                //the curve while the others still rise, so near white it may dip by a hair
+                let slack = if operator == Operator::Agx { 1e-4 } else { 1e-6 };
+                for pair in mapped.windows(2) {
+                    for (a, b) in [(pair[0].x(), pair[1].x()), (pair[0].y(), pair[1].y()), (pair[0].z(), pair[1].z())] {
+                        assert!((0.0..=1.0).contains(&b), "{:?} {:?}", operator, pair[1]);
+                        assert!(b >= a - slack, "{:?} {:?} then {:?}", operator, pair[0], pair[1]);
+                    }
+                    assert!(pair[1].luminance() >= pair[0].luminance() - slack, "{:?} {:?} then {:?}", operator, pair[0], pair[1]);
+                }
+                //Bright inputs get close to white rather than stalling part way
+                assert!(mapped[mapped.len() - 1].luminance() > 0.7, "{:?} {:?}", operator, mapped[mapped.len() - 1]);
+            }
+        }
+    }
+
+    #[test]
+    fn one_stop_doubles_the_input() {
+        let pixels = [Vec3::new(0.01, 0.1, 0.2), Vec3::new(0.3, 0.05, 0.45), Vec3::new(1.5, 2.0, 4.0)];
+        let doubled: Vec<Vec3> = pixels.iter().map(|p| 2.0 * *p).collect();
+        for &operator in OPERATORS.iter() {
+            let brighter = ToneMapSettings { exposure: 1.0, ..settings(operator) }.map(&pixels);
+            for (a, b) in brighter.iter().zip(settings(operator).map(&doubled).iter()) {
+                assert!((*a - *b).length() < 1e-6, "{:?} {:?} {:?}", operator, a, b);
+            }
+        }
+        //Linear below white with the clamp operator
+        let half = ToneMapSettings { exposure: -1.0, ..settings(Operator::Clamp) }.map(&pixels[..2]);
+        for (a, b) in half.iter().zip(pixels.iter()) {
+            assert!((*a - 0.5 * *b).length() < 1e-7, "{:?} {:?}", a, b);
+        }
+    }
+
+    #[test]
+    fn d65_white_balance_is_close_to_identity() {
+        let m = white_balance_matrix(6504.0);
+        for (i, row) in m.iter().enumerate() {
+            for (j, v) in row.iter().enumerate() {
+                let expected = if i == j { 1.0 } else { 0.0 };
+                assert!((v - expected).abs() < 5e-3, "{:?}", m);
+            }
+        }
+        //Warmer light is cooled and cooler light warmed
+        let grey = Vec3::new(0.5, 0.5, 0.5);
+        let warm = transform(&white_balance_matrix(3200.0), grey);
+        let cool = transform(&white_balance_matrix(10000.0), grey);
+        assert!(warm.z() > warm.x() && cool.x() > cool.z(), "{:?} {:?}", warm, cool);
+    }
+}
@@ -0,0 +1,262 @@
+use vec3::Vec3;
+use aabb::AABB;
+use aabb::surrounding_bbox;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Quaternion {
+    w: f32,
+    x: f32,
+    y: f32,
+    z: f32
+}
+
+/// Similarity transform: uniform scale, then rotation, then translation
+#[derive(Clone, Copy, Debug)]
+pub struct Transform {
+    translation: Vec3,
+    rotation: Quaternion,
+    scale: f32
+}
+
+/// Transform keyframes sorted by time, interpolated linearly (slerp for rotations)
+#[derive(Clone, Debug)]
+pub struct AnimatedTransform {
+    keys: Vec<(f32, Transform)>
+}
+
+impl Quaternion {
+    pub fn identity() -> Quaternion {
+        Quaternion {
+            w: 1.0,
+            x: 0.0,
+            y: 0.0,
+            z: 0.0
+        }
+    }
+
+    pub fn from_axis_angle(axis: Vec3, degrees: f32) -> Quaternion {
+        let axis = Vec3::unit_vector(axis);
+        let half = 0.5 * degrees.to_radians();
+        let s = half.sin();
+        Quaternion {
+            w: half.cos(),
+            x: axis.x() * s,
+            y: axis.y() * s,
+            z: axis.z() * s
+        }
+    }
+
+    pub fn conjugate(&self) -> Quaternion {
+        Quaternion {
+            w: self.w,
+            x: -self.x,
+            y: -self.y,
+            z: -self.z
+        }
+    }
+
+    fn dot(&self, q: Quaternion) -> f32 {
+        self.w * q.w + self.x * q.x + self.y * q.y + self.z * q.z
+    }
+
+    fn normalized(&self) -> Quaternion {
+        let length = self.dot(*self).sqrt();
+        Quaternion {
+            w: self.w / length,
+            x: self.x / length,
+            y: self.y / length,
+            z: self.z / length
+        }
+    }
+
+    pub fn rotate(&self, v: Vec3) -> Vec3 {
+        let q = Vec3::new(self.x, self.y, self.z);
+        let t = 2.0 * q.cross(v);
+        v + self.w * t + q.cross(t)
+    }
+
+    pub fn slerp(a: Quaternion, b: Quaternion, t: f32) -> Quaternion {
+        let mut cos_theta = a.dot(b);
+        //Take the short way round
+        let b = if cos_theta < 0.0 {
+            cos_theta = -cos_theta;
+            Quaternion { w: -b.w, x: -b.x, y: -b.y, z: -b.z }
+        } else {
+            b
+        };
+        let (wa, wb) = if cos_theta > 0.9995 {
+            (1.0 - t, t)
+        } else {
+            let theta = cos_theta.acos();
+            let sin_theta = theta.sin();
+            (((1.0 - t) * theta).sin() / sin_theta, (t * theta).sin() / sin_theta)
+        };
+        Quaternion {
+            w: wa * a.w + wb * b.w,
+            x: wa * a.x + wb * b.x,
+            y: wa * a.y + wb * b.y,
+            z: wa * a.z + wb * b.z
+        }.normalized()
+    }
+}
+
+impl Transform {
+    pub fn identity() -> Transform {
+        Transform::new(Vec3::zero_vector(), Quaternion::identity(), 1.0)
+    }
+
+    pub fn new(translation: Vec3, rotation: Quaternion, scale: f32) -> Transform {
+        Transform {
+            translation,
+            rotation,
+            scale
+        }
+    }
+
+    pub fn translation(translation: Vec3) -> Transform {
+        Transform::new(translation, Quaternion::identity(), 1.0)
+    }
+
+    pub fn apply_point(&self, p: Vec3) -> Vec3 {
+        self.rotation.rotate(self.scale * p) + self.translation
+    }
+
+    pub fn apply_vector(&self, v: Vec3) -> Vec3 {
+        self.rotation.rotate(self.scale * v)
+    }
+
+    /// Uniform scaling leaves normal directions unchanged
+    pub fn apply_normal(&self, n: Vec3) -> Vec3 {
+        self.rotation.rotate(n)
+    }
+
+    pub fn inverse_point(&self, p: Vec3) -> Vec3 {
+        self.rotation.conjugate().rotate(p - self.translation) / self.scale
+    }
+
+    pub fn inverse_vector(&self, v: Vec3) -> Vec3 {
+        self.rotation.conjugate().rotate(v) / self.scale
+    }
+
+    pub fn lerp(a: &Transform, b: &Transform, t: f32) -> Transform {
+        Transform {
+            translation: (1.0 - t) * a.translation + t * b.translation,
+            rotation: Quaternion::slerp(a.rotation, b.rotation, t),
+            scale: (1.0 - t) * a.scale + t * b.scale
+        }
+    }
+
+    pub fn apply_bbox(&self, bbox: AABB) -> AABB {
+        let (min, max) = (bbox.min(), bbox.max());
+        let mut result: Option<AABB> = None;
+        for i in 0..8 {
+            let corner = Vec3::new(
+                if i & 1 == 0 { min.x() } else { max.x() },
+                if i & 2 == 0 { min.y() } else { max.y() },
+                if i & 4 == 0 { min.z() } else { max.z() });
+            let p = self.apply_point(corner);
+            let point_box = AABB::new(p, p);
+            result = Some(match result {
+                Some(b) => surrounding_bbox(b, point_box),
+                None => point_box
+            });
+        }
+        result.unwrap()
+    }
+}
+
+impl AnimatedTransform {
+    pub fn fixed(transform: Transform) -> AnimatedTransform {
+        AnimatedTransform {
+            keys: vec![(0.0, transform)]
+        }
+    }
+
+    pub fn linear(time0: f32, start: Transform, time1: f32, end: Transform) -> AnimatedTransform {
+        AnimatedTransform::keyframed(vec![(time0, start), (time1, end)])
+    }
+
+    pub fn keyframed(mut keys: Vec<(f32, Transform)>) -> AnimatedTransform {
+        assert!(!keys.is_empty(), "Animated transform needs at least one key");
+        keys.sort_by(|a, b| a.0.total_cmp(&b.0));
+        AnimatedTransform {
+            keys
+        }
+    }
+
+    pub fn is_animated(&self) -> bool {
+        self.keys.len() > 1
+    }
+
+    pub fn at(&self, time: f32) -> Transform {
+        let first = &self.keys[0];
+        if time <= first.0 || self.keys.len() == 1 {
+            return first.1;
+        }
+        for pair in self.keys.windows(2) {
+            let (t0, ref a) = pair[0];
+            let (t1, ref b) = pair[1];
+            if time <= t1 {
+                let span = t1 - t0;
+                let t = if span > 0.0 { (time - t0) / span } else { 1.0 };
+                return Transform::lerp(a, b, t);
+            }
+        }
+        self.keys[self.keys.len() - 1].1
+    }
+
+    /// Bounds of `bbox` over the whole animation, sampling rotations between keys
+    pub fn swept_bbox(&self, bbox: AABB) -> AABB {
+        const STEPS: usize = 16;
+        let mut result = self.keys[0].1.apply_bbox(bbox);
+        for pair in self.keys.windows(2) {
+            let (_, ref a) = pair[0];
+            let (_, ref b) = pair[1];
+            for step in 1..=STEPS {
+                let xf = Transform::lerp(a, b, step as f32 / STEPS as f32);
+                result = surrounding_bbox(result, xf.apply_bbox(bbox));
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: Vec3, b: Vec3) -> bool {
+        (a - b).length() < 1e-5
+    }
+
+    #[test]
+    fn interpolates_between_keys() {
+        let start = Transform::translation(Vec3::new(0.0, 0.0, 0.0));
+        let end = Transform::new(Vec3::new(2.0, 4.0, 0.0), Quaternion::from_axis_angle(Vec3::new(0.0, 0.0, 1.0), 90.0), 3.0);
+        let animated = AnimatedTransform::linear(0.0, start, 1.0, end);
+        let p = Vec3::new(1.0, 0.0, 0.0);
+        assert!(close(animated.at(0.0).apply_point(p), p));
+        //Halfway: scale 2, rotated 45 degrees, moved by (1, 2, 0)
+        let half = std::f32::consts::FRAC_1_SQRT_2;
+        assert!(close(animated.at(0.5).apply_point(p), Vec3::new(1.0 + 2.0 * half, 2.0 + 2.0 * half, 0.0)), "{:?}", animated.at(0.5).apply_point(p));
+        assert!(close(animated.at(1.0).apply_point(p), Vec3::new(2.0, 7.0, 0.0)), "{:?}", animated.at(1.0).apply_point(p));
+        //Held at the ends outside the keys
+        assert!(close(animated.at(-1.0).apply_point(p), p));
+        assert!(close(animated.at(2.0).apply_point(p), Vec3::new(2.0, 7.0, 0.0)));
+    }
+
+    #[test]
+    fn follows_keyframes_in_time_order() {
+        let animated = AnimatedTransform::keyframed(vec![
+            (1.0, Transform::translation(Vec3::new(0.0, 2.0, 0.0))),
+            (0.0, Transform::translation(Vec3::new(0.0, 0.0, 0.0))),
+            (0.5, Transform::translation(Vec3::new(4.0, 0.0, 0.0)))
+        ]);
+        let o = Vec3::zero_vector();
+        assert!(close(animated.at(0.0).apply_point(o), Vec3::new(0.0, 0.0, 0.0)));
+        assert!(close(animated.at(0.25).apply_point(o), Vec3::new(2.0, 0.0, 0.0)));
+        assert!(close(animated.at(0.5).apply_point(o), Vec3::new(4.0, 0.0, 0.0)));
+        assert!(close(animated.at(0.75).apply_point(o), Vec3::new(2.0, 1.0, 0.0)));
+        assert!(close(animated.at(1.0).apply_point(o), Vec3::new(0.0, 2.0, 0.0)));
+    }
+}
@@ -4,6 +4,8 @@ use aabb::AABB;
 use material::Material;
 use hitable::Hitable;
 use hitable::Hit;
+use light::{Light, LightShape};
+use stats;
 
 pub struct Triangle {
     p1: Vec3,
@@ -26,7 +28,8 @@ impl Triangle {
 }
 
 impl Hitable for Triangle {
-    fn hit(&self, t_min: f32, t_max: f32, r: &Ray) -> Option<Hit> {
+    fn hit(&self, t_min: f32, t_max: f32, r: &Ray) -> Option<Hit<'_>> {
+        stats::count(|s| s.primitive_tests += 1);
         const EPSILON: f32 = 0.0000001;
         let edge1 = self.p2 - self.p1;
         let edge2 = self.p3 - self.p1;
@@ -41,7 +44,7 @@ impl Hitable for Triangle {
         let f = 1.0 / a;
         let s = r.origin() - self.p1;
         let u = f * s.dot(h);
-        if u < 0.0 || u > 1.0 {
+        if !(0.0..=1.0).contains(&u) {
             return None;
         }
 
@@ -62,7 +65,8 @@ impl Hitable for Triangle {
                 t,
                 p: r.origin() + t * r.direction(),
                 normal,
-                material: &self.material
+                material: &*self.material,
+                explicit_light: true
             });
         }
 
@@ -80,4 +84,14 @@ impl Hitable for Triangle {
         let min = Vec3::new(min_x - delta, min_y - delta, min_z - delta);
         AABB::new(min, max)
     }
-}
\ No newline at end of file
+
+    fn as_light(&self) -> Option<Light> {
+        let centroid = (self.p1 + self.p2 + self.p3) / 3.0;
+        let radiance = self.material.emitted(0.0, 0.0, &centroid);
+        if radiance.luminance() > 0.0 {
+            Some(Light::new(LightShape::Triangle { p1: self.p1, p2: self.p2, p3: self.p3 }, radiance).with_group(self.material.labels().light_group))
+        } else {
+            None
+        }
+    }
+}
@@ -58,6 +58,9 @@ impl Vec3 {
             z: self.x*v2.y - self.y*v2.x
         }
     }
+    pub fn luminance(&self) -> f32 {
+        0.2126 * self.x + 0.7152 * self.y + 0.0722 * self.z
+    }
     pub fn clamp(&self, min: Vec3, max: Vec3) -> Vec3 {
         let x = if self.x > max.x {
             max.x